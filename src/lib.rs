@@ -10,6 +10,12 @@ extern crate test;
 pub mod linked_map;
 pub mod list;
 pub mod map;
+pub mod owned_map;
+#[cfg(unix)]
+pub mod persistent;
+pub mod pod_map;
+#[cfg(target_os = "linux")]
+pub mod shm;
 pub mod spin;
 
 pub mod rand;
@@ -2,12 +2,15 @@
 
 use crate::map::{Map, ObjectMap};
 use crate::spin::SpinLock;
+use std::alloc::{Allocator, System};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
 use std::ops::Deref;
 use std::sync::atomic::Ordering::{AcqRel, Acquire, Release};
 use std::sync::atomic::{fence, AtomicUsize};
 use std::sync::Arc;
 
-const NONE_KEY: usize = !0 >> 1;
+pub(crate) const NONE_KEY: usize = !0 >> 1;
 
 pub type NodeRef<T> = Arc<Node<T>>;
 
@@ -19,16 +22,26 @@ pub struct Node<T> {
     obj: T,
 }
 
-pub struct LinkedObjectMap<T> {
-    map: ObjectMap<NodeRef<T>>,
+pub struct LinkedObjectMap<
+    T,
+    ALLOC: Allocator + Clone = System,
+    H: Hasher + Default = DefaultHasher,
+> {
+    map: ObjectMap<NodeRef<T>, ALLOC, H>,
     head: AtomicUsize,
     tail: AtomicUsize,
 }
 
 impl<T> LinkedObjectMap<T> {
     pub fn with_capacity(cap: usize) -> Self {
+        Self::with_map(ObjectMap::with_capacity(cap))
+    }
+}
+
+impl<T, ALLOC: Allocator + Clone, H: Hasher + Default> LinkedObjectMap<T, ALLOC, H> {
+    pub(crate) fn with_map(map: ObjectMap<NodeRef<T>, ALLOC, H>) -> Self {
         LinkedObjectMap {
-            map: ObjectMap::with_capacity(cap),
+            map,
             head: AtomicUsize::new(NONE_KEY),
             tail: AtomicUsize::new(NONE_KEY),
         }
@@ -179,64 +192,87 @@ impl<T> LinkedObjectMap<T> {
     }
 
     pub fn all_pairs(&self) -> Vec<(usize, NodeRef<T>)> {
-        let mut res = vec![];
-        let mut node_key = self.head.load(Acquire);
-        loop {
-            if let Some(node) = self.map.get(&node_key) {
-                let new_node_key = node.get_next();
-                res.push((node_key, node));
-                node_key = new_node_key;
-            } else if node_key == NONE_KEY {
-                break;
-            } else {
-                unreachable!();
-            }
-        }
-        res
+        self.iter().collect()
     }
 
     pub fn all_keys(&self) -> Vec<usize> {
-        let mut res = vec![];
-        let mut node_key = self.head.load(Acquire);
-        loop {
-            if let Some(node) = self.map.get(&node_key) {
-                res.push(node_key);
-                node_key = node.get_next();
-            } else if node_key == NONE_KEY {
-                break;
-            } else {
-                unreachable!();
-            }
-        }
-        res
+        self.iter().map(|(k, _)| k).collect()
     }
 
     pub fn all_values(&self) -> Vec<NodeRef<T>> {
-        let mut res = vec![];
-        let mut node_key = self.head.load(Acquire);
-        loop {
-            if let Some(node) = self.map.get(&node_key) {
-                node_key = node.get_next();
-                res.push(node);
-            } else if node_key == NONE_KEY {
-                break;
-            } else {
-                unreachable!();
-            }
+        self.iter().map(|(_, v)| v).collect()
+    }
+
+    /// Walk the nodes from the front without collecting them. When the next node is removed
+    /// during the walk, it goes on with the node that replaced it as the next one, it only ends
+    /// early when the node it stands on is removed as well
+    pub fn iter(&self) -> LinkedMapIter<T, ALLOC, H> {
+        LinkedMapIter {
+            node: None,
+            next: self.head.load(Acquire),
+            map: self,
         }
-        res
     }
 
-    pub fn iter(&self) -> LinkedMapIter<T> {
-        loop {
-            
+    /// Remove the nodes `keep` returns false for, walking from the front.
+    /// Nodes are unlinked one by one, as `remove` does
+    pub fn retain<F: FnMut(usize, &T) -> bool>(&self, mut keep: F) {
+        for (key, node) in self.iter() {
+            if !keep(key, &node) {
+                self.remove(&key);
+            }
         }
     }
+
+    pub fn clear(&self) {
+        self.retain(|_, _| false)
+    }
+
+    /// Remove the nodes as they are yielded from the front
+    pub fn drain(&self) -> impl Iterator<Item = (usize, NodeRef<T>)> + '_ {
+        self.iter()
+            .filter_map(move |(key, _)| self.remove(&key).map(|node| (key, node)))
+    }
 }
 
-pub struct LinkedMapIter<'a, T> {
-    node: Arc<Node<T>>,
-    map: &'a LinkedObjectMap<T>
+pub struct LinkedMapIter<
+    'a,
+    T,
+    ALLOC: Allocator + Clone = System,
+    H: Hasher + Default = DefaultHasher,
+> {
+    node: Option<NodeRef<T>>,
+    next: usize,
+    map: &'a LinkedObjectMap<T, ALLOC, H>,
+}
+
+impl<'a, T, ALLOC: Allocator + Clone, H: Hasher + Default> Iterator
+    for LinkedMapIter<'a, T, ALLOC, H>
+{
+    type Item = (usize, NodeRef<T>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.next;
+            if key == NONE_KEY {
+                return None;
+            }
+            if let Some(node) = self.map.get(&key) {
+                self.next = node.get_next();
+                self.node = Some(node.clone());
+                return Some((key, node));
+            }
+            // Nodes are unlinked before they leave the map, read the link again
+            let next = match &self.node {
+                Some(node) => node.get_next(),
+                None => self.map.head.load(Acquire),
+            };
+            if next == key {
+                return None;
+            }
+            self.next = next;
+        }
+    }
 }
 
 impl<T> Node<T> {
@@ -274,6 +310,67 @@ impl<T> Deref for Node<T> {
     }
 }
 
+// Serialized from the front by `iter` and deserialized by inserting to the back, so the link
+// order survives a round trip
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use super::*;
+    use crate::map::deserialize_builder;
+    use serde::de::{Deserialize, Deserializer, Error, MapAccess, Visitor};
+    use serde::ser::{Serialize, Serializer};
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    impl<T: Serialize, ALLOC: Allocator + Clone, H: Hasher + Default> Serialize
+        for LinkedObjectMap<T, ALLOC, H>
+    {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.iter().map(|(key, node)| (key, SerializeNode(node))))
+        }
+    }
+
+    struct SerializeNode<T>(NodeRef<T>);
+
+    impl<T: Serialize> Serialize for SerializeNode<T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.0.obj.serialize(serializer)
+        }
+    }
+
+    struct LinkedObjectMapVisitor<T, ALLOC, H>(PhantomData<(T, ALLOC, H)>);
+
+    impl<'de, T: Deserialize<'de>, ALLOC: Allocator + Clone + Default, H: Hasher + Default>
+        Visitor<'de> for LinkedObjectMapVisitor<T, ALLOC, H>
+    {
+        type Value = LinkedObjectMap<T, ALLOC, H>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a map of words to objects in link order")
+        }
+
+        fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Self::Value, M::Error> {
+            let map = deserialize_builder(access.size_hint())
+                .linked_object_map()
+                .map_err(M::Error::custom)?;
+            while let Some((key, value)) = access.next_entry::<usize, T>()? {
+                if key == NONE_KEY || key > ObjectMap::<NodeRef<T>, ALLOC, H>::MAX_KEY {
+                    return Err(M::Error::custom(format!("key {} is out of range", key)));
+                }
+                map.insert_back(&key, value);
+            }
+            Ok(map)
+        }
+    }
+
+    impl<'de, T: Deserialize<'de>, ALLOC: Allocator + Clone + Default, H: Hasher + Default>
+        Deserialize<'de> for LinkedObjectMap<T, ALLOC, H>
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_map(LinkedObjectMapVisitor(PhantomData))
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -290,6 +387,55 @@ mod test {
         }
     }
 
+    #[test]
+    pub fn linked_map_iter() {
+        let map = LinkedObjectMap::with_capacity(16);
+        for i in 0..1024 {
+            map.insert_back(&i, i);
+        }
+        let mut iter = map.iter();
+        assert_eq!(iter.next().map(|(k, v)| (k, **v)), Some((0, 0)));
+        // Both the node ahead and the one under the iterator go away
+        map.remove(&1);
+        assert_eq!(iter.next().map(|(k, _)| k), Some(2));
+        map.remove(&2);
+        map.remove(&3);
+        assert_eq!(iter.next().map(|(k, _)| k), None);
+        for i in (0..1024).step_by(2) {
+            map.remove(&i);
+        }
+        let keys = map.iter().map(|(k, v)| {
+            assert_eq!(k, **v);
+            k
+        });
+        assert!(keys.eq((5..1024).step_by(2)));
+        map.retain(|k, _| k % 4 == 1);
+        assert!(map.all_keys().into_iter().eq((5..1024).step_by(4)));
+        let drained = map.drain().take(10).map(|(k, _)| k);
+        assert!(drained.eq((5..45).step_by(4)));
+        assert_eq!(map.len(), 245);
+        map.clear();
+        assert_eq!(map.len(), 0);
+        assert!(map.iter().next().is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn linked_map_serde() {
+        let map = LinkedObjectMap::with_capacity(16);
+        for i in 0..100 {
+            map.insert_front(&i, format!("{}", i));
+        }
+        let json = serde_json::to_string(&map).unwrap();
+        let map = serde_json::from_str::<LinkedObjectMap<String>>(&json).unwrap();
+        assert!(map.all_keys().into_iter().eq((0..100).rev()));
+        for (key, node) in map.iter() {
+            assert_eq!(**node, format!("{}", key));
+        }
+        let none_key = format!("{{\"{}\":\"\"}}", NONE_KEY);
+        assert!(serde_json::from_str::<LinkedObjectMap<String>>(&none_key).is_err());
+    }
+
     #[test]
     pub fn linked_map_insertions() {
         let _ = env_logger::try_init();
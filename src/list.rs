@@ -1,54 +1,63 @@
 // usize lock-free, wait free paged linked list stack
 use crate::align_padding;
-use core::alloc::Layout;
+use core::alloc::{Allocator, Layout};
 use core::borrow::BorrowMut;
 use core::cmp::min;
 use core::ops::Deref;
 use core::ptr;
-use core::ptr::null_mut;
+use core::ptr::{null_mut, NonNull};
 use core::sync::atomic::Ordering::{AcqRel, Acquire, Release};
 use core::sync::atomic::{AtomicPtr, AtomicUsize};
 use core::{intrinsics, mem};
 use crossbeam_utils::Backoff;
 #[cfg(feature = "exchange_backoff")]
 use exchange::*;
-use std::alloc::{GlobalAlloc, System};
+use std::alloc::{handle_alloc_error, System};
 
 const CACHE_LINE_SIZE: usize = 64;
 const EMPTY_SLOT: usize = 0;
 const SENTINEL_SLOT: usize = 1;
 
-struct BufferMeta<T: Default, A: GlobalAlloc + Default> {
+struct BufferMeta<T: Default, A: Allocator + Clone> {
     head: AtomicUsize,
     next: AtomicPtr<BufferMeta<T, A>>,
     refs: AtomicUsize,
     lower_bound: usize,
     tuple_size: usize,
     total_size: usize,
+    alloc: A,
 }
 
-pub struct List<T: Default + Copy, A: GlobalAlloc + Default> {
+pub struct List<T: Default + Copy, A: Allocator + Clone> {
     head: AtomicPtr<BufferMeta<T, A>>,
     count: AtomicUsize,
     buffer_cap: usize,
+    alloc: A,
     #[cfg(feature = "exchange_backoff")]
     exchange: ExchangeArray<T, A>,
 }
 
-pub struct ListIterator<T: Default + Copy, A: GlobalAlloc + Default> {
+pub struct ListIterator<T: Default + Copy, A: Allocator + Clone> {
     buffer: BufferRef<T, A>,
     current: usize,
 }
 
-impl<T: Default + Copy, A: GlobalAlloc + Default> List<T, A> {
+impl<T: Default + Copy, A: Allocator + Clone + Default> List<T, A> {
     pub fn new(buffer_cap: usize) -> Self {
-        let first_buffer = BufferMeta::new(buffer_cap);
+        Self::new_in(buffer_cap, A::default())
+    }
+}
+
+impl<T: Default + Copy, A: Allocator + Clone> List<T, A> {
+    pub fn new_in(buffer_cap: usize, alloc: A) -> Self {
+        let first_buffer = BufferMeta::new(buffer_cap, alloc.clone());
         Self {
             head: AtomicPtr::new(first_buffer),
             count: AtomicUsize::new(0),
             #[cfg(feature = "exchange_backoff")]
             exchange: ExchangeArray::new(),
             buffer_cap,
+            alloc,
         }
     }
 
@@ -68,7 +77,7 @@ impl<T: Default + Copy, A: GlobalAlloc + Default> List<T, A> {
             let next_pos = slot_pos + 1;
             if next_pos > self.buffer_cap {
                 // buffer overflow, make new and link to last buffer
-                let new_head = BufferMeta::new(self.buffer_cap);
+                let new_head = BufferMeta::new(self.buffer_cap, self.alloc.clone());
                 unsafe {
                     (*new_head).next.store(head_ptr, Release);
                     debug_assert_eq!((*new_head).total_size, page.total_size);
@@ -127,7 +136,7 @@ impl<T: Default + Copy, A: GlobalAlloc + Default> List<T, A> {
             let next_pos = slot_pos + 1;
             if next_pos > self.buffer_cap {
                 // buffer overflow, make new and link to last buffer
-                let new_head = BufferMeta::new(self.buffer_cap);
+                let new_head = BufferMeta::new(self.buffer_cap, self.alloc.clone());
                 unsafe {
                     (*new_head).next.store(head_ptr, Release);
                 }
@@ -274,7 +283,7 @@ impl<T: Default + Copy, A: GlobalAlloc + Default> List<T, A> {
                 }
             }
         }
-        let new_head_buffer = BufferMeta::new(self.buffer_cap);
+        let new_head_buffer = BufferMeta::new(self.buffer_cap, self.alloc.clone());
         let mut buffer_ptr = self.head.swap(new_head_buffer, AcqRel);
         let null = null_mut();
         let mut counter = 0;
@@ -288,7 +297,9 @@ impl<T: Default + Copy, A: GlobalAlloc + Default> List<T, A> {
         if other.count.load(Acquire) == 0 {
             return;
         }
-        let other_head = other.head.swap(BufferMeta::new(self.buffer_cap), AcqRel);
+        let other_head = other
+            .head
+            .swap(BufferMeta::new(self.buffer_cap, self.alloc.clone()), AcqRel);
         let other_count = other.count.swap(0, AcqRel);
         let mut other_tail = BufferMeta::borrow(other_head);
         // probe the last buffer in other link
@@ -327,7 +338,7 @@ impl<T: Default + Copy, A: GlobalAlloc + Default> List<T, A> {
     }
 }
 
-impl<T: Default + Copy, A: GlobalAlloc + Default> Drop for List<T, A> {
+impl<T: Default + Copy, A: Allocator + Clone> Drop for List<T, A> {
     fn drop(&mut self) {
         unsafe {
             let mut node_ptr = self.head.load(Acquire);
@@ -340,14 +351,14 @@ impl<T: Default + Copy, A: GlobalAlloc + Default> Drop for List<T, A> {
     }
 }
 
-impl<T: Default + Copy, A: GlobalAlloc + Default> Default for List<T, A> {
+impl<T: Default + Copy, A: Allocator + Clone + Default> Default for List<T, A> {
     fn default() -> Self {
         Self::new(32)
     }
 }
 
-impl<T: Default, A: GlobalAlloc + Default> BufferMeta<T, A> {
-    pub fn new(buffer_cap: usize) -> *mut BufferMeta<T, A> {
+impl<T: Default, A: Allocator + Clone> BufferMeta<T, A> {
+    pub fn new(buffer_cap: usize, alloc: A) -> *mut BufferMeta<T, A> {
         let self_size = mem::size_of::<Self>();
         let meta_size = self_size + align_padding(self_size, CACHE_LINE_SIZE);
         let slots_size = mem::size_of::<usize>();
@@ -363,7 +374,7 @@ impl<T: Default, A: GlobalAlloc + Default> BufferMeta<T, A> {
             tuple_size + align_padding(tuple_size, CACHE_LINE_SIZE)
         };
         let total_size = meta_size + tuple_size_aligned * buffer_cap;
-        let head_page = alloc_mem::<A>(total_size) as *mut Self;
+        let head_page = alloc_mem(&alloc, total_size) as *mut Self;
         let head_page_addr = head_page as usize;
         let slots_start = head_page_addr + meta_size;
         unsafe {
@@ -376,6 +387,7 @@ impl<T: Default, A: GlobalAlloc + Default> BufferMeta<T, A> {
                     lower_bound: slots_start,
                     tuple_size,
                     total_size,
+                    alloc,
                 },
             );
         }
@@ -398,7 +410,9 @@ impl<T: Default, A: GlobalAlloc + Default> BufferMeta<T, A> {
         if mem::needs_drop::<T>() {
             Self::flush_buffer(buffer_ref, &mut Some(|x| drop(x)), &mut 0);
         }
-        dealloc_mem::<A>(buffer as usize, total_size)
+        // The allocator lives in the buffer, move it out before the memory is gone
+        let alloc = unsafe { ptr::read(&buffer_ref.alloc) };
+        dealloc_mem(&alloc, buffer as usize, total_size)
     }
 
     // only use when the buffer is about to be be dead
@@ -499,17 +513,17 @@ impl<T: Default, A: GlobalAlloc + Default> BufferMeta<T, A> {
     }
 }
 
-struct BufferRef<T: Default, A: GlobalAlloc + Default> {
+struct BufferRef<T: Default, A: Allocator + Clone> {
     ptr: *mut BufferMeta<T, A>,
 }
 
-impl<T: Default, A: GlobalAlloc + Default> Drop for BufferRef<T, A> {
+impl<T: Default, A: Allocator + Clone> Drop for BufferRef<T, A> {
     fn drop(&mut self) {
         BufferMeta::unref(self.ptr);
     }
 }
 
-impl<T: Default, A: GlobalAlloc + Default> Deref for BufferRef<T, A> {
+impl<T: Default, A: Allocator + Clone> Deref for BufferRef<T, A> {
     type Target = BufferMeta<T, A>;
 
     fn deref(&self) -> &Self::Target {
@@ -517,7 +531,7 @@ impl<T: Default, A: GlobalAlloc + Default> Deref for BufferRef<T, A> {
     }
 }
 
-impl<T: Default + Clone + Copy, A: GlobalAlloc + Default> Iterator for ListIterator<T, A> {
+impl<T: Default + Clone + Copy, A: Allocator + Clone> Iterator for ListIterator<T, A> {
     type Item = (usize, T);
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -547,19 +561,25 @@ impl<T: Default + Clone + Copy, A: GlobalAlloc + Default> Iterator for ListItera
     }
 }
 
-pub struct WordList<A: GlobalAlloc + Default = System> {
+pub struct WordList<A: Allocator + Clone = System> {
     inner: List<(), A>,
 }
 
-impl<A: GlobalAlloc + Default> WordList<A> {
+impl<A: Allocator + Clone + Default> WordList<A> {
     pub fn with_capacity(cap: usize) -> Self {
-        Self {
-            inner: List::new(cap),
-        }
+        Self::with_capacity_in(cap, A::default())
     }
     pub fn new() -> Self {
         Self::with_capacity(512)
     }
+}
+
+impl<A: Allocator + Clone> WordList<A> {
+    pub fn with_capacity_in(cap: usize, alloc: A) -> Self {
+        Self {
+            inner: List::new_in(cap, alloc),
+        }
+    }
     pub fn push(&self, data: usize) {
         debug_assert_ne!(data, 0);
         debug_assert_ne!(data, 1);
@@ -591,19 +611,25 @@ impl<A: GlobalAlloc + Default> WordList<A> {
     }
 }
 
-pub struct ObjectList<T: Default + Copy, A: GlobalAlloc + Default> {
+pub struct ObjectList<T: Default + Copy, A: Allocator + Clone> {
     inner: List<T, A>,
 }
 
-impl<T: Default + Copy, A: GlobalAlloc + Default> ObjectList<T, A> {
+impl<T: Default + Copy, A: Allocator + Clone + Default> ObjectList<T, A> {
     pub fn with_capacity(cap: usize) -> Self {
-        Self {
-            inner: List::new(cap),
-        }
+        Self::with_capacity_in(cap, A::default())
     }
     pub fn new() -> Self {
         Self::with_capacity(512)
     }
+}
+
+impl<T: Default + Copy, A: Allocator + Clone> ObjectList<T, A> {
+    pub fn with_capacity_in(cap: usize, alloc: A) -> Self {
+        Self {
+            inner: List::new_in(cap, alloc),
+        }
+    }
     pub fn push(&self, data: T) {
         self.inner.push(!0, data)
     }
@@ -633,20 +659,21 @@ impl<T: Default + Copy, A: GlobalAlloc + Default> ObjectList<T, A> {
 }
 
 #[inline]
-pub fn dealloc_mem<A: GlobalAlloc + Default>(ptr: usize, size: usize) {
-    let a = A::default();
+pub fn dealloc_mem<A: Allocator>(alloc: &A, ptr: usize, size: usize) {
     let align = 16;
     let layout = Layout::from_size_align(size, align).unwrap();
-    unsafe { a.dealloc(ptr as *mut u8, layout) }
+    unsafe { alloc.deallocate(NonNull::new_unchecked(ptr as *mut u8), layout) }
 }
 
 #[inline]
-pub fn alloc_mem<A: GlobalAlloc + Default>(size: usize) -> usize {
-    let a = A::default();
+pub fn alloc_mem<A: Allocator>(alloc: &A, size: usize) -> usize {
     let align = 16;
     let layout = Layout::from_size_align(size, align).unwrap();
     // must be all zeroed
-    (unsafe { a.alloc_zeroed(layout) }) as usize
+    match alloc.allocate_zeroed(layout) {
+        Ok(ptr) => ptr.cast::<u8>().as_ptr() as usize,
+        Err(_) => handle_alloc_error(layout),
+    }
 }
 
 #[cfg(feature = "exchange_backoff")]
@@ -677,7 +704,7 @@ mod exchange {
         data_state: AtomicUsize,
     }
 
-    pub struct ExchangeArray<T: Default + Copy, A: GlobalAlloc + Default> {
+    pub struct ExchangeArray<T: Default + Copy, A: Allocator + Clone> {
         rand: XorRand,
         shadow: PhantomData<A>,
         capacity: usize,
@@ -805,7 +832,7 @@ mod exchange {
     unsafe impl<T: Default + Copy> Sync for ExchangeSlot<T> {}
     unsafe impl<T: Default + Copy> Send for ExchangeSlot<T> {}
 
-    impl<T: Default + Copy, A: GlobalAlloc + Default> ExchangeArray<T, A> {
+    impl<T: Default + Copy, A: Allocator + Clone> ExchangeArray<T, A> {
         pub fn new() -> Self {
             let num_cpus = num_cpus::get();
             let default_capacity = num_cpus >> 3;
@@ -839,8 +866,8 @@ mod exchange {
         }
     }
 
-    unsafe impl<T: Default + Copy, A: GlobalAlloc + Default> Send for ExchangeArray<T, A> {}
-    unsafe impl<T: Default + Copy, A: GlobalAlloc + Default> Sync for ExchangeArray<T, A> {}
+    unsafe impl<T: Default + Copy, A: Allocator + Clone> Send for ExchangeArray<T, A> {}
+    unsafe impl<T: Default + Copy, A: Allocator + Clone> Sync for ExchangeArray<T, A> {}
 
     #[cfg(test)]
     mod test {
@@ -910,10 +937,100 @@ mod exchange {
     }
 }
 
+// Lists are serialized from the top by `iter` and pushed back from the bottom on
+// deserialization, so items pop in the same order from the new list
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use super::*;
+    use serde::de::{Deserialize, Deserializer, Error, SeqAccess, Visitor};
+    use serde::ser::{Serialize, Serializer};
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    impl<T: Default + Copy + Serialize, A: Allocator + Clone> Serialize for List<T, A> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(self.iter())
+        }
+    }
+
+    struct ListVisitor<T, A>(PhantomData<(T, A)>);
+
+    impl<'de, T: Default + Copy + Deserialize<'de>, A: Allocator + Clone + Default> Visitor<'de>
+        for ListVisitor<T, A>
+    {
+        type Value = List<T, A>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a sequence of flags and items")
+        }
+
+        fn visit_seq<S: SeqAccess<'de>>(self, mut access: S) -> Result<Self::Value, S::Error> {
+            let mut items = Vec::new();
+            while let Some((flag, data)) = access.next_element::<(usize, T)>()? {
+                if flag == EMPTY_SLOT || flag == SENTINEL_SLOT {
+                    return Err(S::Error::custom(format!("flag {} is reserved", flag)));
+                }
+                items.push((flag, data));
+            }
+            let list = List::default();
+            for (flag, data) in items.into_iter().rev() {
+                list.exclusive_push(flag, data);
+            }
+            Ok(list)
+        }
+    }
+
+    impl<'de, T: Default + Copy + Deserialize<'de>, A: Allocator + Clone + Default> Deserialize<'de>
+        for List<T, A>
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_seq(ListVisitor(PhantomData))
+        }
+    }
+
+    impl<T: Default + Copy + Serialize, A: Allocator + Clone> Serialize for ObjectList<T, A> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(self.iter().map(|(_, data)| data))
+        }
+    }
+
+    struct ObjectListVisitor<T, A>(PhantomData<(T, A)>);
+
+    impl<'de, T: Default + Copy + Deserialize<'de>, A: Allocator + Clone + Default> Visitor<'de>
+        for ObjectListVisitor<T, A>
+    {
+        type Value = ObjectList<T, A>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a sequence of items")
+        }
+
+        fn visit_seq<S: SeqAccess<'de>>(self, mut access: S) -> Result<Self::Value, S::Error> {
+            let mut items = Vec::new();
+            while let Some(data) = access.next_element::<T>()? {
+                items.push(data);
+            }
+            let list = ObjectList::new();
+            for data in items.into_iter().rev() {
+                list.exclusive_push(data);
+            }
+            Ok(list)
+        }
+    }
+
+    impl<'de, T: Default + Copy + Deserialize<'de>, A: Allocator + Clone + Default> Deserialize<'de>
+        for ObjectList<T, A>
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_seq(ObjectListVisitor(PhantomData))
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::list::*;
-    use std::alloc::{Global, System};
+    use std::alloc::{AllocError, Global, System};
     use std::collections::BTreeSet;
     use std::sync::atomic::AtomicUsize;
     use std::sync::atomic::Ordering::Relaxed;
@@ -947,6 +1064,63 @@ mod test {
         assert_eq!(list.count(), 0);
     }
 
+    #[derive(Clone, Default)]
+    struct CountingAlloc(Arc<AtomicUsize>);
+
+    unsafe impl Allocator for CountingAlloc {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.0.fetch_add(layout.size(), Relaxed);
+            System.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.0.fetch_sub(layout.size(), Relaxed);
+            System.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    pub fn stateful_allocator() {
+        let alloc = CountingAlloc::default();
+        let list = ObjectList::with_capacity_in(4, alloc.clone());
+        let first_buffer = alloc.0.load(Relaxed);
+        assert!(first_buffer > 0);
+        for i in 0..100usize {
+            list.push(i);
+        }
+        assert!(alloc.0.load(Relaxed) > first_buffer);
+        for i in (0..100).rev() {
+            assert_eq!(list.pop(), Some(i));
+        }
+        drop(list);
+        assert_eq!(alloc.0.load(Relaxed), 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn serde_round_trip() {
+        let list = ObjectList::<usize, System>::with_capacity(4);
+        for i in 0..100usize {
+            list.push(i);
+        }
+        let json = serde_json::to_string(&list).unwrap();
+        let list = serde_json::from_str::<ObjectList<usize, System>>(&json).unwrap();
+        assert_eq!(list.count(), 100);
+        for i in (0..100).rev() {
+            assert_eq!(list.pop(), Some(i));
+        }
+        let list = List::<usize, System>::new(4);
+        for i in 2..100usize {
+            list.push(i, i * 2);
+        }
+        let json = serde_json::to_string(&list).unwrap();
+        let list = serde_json::from_str::<List<usize, System>>(&json).unwrap();
+        for i in (2..100).rev() {
+            assert_eq!(list.pop(), Some((i, i * 2)));
+        }
+        assert!(serde_json::from_str::<List<usize, System>>("[[1,0]]").is_err());
+    }
+
     #[test]
     pub fn parallel_insertion() {}
 
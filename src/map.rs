@@ -1,15 +1,22 @@
 // usize to usize lock-free, wait free table
 use crate::align_padding;
+use crate::linked_map::LinkedObjectMap;
+use crate::owned_map::OwnedMap;
+use crate::pod_map::{PodMap, WordRepr};
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::alloc::{GlobalAlloc, Layout};
+use core::alloc::{Allocator, Layout};
+use core::cell::Cell;
 use core::hash::Hasher;
 use core::marker::PhantomData;
 use core::ops::Deref;
+use core::ptr::NonNull;
 use core::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release, SeqCst};
-use core::sync::atomic::{compiler_fence, fence, AtomicU64, AtomicUsize};
+use core::sync::atomic::{compiler_fence, fence, AtomicBool, AtomicU64, AtomicUsize};
 use core::{intrinsics, mem, ptr};
 use crossbeam_epoch::*;
-use std::alloc::System;
+pub use crossbeam_epoch::{pin, Guard};
+use std::alloc::{handle_alloc_error, System};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::Hash;
 use std::ops::DerefMut;
@@ -26,6 +33,8 @@ const VAL_BIT_MASK: usize = !0 << 1 >> 1;
 const INV_VAL_BIT_MASK: usize = !VAL_BIT_MASK;
 const MUTEX_BIT_MASK: usize = !WORD_MUTEX_DATA_BIT_MASK & VAL_BIT_MASK;
 const ENTRY_SIZE: usize = mem::size_of::<EntryTemplate>();
+const MIGRATION_RANGE: usize = 1024;
+const PREFETCH_AHEAD: usize = 16;
 
 struct Value {
     raw: usize,
@@ -46,7 +55,9 @@ enum ModResult<V> {
     Fail,
     Sentinel,
     NotFound,
-    Done(usize, Option<V>, usize), // _, value, index
+    Done(usize, Option<V>, usize),  // _, value, index
+    Revived(usize),                 // index of the tombstone brought back
+    Computed(Option<V>, Option<V>), // value before and after
     TableFull,
     Aborted,
 }
@@ -55,11 +66,23 @@ enum ModOp<'a, V> {
     Insert(usize, &'a V),
     UpsertFastVal(usize),
     AttemptInsert(usize, &'a V),
-    SwapFastVal(Box<dyn Fn(usize) -> Option<usize>>),
+    SwapFastVal(&'a dyn Fn(usize) -> Option<usize>),
+    Compute(usize, &'a dyn Fn(Option<&V>) -> Option<V>),
+    Init(usize, Option<&'a dyn Fn(&mut V)>, &'a dyn Fn() -> V),
     Sentinel,
     Tombstone,
 }
 
+// Only holds references, a retry can take it again
+impl<'a, V> Clone for ModOp<'a, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, V> Copy for ModOp<'a, V> {}
+
+#[derive(Clone, Copy)]
 pub enum InsertOp {
     Insert,
     UpsertFast,
@@ -71,95 +94,289 @@ enum ResizeResult {
     SwapFailed,
     ChunkChanged,
     Done,
+    // The allocator gave no memory for the new chunk, the old one stays
+    NoMemory,
 }
 
-enum SwapResult<'a, K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default> {
+enum SwapResult<'a, K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone> {
     Succeed(usize, usize, Shared<'a, ChunkPtr<K, V, A, ALLOC>>),
     NotFound,
     Failed,
     Aborted,
 }
 
-pub struct Chunk<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default> {
+// Chunks hold no address of their own memory, the entries and the attachment slots are found
+// from where the chunk is, so a chunk can be mapped at a different address in every process
+pub struct Chunk<K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone> {
     capacity: usize,
-    base: usize,
+    // Offset of the entries from the chunk
+    data: usize,
     occu_limit: usize,
     occupation: AtomicUsize,
     empty_entries: AtomicUsize,
+    // Next entry index to claim and number of entries migrated, when this chunk is the old one
+    migrate_cursor: AtomicUsize,
+    migrated: AtomicUsize,
+    // Entries are dropped instead of migrated, when this chunk is the old one of a clear
+    discard: AtomicBool,
     total_size: usize,
     attachment: A,
-    shadow: PhantomData<(K, V, ALLOC)>,
+    alloc: ALLOC,
+    shadow: PhantomData<(K, V)>,
 }
 
-pub struct ChunkPtr<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default> {
+pub struct ChunkPtr<K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone> {
     ptr: *mut Chunk<K, V, A, ALLOC>,
 }
 
-pub struct Table<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default, H: Hasher + Default> {
+pub struct Table<K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone, H: Hasher + Default> {
     new_chunk: Atomic<ChunkPtr<K, V, A, ALLOC>>,
     chunk: Atomic<ChunkPtr<K, V, A, ALLOC>>,
     count: AtomicUsize,
     epoch: AtomicUsize,
     timestamp: AtomicU64,
+    init_cap: usize,
+    policy: Arc<dyn ResizePolicy>,
+    alloc: ALLOC,
+    // The table stays on a chunk it does not own and never migrates, see `Table::fixed_at`
+    fixed: bool,
     mark: PhantomData<H>,
 }
 
+/// Decides when and how the chunks of a table shall resize
+pub trait ResizePolicy: Send + Sync {
+    /// Fraction of the slots can be occupied before the chunk grows
+    fn max_load_factor(&self) -> f64 {
+        0.75
+    }
+
+    /// Capacity of the chunk replacing a full chunk of `cap` slots, raised to at least twice
+    /// `cap` and rounded up to a power of 2 by the table.
+    /// `epoch` is the number of migrations the table have been through times two and
+    /// `elapsed` is milliseconds since last migration according to `now`
+    fn grow_capacity(&self, cap: usize, epoch: usize, elapsed: u64) -> usize {
+        let mut cap = cap << 1;
+        if cap < 2048 {
+            cap <<= 1;
+        }
+        if epoch < 5 {
+            cap <<= 1;
+        }
+        if elapsed < 1000 {
+            cap <<= 1;
+        }
+        cap
+    }
+
+    /// Number of tombstones in a chunk of `cap` slots, more than which the chunk will be
+    /// compacted in the same size instead of growing
+    fn tombstone_threshold(&self, cap: usize) -> usize {
+        cap >> 1
+    }
+
+    /// Number of live entries in a chunk of `cap` slots, less than which the chunk will shrink
+    fn shrink_threshold(&self, cap: usize) -> usize {
+        cap >> 3
+    }
+
+    /// Clock in milliseconds
+    fn now(&self) -> u64 {
+        timestamp()
+    }
+
+    /// Most entries of the old chunk a mutating operation migrates during a resize.
+    /// With `None`, the thread starting the resize keeps migrating until no range is left,
+    /// with `Some(n)` the migration is spread over the following operations and reads
+    /// consult both chunks until it is finished. The new chunk is then sized for the live
+    /// entries plus one new key per batch of the old chunk, so the copy keeps ahead of the
+    /// writers: tables grow larger and shrink less with smaller batches. Writers that outrun
+    /// it anyway, by racing on other threads, wait for room migrating one batch at a time
+    fn migration_batch(&self) -> Option<usize> {
+        None
+    }
+}
+
+pub struct DefaultResizePolicy;
+
+impl ResizePolicy for DefaultResizePolicy {}
+
 impl<
         K: Clone + Hash + Eq,
         V: Clone,
-        A: Attachment<K, V>,
-        ALLOC: GlobalAlloc + Default,
+        A: Attachment<K, V, ALLOC>,
+        ALLOC: Allocator + Clone + Default,
         H: Hasher + Default,
     > Table<K, V, A, ALLOC, H>
 {
     pub fn with_capacity(cap: usize) -> Self {
+        Self::with_policy_in(cap, Arc::new(DefaultResizePolicy), ALLOC::default())
+    }
+
+    pub fn new() -> Self {
+        Self::with_capacity(64)
+    }
+}
+
+impl<
+        K: Clone + Hash + Eq,
+        V: Clone,
+        A: Attachment<K, V, ALLOC>,
+        ALLOC: Allocator + Clone,
+        H: Hasher + Default,
+    > Table<K, V, A, ALLOC, H>
+{
+    pub fn with_policy_in(cap: usize, policy: Arc<dyn ResizePolicy>, alloc: ALLOC) -> Self {
         if !is_power_of_2(cap) {
             panic!("capacity is not power of 2");
         }
         // Each entry key value pair is 2 words
         // steal 1 bit in the MSB of value indicate Prime(1)
-        let chunk = Chunk::alloc_chunk(cap);
+        let chunk = Chunk::alloc_chunk(cap, occupation_limit(&*policy, cap), alloc.clone());
+        Self::with_chunk(chunk, cap, policy, alloc)
+    }
+
+    // Table on a chunk of the initial capacity, allocated by `alloc`
+    fn with_chunk(
+        chunk: *mut Chunk<K, V, A, ALLOC>,
+        cap: usize,
+        policy: Arc<dyn ResizePolicy>,
+        alloc: ALLOC,
+    ) -> Self {
         Self {
             chunk: Atomic::new(ChunkPtr::new(chunk)),
             new_chunk: Atomic::null(),
             count: AtomicUsize::new(0),
             epoch: AtomicUsize::new(0),
-            timestamp: AtomicU64::new(timestamp()),
+            timestamp: AtomicU64::new(policy.now()),
+            init_cap: cap,
+            policy,
+            alloc,
+            fixed: false,
             mark: PhantomData,
         }
     }
 
-    pub fn new() -> Self {
-        Self::with_capacity(64)
+    /// Table on the chunk at `addr`, in zeroed memory of `fixed_size(cap)` bytes aligned to 64
+    /// that tables of other processes may map at other addresses. The chunk is written if
+    /// `init`, or taken as it is. The table never migrates and never releases the chunk, new
+    /// keys are declined once the occupied slots reach the load limit. The allocator is kept in
+    /// the chunk, it shall take no space for every table to read the same chunk
+    pub(crate) unsafe fn fixed_at(addr: usize, cap: usize, init: bool, alloc: ALLOC) -> Self {
+        assert!(is_power_of_2(cap), "capacity is not power of 2");
+        assert_eq!(
+            mem::size_of::<ALLOC>(),
+            0,
+            "allocator of a fixed chunk takes space"
+        );
+        let policy: Arc<dyn ResizePolicy> = Arc::new(DefaultResizePolicy);
+        let chunk = addr as *mut Chunk<K, V, A, ALLOC>;
+        if init {
+            Chunk::init(chunk, cap, occupation_limit(&*policy, cap), alloc.clone());
+        }
+        Self {
+            chunk: Atomic::new(ChunkPtr::new(chunk)),
+            new_chunk: Atomic::null(),
+            count: AtomicUsize::new(0),
+            epoch: AtomicUsize::new(0),
+            timestamp: AtomicU64::new(policy.now()),
+            init_cap: cap,
+            policy,
+            alloc,
+            fixed: true,
+            mark: PhantomData,
+        }
+    }
+
+    /// Bytes of memory a table of `cap` slots takes on `fixed_at`
+    pub(crate) fn fixed_size(cap: usize) -> usize {
+        Chunk::<K, V, A, ALLOC>::total_size_of(cap)
+    }
+
+    pub fn get(&self, key: &K, fkey: usize, read_attachment: bool) -> Option<(usize, Option<V>)>
+    where
+        V: Clone,
+    {
+        let guard = crossbeam_epoch::pin();
+        self.find_hashed(
+            key,
+            fkey,
+            hash::<H>(fkey),
+            read_attachment,
+            |chunk, idx| chunk.attachment.clone_value(idx),
+            &guard,
+        )
+    }
+
+    /// The value is borrowed from its slot, it stays alive as long as the guard is pinned
+    pub fn get_ref<'a>(
+        &'a self,
+        key: &K,
+        fkey: usize,
+        read_attachment: bool,
+        guard: &'a Guard,
+    ) -> Option<(usize, Option<&'a V>)>
+    where
+        A: StableAttachment<K, V, ALLOC>,
+    {
+        self.get_ref_hashed(key, fkey, hash::<H>(fkey), read_attachment, guard)
+    }
+
+    fn get_ref_hashed<'a>(
+        &'a self,
+        key: &K,
+        fkey: usize,
+        hash: usize,
+        read_attachment: bool,
+        guard: &'a Guard,
+    ) -> Option<(usize, Option<&'a V>)>
+    where
+        A: StableAttachment<K, V, ALLOC>,
+    {
+        self.find_hashed(
+            key,
+            fkey,
+            hash,
+            read_attachment,
+            |chunk, idx| chunk.attachment.value(idx),
+            guard,
+        )
     }
 
-    pub fn get(&self, key: &K, fkey: usize, read_attachment: bool) -> Option<(usize, Option<V>)> {
-        enum FromChunkRes<V> {
-            Value(usize, Value, Option<V>, usize, usize), // Last one is idx
+    // Find the live entry of the key, `read` takes the value out of the slot of the entry
+    fn find_hashed<'a, T, R: Fn(&'a Chunk<K, V, A, ALLOC>, usize) -> T>(
+        &'a self,
+        key: &K,
+        fkey: usize,
+        hash: usize,
+        read_attachment: bool,
+        read: R,
+        guard: &'a Guard,
+    ) -> Option<(usize, Option<T>)> {
+        enum FromChunkRes<T> {
+            Value(usize, Value, Option<T>, usize, usize), // Last one is idx
             Prime,
             None,
             Sentinel,
         }
-        let guard = crossbeam_epoch::pin();
         let backoff = crossbeam_utils::Backoff::new();
-        let hash = hash::<H>(fkey);
         loop {
             let epoch = self.now_epoch();
-            let chunk_ptr = self.chunk.load(Acquire, &guard);
-            let new_chunk_ptr = self.new_chunk.load(Acquire, &guard);
+            let chunk_ptr = self.chunk.load(Acquire, guard);
+            let new_chunk_ptr = self.new_chunk.load(Acquire, guard);
             let chunk = unsafe { chunk_ptr.deref() };
             let new_chunk = Self::to_chunk_ref(epoch, &chunk_ptr, &new_chunk_ptr);
             debug_assert!(!chunk_ptr.is_null());
             let get_from =
-                |chunk: &Chunk<K, V, A, ALLOC>, migrating: Option<&ChunkPtr<K, V, A, ALLOC>>| {
-                    let (val, idx, addr) = self.get_from_chunk(&*chunk, hash, key, fkey, migrating);
+                |chunk: &'a Chunk<K, V, A, ALLOC>, migrating: Option<&ChunkPtr<K, V, A, ALLOC>>| {
+                    let (val, idx, addr) =
+                        self.get_from_chunk(&*chunk, hash, key, fkey, migrating, guard);
                     match val.parsed {
                         ParsedValue::Empty | ParsedValue::Val(0) => FromChunkRes::None,
                         ParsedValue::Val(v) => FromChunkRes::Value(
                             v,
                             val,
                             if Self::can_attach() && read_attachment {
-                                Some(chunk.attachment.get(idx).1)
+                                Some(read(chunk, idx))
                             } else {
                                 None
                             },
@@ -173,7 +390,7 @@ impl<
             return match get_from(&chunk, new_chunk) {
                 FromChunkRes::Value(fval, val, attach_val, idx, addr) => {
                     if let Some(new_chunk) = new_chunk {
-                        self.migrate_entry(fkey, idx, val, chunk, new_chunk, addr, &mut 0);
+                        self.migrate_entry(fkey, idx, val, chunk, new_chunk, addr, &mut 0, guard);
                     }
                     Some((fval, attach_val))
                 }
@@ -255,30 +472,73 @@ impl<
         fkey: usize,
         fvalue: usize,
     ) -> Option<(usize, V)> {
-        let backoff = crossbeam_utils::Backoff::new();
+        self.fallible_insert(op, key, value, fkey, fvalue)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// `insert` that gives an error instead of panicking when a table on `fixed_at` has no slot
+    /// left for a new key, or a full table cannot grow as its allocator has no memory left
+    pub(crate) fn fallible_insert(
+        &self,
+        op: InsertOp,
+        key: &K,
+        value: Option<V>,
+        fkey: usize,
+        fvalue: usize,
+    ) -> Result<Option<(usize, V)>, FullError> {
         let guard = crossbeam_epoch::pin();
-        let hash = hash::<H>(fkey);
+        self.insert_hashed(op, key, value, fkey, hash::<H>(fkey), fvalue, &guard)
+    }
+
+    fn insert_hashed(
+        &self,
+        op: InsertOp,
+        key: &K,
+        value: Option<V>,
+        fkey: usize,
+        hash: usize,
+        fvalue: usize,
+        guard: &Guard,
+    ) -> Result<Option<(usize, V)>, FullError> {
+        let backoff = crossbeam_utils::Backoff::new();
         loop {
             let epoch = self.now_epoch();
             // trace!("Inserting key: {}, value: {}", fkey, fvalue);
-            let chunk_ptr = self.chunk.load(Acquire, &guard);
-            let new_chunk_ptr = self.new_chunk.load(Acquire, &guard);
+            let chunk_ptr = self.chunk.load(Acquire, guard);
+            let new_chunk_ptr = self.new_chunk.load(Acquire, guard);
             let new_chunk = Self::to_chunk_ref(epoch, &chunk_ptr, &new_chunk_ptr);
             if new_chunk.is_none() {
-                match self.check_migration(chunk_ptr, &guard) {
+                match self.check_migration(chunk_ptr, guard) {
                     ResizeResult::Done | ResizeResult::SwapFailed | ResizeResult::ChunkChanged => {
                         debug!("Retry insert due to resize");
                         backoff.spin();
                         continue;
                     }
-                    ResizeResult::NoNeed => {}
+                    // Slots above the load limit are still free, new keys fail once it is hit
+                    ResizeResult::NoNeed | ResizeResult::NoMemory => {}
                 }
             } else if new_chunk_ptr.is_null() {
                 // Copying, must have new chunk
                 warn!("Chunk ptrs does not consist with epoch");
                 continue;
+            } else if self.help_migration(chunk_ptr, new_chunk_ptr, self.migration_batch(), guard) {
+                // This thread have finished the migration, start over with the new chunk
+                continue;
+            } else if self.filling_up(unsafe { chunk_ptr.deref() }, new_chunk.unwrap()) {
+                // Entries yet to migrate may not fit if more are inserted, wait for the
+                // migration to finish and let the new chunk resize in turn
+                self.help_migration(chunk_ptr, new_chunk_ptr, self.migration_batch(), guard);
+                backoff.spin();
+                continue;
             }
             let chunk = unsafe { chunk_ptr.deref() };
+            if let Some(new_chunk) = new_chunk {
+                // Bring the key over first, so the new chunk can tell what is replaced
+                if !self.bring_over(chunk, new_chunk, hash, key, fkey, guard) {
+                    backoff.spin();
+                    continue;
+                }
+            }
             let modify_chunk = if let Some(new_chunk) = new_chunk {
                 new_chunk
             } else {
@@ -291,13 +551,17 @@ impl<
                 InsertOp::TryInsert => ModOp::AttemptInsert(masked_value, value.as_ref().unwrap()),
             };
             let value_insertion =
-                self.modify_entry(&*modify_chunk, hash, key, fkey, mod_op, None, &guard);
+                self.modify_entry(&*modify_chunk, hash, key, fkey, mod_op, None, guard);
             let mut result = None;
             match value_insertion {
                 ModResult::Done(_, _, _) => {
                     modify_chunk.occupation.fetch_add(1, Relaxed);
                     self.count.fetch_add(1, Relaxed);
                 }
+                ModResult::Revived(_) => {
+                    // The tombstone was already counted in the occupation
+                    self.count.fetch_add(1, Relaxed);
+                }
                 ModResult::Replaced(fv, v, _) | ModResult::Existed(fv, v) => result = Some((fv, v)),
                 ModResult::Fail => {
                     // If fail insertion then retry
@@ -312,6 +576,7 @@ impl<
                     backoff.spin();
                     continue;
                 }
+                ModResult::TableFull if self.fixed => return Err(FullError),
                 ModResult::TableFull => {
                     trace!(
                         "Insertion is too fast, copying {}, cap {}, count {}, old {:?}, new {:?}.",
@@ -321,7 +586,9 @@ impl<
                         chunk_ptr,
                         new_chunk_ptr
                     );
-                    self.do_migration(chunk_ptr, &guard);
+                    if let ResizeResult::NoMemory = self.do_migration(chunk_ptr, guard) {
+                        return Err(FullError);
+                    }
                     backoff.spin();
                     continue;
                 }
@@ -332,6 +599,7 @@ impl<
                 }
                 ModResult::NotFound => unreachable!("Not Found on insertion is impossible"),
                 ModResult::Aborted => unreachable!("Should no abort"),
+                ModResult::Computed(_, _) => unreachable!("Insertion computed"),
             }
             if new_chunk.is_some() {
                 dfence();
@@ -348,10 +616,171 @@ impl<
                     fkey,
                     fvalue
                 );
-                self.modify_entry(chunk, hash, key, fkey, ModOp::Sentinel, new_chunk, &guard);
+                self.modify_entry(chunk, hash, key, fkey, ModOp::Sentinel, new_chunk, guard);
             }
             // trace!("Inserted key {}, with value {}", fkey, fvalue);
-            return result;
+            return Ok(result);
+        }
+    }
+
+    /// Replace the value of the key by what `func` returns for it, as one atomic step on the
+    /// entry. `func` is given none for absent keys, returning none removes the entry or leaves
+    /// the key absent. The slot is primed while `func` runs, so it may be called more than once
+    /// on contention but never on a value that is replaced before its result is put in. The
+    /// slot is put back as it was if `func` unwinds. Writing the key from `func` waits on the
+    /// prime forever, it deadlocks.
+    /// Returns the value before and after, panics on a new key where `fallible_insert` fails
+    pub fn compute(
+        &self,
+        key: &K,
+        fkey: usize,
+        fvalue: usize,
+        func: &dyn Fn(Option<&V>) -> Option<V>,
+    ) -> (Option<V>, Option<V>) {
+        self.compute_with(key, fkey, ModOp::Compute(fvalue & VAL_BIT_MASK, func))
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Get the value of the key, or insert the one `init` makes. The slot is owned before
+    /// `init` or `modify` is called, so each of them runs at most once and concurrent callers
+    /// wait for the value instead of making their own, so both should be quick. The slot is
+    /// given back as it was if either of them unwinds.
+    /// `modify` changes an existing value
+    pub fn get_or_init(
+        &self,
+        key: &K,
+        fkey: usize,
+        fvalue: usize,
+        modify: Option<&dyn Fn(&mut V)>,
+        init: &dyn Fn() -> V,
+    ) -> V {
+        let mod_op = ModOp::Init(fvalue & VAL_BIT_MASK, modify, init);
+        self.compute_with(key, fkey, mod_op)
+            .unwrap_or_else(|e| panic!("{}", e))
+            .1
+            .unwrap()
+    }
+
+    fn compute_with(
+        &self,
+        key: &K,
+        fkey: usize,
+        mod_op: ModOp<V>,
+    ) -> Result<(Option<V>, Option<V>), FullError> {
+        debug_assert!(Self::can_attach());
+        let backoff = crossbeam_utils::Backoff::new();
+        let guard = crossbeam_epoch::pin();
+        let hash = hash::<H>(fkey);
+        loop {
+            let epoch = self.now_epoch();
+            let chunk_ptr = self.chunk.load(Acquire, &guard);
+            let new_chunk_ptr = self.new_chunk.load(Acquire, &guard);
+            let new_chunk = Self::to_chunk_ref(epoch, &chunk_ptr, &new_chunk_ptr);
+            if new_chunk.is_none() {
+                match self.check_migration(chunk_ptr, &guard) {
+                    ResizeResult::Done | ResizeResult::SwapFailed | ResizeResult::ChunkChanged => {
+                        backoff.spin();
+                        continue;
+                    }
+                    ResizeResult::NoNeed | ResizeResult::NoMemory => {}
+                }
+            } else if new_chunk_ptr.is_null() {
+                continue;
+            } else if self.help_migration(chunk_ptr, new_chunk_ptr, self.migration_batch(), &guard)
+            {
+                continue;
+            } else if self.filling_up(unsafe { chunk_ptr.deref() }, new_chunk.unwrap()) {
+                self.help_migration(chunk_ptr, new_chunk_ptr, self.migration_batch(), &guard);
+                backoff.spin();
+                continue;
+            }
+            let chunk = unsafe { chunk_ptr.deref() };
+            if let Some(new_chunk) = new_chunk {
+                // Same as insert, the key have to be in the new chunk to compute on it
+                if !self.bring_over(chunk, new_chunk, hash, key, fkey, &guard) {
+                    backoff.spin();
+                    continue;
+                }
+            }
+            let modify_chunk = new_chunk.map(|c| &**c).unwrap_or(chunk);
+            let result =
+                match self.modify_entry(modify_chunk, hash, key, fkey, mod_op, None, &guard) {
+                    ModResult::Done(_, new_value, _) => {
+                        modify_chunk.occupation.fetch_add(1, Relaxed);
+                        self.count.fetch_add(1, Relaxed);
+                        (None, new_value)
+                    }
+                    ModResult::Computed(old_value, new_value) => {
+                        match (&old_value, &new_value) {
+                            (None, Some(_)) => {
+                                self.count.fetch_add(1, Relaxed);
+                            }
+                            (Some(_), None) => {
+                                self.count.fetch_sub(1, Relaxed);
+                                if new_chunk.is_none() {
+                                    self.check_shrink(chunk_ptr, &guard);
+                                }
+                            }
+                            _ => {}
+                        }
+                        (old_value, new_value)
+                    }
+                    // Left as it was, only the value is of interest
+                    ModResult::Existed(_, value) => (None, Some(value)),
+                    ModResult::NotFound => (None, None),
+                    ModResult::TableFull if self.fixed => return Err(FullError),
+                    ModResult::TableFull => {
+                        if let ResizeResult::NoMemory = self.do_migration(chunk_ptr, &guard) {
+                            return Err(FullError);
+                        }
+                        backoff.spin();
+                        continue;
+                    }
+                    ModResult::Fail | ModResult::Sentinel => {
+                        backoff.spin();
+                        continue;
+                    }
+                    _ => unreachable!("Unexpected result on compute"),
+                };
+            if new_chunk.is_some() {
+                dfence();
+                self.modify_entry(chunk, hash, key, fkey, ModOp::Sentinel, new_chunk, &guard);
+            }
+            return Ok(result);
+        }
+    }
+
+    // Migrate the entry of the key if it is still in the old chunk, fails if it have to be read
+    // again
+    fn bring_over(
+        &self,
+        chunk: &Chunk<K, V, A, ALLOC>,
+        new_chunk: &ChunkPtr<K, V, A, ALLOC>,
+        hash: usize,
+        key: &K,
+        fkey: usize,
+        guard: &Guard,
+    ) -> bool {
+        let (old_val, old_idx, old_addr) =
+            self.get_from_chunk(chunk, hash, key, fkey, Some(new_chunk), guard);
+        match old_val.parsed {
+            ParsedValue::Val(v) if v != 0 => {
+                let mut copied = 0;
+                let migrated = self.migrate_entry(
+                    fkey,
+                    old_idx,
+                    old_val,
+                    chunk,
+                    new_chunk,
+                    old_addr,
+                    &mut copied,
+                    guard,
+                );
+                new_chunk.occupation.fetch_add(copied, Relaxed);
+                migrated
+            }
+            ParsedValue::Prime(_) => false,
+            _ => true,
         }
     }
 
@@ -365,7 +794,7 @@ impl<
         self.now_epoch() != epoch
     }
 
-    fn swap<'a, F: Fn(usize) -> Option<usize> + Copy + 'static>(
+    fn swap<'a, F: Fn(usize) -> Option<usize>>(
         &self,
         fkey: usize,
         key: &K,
@@ -380,49 +809,17 @@ impl<
             let new_chunk_ptr = self.new_chunk.load(Acquire, &guard);
             let chunk = unsafe { chunk_ptr.deref() };
             let new_chunk = Self::to_chunk_ref(epoch, &chunk_ptr, &new_chunk_ptr);
+            if new_chunk.is_some()
+                && self.help_migration(chunk_ptr, new_chunk_ptr, self.migration_batch(), guard)
+            {
+                continue;
+            }
             if let Some(new_chunk) = new_chunk {
-                // && self.now_epoch() == epoch
-                // Copying is on the way, should try to get old value from old chunk then put new value in new chunk
-                let (old_parsed_val, old_index, _) =
-                    self.get_from_chunk(chunk, hash, key, fkey, Some(new_chunk));
-                let old_fval = old_parsed_val.raw;
-                if old_fval != SENTINEL_VALUE
-                    && old_fval != EMPTY_VALUE
-                    && old_fval != TOMBSTONE_VALUE
-                {
-                    if let Some(new_val) = func(old_fval) {
-                        let val = chunk.attachment.get(old_index).1;
-                        match self.modify_entry(
-                            new_chunk,
-                            hash,
-                            key,
-                            fkey,
-                            ModOp::AttemptInsert(new_val, &val),
-                            None,
-                            guard,
-                        ) {
-                            ModResult::Done(_, _, new_index)
-                            | ModResult::Replaced(_, _, new_index) => {
-                                let old_addr = chunk.base + old_index * ENTRY_SIZE;
-                                if self.cas_sentinel(old_addr, old_fval) {
-                                    // Put a sentinel in the old chunk
-                                    return SwapResult::Succeed(
-                                        old_fval & VAL_BIT_MASK,
-                                        new_index,
-                                        new_chunk_ptr,
-                                    );
-                                } else {
-                                    // If fail, we may have some problem here
-                                    // The best strategy can be CAS a tombstone to the new index and try everything again
-                                    // Note that we use attempt insert, it will be safe to just `remove` it
-                                    let new_addr = new_chunk.base + new_index * ENTRY_SIZE;
-                                    self.cas_tombstone(new_addr, new_val);
-                                    continue;
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
+                // Swap on the new chunk only, an old value left behind would be dropped by the
+                // sentinel put after the swap
+                if !self.bring_over(chunk, new_chunk, hash, key, fkey, guard) {
+                    backoff.spin();
+                    continue;
                 }
             }
             let modify_chunk_ptr = if new_chunk.is_some() {
@@ -441,7 +838,7 @@ impl<
                 hash,
                 key,
                 fkey,
-                ModOp::SwapFastVal(Box::new(func)),
+                ModOp::SwapFastVal(&func),
                 None,
                 guard,
             );
@@ -462,7 +859,8 @@ impl<
                     continue;
                 }
                 ModResult::Existed(_, _) => unreachable!("Swap have existed result"),
-                ModResult::Done(_, _, _) => unreachable!("Swap Done"),
+                ModResult::Done(_, _, _) | ModResult::Revived(_) => unreachable!("Swap Done"),
+                ModResult::Computed(_, _) => unreachable!("Swap computed"),
                 ModResult::TableFull => unreachable!("Swap table full"),
             };
         }
@@ -471,8 +869,8 @@ impl<
     #[inline(always)]
     fn to_chunk_ref<'a>(
         epoch: usize,
-        old_chunk_ptr: &'a Shared<ChunkPtr<K, V, A, ALLOC>>,
-        new_chunk_ptr: &'a Shared<ChunkPtr<K, V, A, ALLOC>>,
+        old_chunk_ptr: &Shared<'a, ChunkPtr<K, V, A, ALLOC>>,
+        new_chunk_ptr: &Shared<'a, ChunkPtr<K, V, A, ALLOC>>,
     ) -> Option<&'a ChunkPtr<K, V, A, ALLOC>> {
         if (Self::is_copying(epoch)) && (!old_chunk_ptr.eq(new_chunk_ptr)) {
             unsafe { new_chunk_ptr.as_ref() }
@@ -488,17 +886,30 @@ impl<
 
     pub fn remove(&self, key: &K, fkey: usize) -> Option<(usize, V)> {
         let guard = crossbeam_epoch::pin();
+        self.remove_hashed(key, fkey, hash::<H>(fkey), &guard)
+    }
+
+    fn remove_hashed(
+        &self,
+        key: &K,
+        fkey: usize,
+        hash: usize,
+        guard: &Guard,
+    ) -> Option<(usize, V)> {
         let backoff = crossbeam_utils::Backoff::new();
-        let hash = hash::<H>(fkey);
         loop {
             let epoch = self.now_epoch();
-            let new_chunk_ptr = self.new_chunk.load(Acquire, &guard);
-            let old_chunk_ptr = self.chunk.load(Acquire, &guard);
+            let new_chunk_ptr = self.new_chunk.load(Acquire, guard);
+            let old_chunk_ptr = self.chunk.load(Acquire, guard);
             let copying = Self::is_copying(epoch);
             if copying && (new_chunk_ptr.is_null() || new_chunk_ptr == old_chunk_ptr) {
                 continue;
             }
-            let new_chunk = unsafe { new_chunk_ptr.deref() };
+            if copying
+                && self.help_migration(old_chunk_ptr, new_chunk_ptr, self.migration_batch(), guard)
+            {
+                continue;
+            }
             let old_chunk = unsafe { old_chunk_ptr.deref() };
             let mut retr = None;
             if copying {
@@ -506,6 +917,7 @@ impl<
                 // If not migration might put the old value back
                 trace!("Put sentinel in old chunk for removal");
                 debug_assert_ne!(new_chunk_ptr, Shared::null());
+                let new_chunk = unsafe { new_chunk_ptr.deref() };
                 let remove_from_old = self.modify_entry(
                     &*old_chunk,
                     hash,
@@ -513,7 +925,7 @@ impl<
                     fkey,
                     ModOp::Sentinel,
                     Some(&new_chunk),
-                    &guard,
+                    guard,
                 );
                 match remove_from_old {
                     ModResult::Done(fvalue, Some(value), _)
@@ -522,12 +934,22 @@ impl<
                         retr = Some((fvalue, value));
                     }
                     ModResult::Done(_, None, _) => {}
+                    ModResult::Fail => {
+                        // The value changed under us, it has to be taken care of before
+                        // the new chunk can be touched
+                        backoff.spin();
+                        continue;
+                    }
                     _ => {
                         trace!("Sentinal not placed");
                     }
                 }
             }
-            let modify_chunk = if copying { &new_chunk } else { &old_chunk };
+            let modify_chunk = if copying {
+                unsafe { new_chunk_ptr.deref() }
+            } else {
+                old_chunk
+            };
             let res = self.modify_entry(
                 &*modify_chunk,
                 hash,
@@ -535,15 +957,25 @@ impl<
                 fkey,
                 ModOp::Tombstone,
                 None,
-                &guard,
+                guard,
             );
             match res {
                 ModResult::Replaced(fvalue, value, _) => {
                     retr = Some((fvalue, value));
                     self.count.fetch_sub(1, Relaxed);
+                    if !copying {
+                        self.check_shrink(old_chunk_ptr, guard);
+                    }
+                }
+                ModResult::Done(_, _, _) | ModResult::Revived(_) => {
+                    unreachable!("Remove shall not have done")
+                }
+                ModResult::NotFound => {
+                    if retr.is_some() {
+                        // Taken from the old chunk only
+                        self.count.fetch_sub(1, Relaxed);
+                    }
                 }
-                ModResult::Done(_, _, _) => unreachable!("Remove shall not have done"),
-                ModResult::NotFound => {}
                 ModResult::Sentinel => {
                     backoff.spin();
                     continue;
@@ -553,14 +985,124 @@ impl<
             };
             if self.epoch_changed(epoch) {
                 if retr.is_none() {
-                    return self.remove(key, fkey);
+                    return self.remove_hashed(key, fkey, hash, guard);
                 }
             }
             return retr;
         }
     }
 
+    /// Batch of `get`, see `Table::batch`
+    pub fn get_many(
+        &self,
+        keys: &[(&K, usize)],
+        read_attachment: bool,
+    ) -> Vec<Option<(usize, Option<V>)>>
+    where
+        V: Clone,
+    {
+        self.batch(
+            keys,
+            |(_, fkey)| *fkey,
+            read_attachment,
+            |(key, fkey), hash, guard| {
+                self.find_hashed(
+                    key,
+                    *fkey,
+                    hash,
+                    read_attachment,
+                    |chunk, idx| chunk.attachment.clone_value(idx),
+                    guard,
+                )
+            },
+        )
+    }
+
+    /// Batch of `insert` with the key, value, fast key and fast value of each entry
+    pub fn insert_many(
+        &self,
+        op: InsertOp,
+        entries: Vec<(&K, Option<V>, usize, usize)>,
+    ) -> Vec<Option<(usize, V)>> {
+        let attached = Self::can_attach();
+        self.batch(
+            entries,
+            |(_, _, fkey, _)| *fkey,
+            attached,
+            |entry, hash, guard| {
+                let (key, value, fkey, fvalue) = entry;
+                self.insert_hashed(op, key, value, fkey, hash, fvalue, guard)
+                    .unwrap_or_else(|e| panic!("{}", e))
+            },
+        )
+    }
+
+    /// Batch of `remove`
+    pub fn remove_many(&self, keys: &[(&K, usize)]) -> Vec<Option<(usize, V)>> {
+        let attached = Self::can_attach();
+        self.batch(
+            keys,
+            |(_, fkey)| *fkey,
+            attached,
+            |(key, fkey), hash, guard| self.remove_hashed(key, *fkey, hash, guard),
+        )
+    }
+
+    // Run `op` on the items under one epoch pin, given the hash of their keys. All the keys are
+    // hashed first, then the entries the keys start probing from, and their attachment slots when
+    // `attached`, are prefetched a few items ahead of `op` so that the cache misses overlap
+    fn batch<T, R, I: IntoIterator<Item = T>>(
+        &self,
+        items: I,
+        fkey: impl Fn(&T) -> usize,
+        attached: bool,
+        mut op: impl FnMut(T, usize, &Guard) -> R,
+    ) -> Vec<R> {
+        let guard = crossbeam_epoch::pin();
+        let items = items.into_iter().collect::<Vec<_>>();
+        let hashes = items
+            .iter()
+            .map(|item| hash::<H>(fkey(item)))
+            .collect::<Vec<_>>();
+        // Prefetching from a chunk that was swapped out in the meantime is harmless, it is not
+        // released while the guard is pinned
+        let chunk_ptr = self.chunk.load(Acquire, &guard);
+        let new_chunk_ptr = self.new_chunk.load(Acquire, &guard);
+        let chunk = unsafe { chunk_ptr.deref() };
+        let new_chunk = Self::to_chunk_ref(self.now_epoch(), &chunk_ptr, &new_chunk_ptr);
+        let prefetch = |hash: usize| {
+            chunk.prefetch(hash, attached);
+            if let Some(new_chunk) = new_chunk {
+                new_chunk.prefetch(hash, attached);
+            }
+        };
+        hashes
+            .iter()
+            .take(PREFETCH_AHEAD)
+            .for_each(|hash| prefetch(*hash));
+        let mut res = Vec::with_capacity(items.len());
+        for (i, item) in items.into_iter().enumerate() {
+            if let Some(hash) = hashes.get(i + PREFETCH_AHEAD) {
+                prefetch(*hash);
+            }
+            res.push(op(item, hashes[i], &guard));
+        }
+        res
+    }
+
     pub fn len(&self) -> usize {
+        if self.fixed {
+            // Tables sharing the chunk only count their own changes, the chunk counts them all
+            let chunk = unsafe {
+                self.chunk
+                    .load(Acquire, crossbeam_epoch::unprotected())
+                    .deref()
+            };
+            return chunk
+                .occupation
+                .load(Relaxed)
+                .saturating_sub(chunk.empty_entries.load(Relaxed));
+        }
         self.count.load(Relaxed)
     }
 
@@ -571,19 +1113,19 @@ impl<
         key: &K,
         fkey: usize,
         migrating: Option<&ChunkPtr<K, V, A, ALLOC>>,
+        guard: &Guard,
     ) -> (Value, usize, usize) {
         debug_assert_ne!(chunk as *const Chunk<K, V, A, ALLOC> as usize, 0);
         let mut idx = hash;
         let cap = chunk.capacity;
-        let base = chunk.base;
         let cap_mask = chunk.cap_mask();
         let mut counter = 0;
         while counter < cap {
             idx &= cap_mask;
-            let addr = base + idx * ENTRY_SIZE;
+            let addr = chunk.entry_addr(idx);
             let k = self.get_fast_key(addr);
-            if k == fkey && chunk.attachment.probe(idx, key) {
-                let val_res = self.get_fast_value(addr);
+            let val_res = self.get_fast_value(addr);
+            if self.key_matches(chunk, idx, k, fkey, key, &val_res) {
                 match val_res.parsed {
                     ParsedValue::Empty => {}
                     _ => return (val_res, idx, addr),
@@ -591,10 +1133,9 @@ impl<
             } else if k == EMPTY_KEY {
                 return (Value::new::<K, V, A, ALLOC, H>(0), 0, addr);
             } else if let Some(new_chunk_ins) = migrating {
-                debug_assert!(new_chunk_ins.base != chunk.base);
-                let val_res = self.get_fast_value(addr);
+                debug_assert!(new_chunk_ins.data_addr() != chunk.data_addr());
                 if let &ParsedValue::Val(_) = &val_res.parsed {
-                    self.migrate_entry(k, idx, val_res, chunk, new_chunk_ins, addr, &mut 0);
+                    self.migrate_entry(k, idx, val_res, chunk, new_chunk_ins, addr, &mut 0, guard);
                 }
             }
             idx += 1; // reprobe
@@ -605,26 +1146,48 @@ impl<
         return (Value::new::<K, V, A, ALLOC, H>(0), 0, 0);
     }
 
+    // Only slots that own their pair can be probed, the key of a tombstone may have been dropped.
+    // The fast key of `HashMap` is the whole hash of its key, a fingerprint that rejects other
+    // keys on the fast key alone, so the attachment is only probed to confirm a hit
     #[inline(always)]
-    fn modify_entry<'a>(
+    fn key_matches(
         &self,
-        chunk: &'a Chunk<K, V, A, ALLOC>,
-        hash: usize,
+        chunk: &Chunk<K, V, A, ALLOC>,
+        idx: usize,
+        k: usize,
+        fkey: usize,
+        key: &K,
+        val: &Value,
+    ) -> bool {
+        if k != fkey {
+            return false;
+        }
+        match val.parsed {
+            ParsedValue::Sentinel => true,
+            ParsedValue::Empty | ParsedValue::Val(0) => mem::size_of::<K>() == 0,
+            _ => chunk.attachment.probe(idx, key),
+        }
+    }
+
+    #[inline(always)]
+    fn modify_entry<'a>(
+        &self,
+        chunk: &'a Chunk<K, V, A, ALLOC>,
+        hash: usize,
         key: &K,
         fkey: usize,
         op: ModOp<V>,
         migration_chunk: Option<&ChunkPtr<K, V, A, ALLOC>>,
-        _guard: &'a Guard,
+        guard: &'a Guard,
     ) -> ModResult<V> {
         let cap = chunk.capacity;
-        let base = chunk.base;
         let mut idx = hash;
         let mut count = 0;
         let cap_mask = chunk.cap_mask();
         let backoff = crossbeam_utils::Backoff::new();
         while count <= cap {
             idx &= cap_mask;
-            let addr = base + idx * ENTRY_SIZE;
+            let addr = chunk.entry_addr(idx);
             let k = self.get_fast_key(addr);
             let v = self.get_fast_value(addr);
             {
@@ -642,21 +1205,24 @@ impl<
                     _ => {}
                 }
             }
-            if k == fkey && chunk.attachment.probe(idx, &key) {
+            if self.key_matches(chunk, idx, k, fkey, key, &v) {
                 // Probing non-empty entry
                 let val = v;
                 match &val.parsed {
                     ParsedValue::Val(v) => {
                         match &op {
                             &ModOp::Sentinel => {
-                                if self.cas_sentinel(addr, val.raw) {
-                                    let (_, value) = chunk.attachment.get(idx);
-                                    chunk.attachment.erase(idx);
-                                    if *v == 0 {
+                                if *v == 0 {
+                                    if self.cas_sentinel(addr, val.raw) {
                                         return ModResult::Done(addr, None, idx);
                                     } else {
-                                        return ModResult::Done(*v, Some(value), idx);
+                                        return ModResult::Fail;
                                     }
+                                }
+                                if self.cas_own_sentinel(addr, val.raw) {
+                                    let (_, value) = chunk.attachment.get(idx);
+                                    chunk.attachment.erase(idx, guard);
+                                    return ModResult::Done(*v, Some(value), idx);
                                 } else {
                                     return ModResult::Fail;
                                 }
@@ -674,17 +1240,18 @@ impl<
                                 } else {
                                     // we have put tombstone on the value, get the attachment and erase it
                                     let (_, value) = chunk.attachment.get(idx);
-                                    chunk.attachment.erase(idx);
+                                    chunk.attachment.erase(idx, guard);
                                     chunk.empty_entries.fetch_add(1, Relaxed);
                                     return ModResult::Replaced(*v, value, idx);
                                 }
                             }
                             &ModOp::UpsertFastVal(ref fv) => {
                                 if self.cas_value(addr, val.raw, *fv).1 {
-                                    let (_, value) = chunk.attachment.get(idx);
                                     if *v == 0 {
-                                        return ModResult::Done(addr, None, idx);
+                                        chunk.empty_entries.fetch_sub(1, Relaxed);
+                                        return ModResult::Revived(idx);
                                     } else {
+                                        let (_, value) = chunk.attachment.get(idx);
                                         return ModResult::Replaced(*v, value, idx);
                                     }
                                 } else {
@@ -702,14 +1269,15 @@ impl<
                                     let (act_val, replaced) =
                                         self.cas_value(addr, val.raw, primed_fval);
                                     if replaced {
-                                        let (_, prev_val) = chunk.attachment.get(idx);
+                                        // The tombstone owns nothing, fill it like an empty slot
                                         if Self::can_attach() {
                                             chunk.attachment.set(idx, key.clone(), (*oval).clone());
                                             let stripped_prime =
                                                 self.cas_value(addr, primed_fval, fval).1;
                                             debug_assert!(stripped_prime);
                                         }
-                                        return ModResult::Replaced(val.raw, prev_val, idx);
+                                        chunk.empty_entries.fetch_sub(1, Relaxed);
+                                        return ModResult::Revived(idx);
                                     } else {
                                         let (_, value) = chunk.attachment.get(idx);
                                         return ModResult::Existed(act_val, value);
@@ -754,6 +1322,109 @@ impl<
                                     }
                                 }
                             }
+                            &ModOp::Compute(fval, func) => {
+                                if *v == 0 {
+                                    // The tombstone owns nothing, fill it like an empty slot
+                                    let new_value = match func(None) {
+                                        Some(new_value) => new_value,
+                                        None => return ModResult::NotFound,
+                                    };
+                                    let primed_fval = fval | INV_VAL_BIT_MASK;
+                                    if !self.cas_value(addr, val.raw, primed_fval).1 {
+                                        return ModResult::Fail;
+                                    }
+                                    chunk.attachment.set(idx, key.clone(), new_value.clone());
+                                    let stripped_prime = self.cas_value(addr, primed_fval, fval).1;
+                                    debug_assert!(stripped_prime);
+                                    chunk.empty_entries.fetch_sub(1, Relaxed);
+                                    return ModResult::Computed(None, Some(new_value));
+                                }
+                                // The pair is owned while primed, nothing can replace it before
+                                // the result of the function takes its place
+                                let primed_fval = val.raw | INV_VAL_BIT_MASK;
+                                if !self.cas_value(addr, val.raw, primed_fval).1 {
+                                    return ModResult::Fail;
+                                }
+                                // Leave the pair as it was if the function unwinds
+                                let primed = PrimedSlot {
+                                    addr: addr + mem::size_of::<usize>(),
+                                    claimed: primed_fval,
+                                    raw: val.raw,
+                                };
+                                let old_value = chunk.attachment.value(idx);
+                                let new_value = func(Some(old_value));
+                                let old_value = old_value.clone();
+                                mem::forget(primed);
+                                let unprimed_fval = if let Some(new_value) = &new_value {
+                                    chunk.attachment.replace(
+                                        idx,
+                                        key.clone(),
+                                        new_value.clone(),
+                                        guard,
+                                    );
+                                    val.raw
+                                } else {
+                                    chunk.attachment.erase(idx, guard);
+                                    chunk.empty_entries.fetch_add(1, Relaxed);
+                                    TOMBSTONE_VALUE
+                                };
+                                let stripped_prime =
+                                    self.cas_value(addr, primed_fval, unprimed_fval).1;
+                                debug_assert!(stripped_prime);
+                                return ModResult::Computed(Some(old_value), new_value);
+                            }
+                            &ModOp::Init(fval, modify, init) => {
+                                if *v == 0 {
+                                    // Own the tombstone before making the value, so that it is
+                                    // only made once
+                                    let primed_fval = fval | INV_VAL_BIT_MASK;
+                                    if !self.cas_value(addr, val.raw, primed_fval).1 {
+                                        return ModResult::Fail;
+                                    }
+                                    // Back to the tombstone if `init` unwinds
+                                    let primed = PrimedSlot {
+                                        addr: addr + mem::size_of::<usize>(),
+                                        claimed: primed_fval,
+                                        raw: val.raw,
+                                    };
+                                    let new_value = init();
+                                    mem::forget(primed);
+                                    chunk.attachment.set(idx, key.clone(), new_value.clone());
+                                    let stripped_prime = self.cas_value(addr, primed_fval, fval).1;
+                                    debug_assert!(stripped_prime);
+                                    chunk.empty_entries.fetch_sub(1, Relaxed);
+                                    return ModResult::Computed(None, Some(new_value));
+                                }
+                                let modify = match modify {
+                                    Some(modify) => modify,
+                                    None => {
+                                        return ModResult::Existed(*v, chunk.attachment.get(idx).1)
+                                    }
+                                };
+                                // Same as compute, the pair is owned while it is modified
+                                let primed_fval = val.raw | INV_VAL_BIT_MASK;
+                                if !self.cas_value(addr, val.raw, primed_fval).1 {
+                                    return ModResult::Fail;
+                                }
+                                let primed = PrimedSlot {
+                                    addr: addr + mem::size_of::<usize>(),
+                                    claimed: primed_fval,
+                                    raw: val.raw,
+                                };
+                                let old_value = chunk.attachment.value(idx).clone();
+                                let mut new_value = old_value.clone();
+                                modify(&mut new_value);
+                                mem::forget(primed);
+                                chunk.attachment.replace(
+                                    idx,
+                                    key.clone(),
+                                    new_value.clone(),
+                                    guard,
+                                );
+                                let stripped_prime = self.cas_value(addr, primed_fval, val.raw).1;
+                                debug_assert!(stripped_prime);
+                                return ModResult::Computed(Some(old_value), Some(new_value));
+                            }
                             &ModOp::Insert(fval, ref v) => {
                                 // Insert with attachment should prime value first when
                                 // duplicate key discovered
@@ -764,14 +1435,33 @@ impl<
                                     fval
                                 };
                                 if self.cas_value(addr, val.raw, primed_fval).1 {
-                                    let (_, prev_val) = chunk.attachment.get(idx);
+                                    let revived = val.raw == TOMBSTONE_VALUE;
+                                    let prev_val = if revived {
+                                        None
+                                    } else {
+                                        Some(chunk.attachment.get(idx).1)
+                                    };
                                     if Self::can_attach() {
-                                        chunk.attachment.set(idx, key.clone(), (*v).clone());
+                                        if revived {
+                                            chunk.attachment.set(idx, key.clone(), (*v).clone());
+                                        } else {
+                                            chunk.attachment.replace(
+                                                idx,
+                                                key.clone(),
+                                                (*v).clone(),
+                                                guard,
+                                            );
+                                        }
                                         let stripped_prime =
                                             self.cas_value(addr, primed_fval, fval).1;
                                         debug_assert!(stripped_prime);
                                     }
-                                    return ModResult::Replaced(val.raw, prev_val, idx);
+                                    if let Some(prev_val) = prev_val {
+                                        return ModResult::Replaced(val.raw, prev_val, idx);
+                                    } else {
+                                        chunk.empty_entries.fetch_sub(1, Relaxed);
+                                        return ModResult::Revived(idx);
+                                    }
                                 } else {
                                     trace!("Cannot insert in place for {}", fkey);
                                     return ModResult::Fail;
@@ -795,6 +1485,17 @@ impl<
                     }
                 }
             } else if k == EMPTY_KEY {
+                if self.chunk_full(chunk)
+                    && !matches!(
+                        op,
+                        ModOp::Compute(..)
+                            | ModOp::Sentinel
+                            | ModOp::Tombstone
+                            | ModOp::SwapFastVal(_)
+                    )
+                {
+                    return ModResult::TableFull;
+                }
                 match op {
                     ModOp::Insert(fval, val) | ModOp::AttemptInsert(fval, val) => {
                         trace!(
@@ -840,34 +1541,81 @@ impl<
                             continue;
                         }
                     }
+                    ModOp::Compute(fval, func) => {
+                        let new_value = match func(None) {
+                            Some(new_value) => new_value,
+                            None => return ModResult::NotFound,
+                        };
+                        if self.chunk_full(chunk) {
+                            return ModResult::TableFull;
+                        }
+                        if self.cas_value(addr, EMPTY_VALUE, fval).1 {
+                            chunk.attachment.set(idx, key.clone(), new_value.clone());
+                            unsafe { intrinsics::atomic_store_rel(addr as *mut usize, fkey) }
+                            return ModResult::Done(addr, Some(new_value), idx);
+                        } else {
+                            backoff.spin();
+                            continue;
+                        }
+                    }
+                    ModOp::Init(fval, _, init) => {
+                        // Claim the slot before making the value, threads probing here wait for
+                        // the key to be stored
+                        let primed_fval = fval | INV_VAL_BIT_MASK;
+                        if self.cas_value(addr, EMPTY_VALUE, primed_fval).1 {
+                            // The key is not stored yet, empty the slot again if `init` unwinds
+                            let primed = PrimedSlot {
+                                addr: addr + mem::size_of::<usize>(),
+                                claimed: primed_fval,
+                                raw: EMPTY_VALUE,
+                            };
+                            let new_value = init();
+                            mem::forget(primed);
+                            chunk.attachment.set(idx, key.clone(), new_value.clone());
+                            unsafe { intrinsics::atomic_store_rel(addr as *mut usize, fkey) }
+                            let stripped_prime = self.cas_value(addr, primed_fval, fval).1;
+                            debug_assert!(stripped_prime);
+                            return ModResult::Done(addr, Some(new_value), idx);
+                        } else {
+                            backoff.spin();
+                            continue;
+                        }
+                    }
                     ModOp::Tombstone => return ModResult::Fail,
                     ModOp::SwapFastVal(_) => return ModResult::NotFound,
                 };
             } else if let (Some(migration_chunk), &ParsedValue::Val(_)) =
                 (migration_chunk, &v.parsed)
             {
-                self.migrate_entry(k, idx, v, chunk, migration_chunk, addr, &mut 0);
+                self.migrate_entry(k, idx, v, chunk, migration_chunk, addr, &mut 0, guard);
             }
             idx += 1; // reprobe
             count += 1;
         }
         match op {
             ModOp::Insert(_fv, _v) | ModOp::AttemptInsert(_fv, _v) => ModResult::TableFull,
-            ModOp::UpsertFastVal(_fv) => ModResult::TableFull,
+            ModOp::UpsertFastVal(_fv) | ModOp::Compute(_fv, _) => ModResult::TableFull,
+            ModOp::Init(_fv, _, _) => ModResult::TableFull,
             _ => ModResult::NotFound,
         }
     }
 
+    // A fixed chunk cannot grow, new keys are declined at the load limit rather than when the
+    // probing runs out of slots
+    #[inline(always)]
+    fn chunk_full(&self, chunk: &Chunk<K, V, A, ALLOC>) -> bool {
+        self.fixed && chunk.occupation.load(Relaxed) >= chunk.occu_limit
+    }
+
     fn all_from_chunk(&self, chunk: &Chunk<K, V, A, ALLOC>) -> Vec<(usize, usize, K, V)> {
         let mut idx = 0;
         let cap = chunk.capacity;
-        let base = chunk.base;
         let mut counter = 0;
         let mut res = Vec::with_capacity(chunk.occupation.load(Relaxed));
         let cap_mask = chunk.cap_mask();
         while counter < cap {
             idx &= cap_mask;
-            let addr = base + idx * ENTRY_SIZE;
+            let addr = chunk.entry_addr(idx);
             let k = self.get_fast_key(addr);
             if k != EMPTY_KEY {
                 let val_res = self.get_fast_value(addr);
@@ -894,14 +1642,300 @@ impl<
         let old_chunk_ref = self.chunk.load(Acquire, &guard);
         let new_chunk_ref = self.new_chunk.load(Acquire, &guard);
         let old_chunk = unsafe { old_chunk_ref.deref() };
-        let new_chunk = unsafe { new_chunk_ref.deref() };
         let mut res = self.all_from_chunk(&*old_chunk);
         if !new_chunk_ref.is_null() && old_chunk_ref != new_chunk_ref {
+            let new_chunk = unsafe { new_chunk_ref.deref() };
             res.append(&mut self.all_from_chunk(&*new_chunk));
         }
         return res;
     }
 
+    /// Walk the entries lazily under a guard pinned by the iterator, `project` turns the fast key,
+    /// fast value and the pair of an entry into an item.
+    /// A migration in progress is finished before the walk starts. Entries present for the whole
+    /// walk are yielded at least once, those moved by a resize started during the walk are looked
+    /// up again in the newer chunk, which may yield a key more than once if it was also removed and
+    /// inserted back. Entries inserted or removed during the walk may or may not be seen.
+    pub fn iter<R>(
+        &self,
+        project: fn(usize, usize, &K, &V) -> R,
+    ) -> TableIter<K, V, A, ALLOC, H, R> {
+        let guard = crossbeam_epoch::pin();
+        let chunk = self.settled_chunk(&guard).1;
+        TableIter {
+            table: self,
+            chunk: Self::chunk_raw(chunk),
+            idx: 0,
+            follow: None,
+            project,
+            guard,
+        }
+    }
+
+    /// Remove the entries `keep` returns false for, walking them as `iter` does while other
+    /// threads keep going. `keep` is called with the slot primed, it should be cheap: writers of
+    /// the key wait for it, and `keep` itself shall not write the key it is given or it waits for
+    /// itself forever. An entry `keep` panics on is left in the table
+    pub fn retain<F: FnMut(usize, usize, &K, &V) -> bool>(&self, mut keep: F) {
+        let mut walk = self.iter(|_, _, _, _| ());
+        while let Some((chunk, idx, k, _)) = walk.next_entry() {
+            let chunk = unsafe { &*chunk };
+            let removed = self.remove_slot_if(chunk, idx, &walk.guard, |k, v, key, value| {
+                if keep(k, v, key, value) {
+                    None
+                } else {
+                    Some(())
+                }
+            });
+            if removed.is_err() {
+                walk.follow_moved(k);
+            }
+        }
+        self.shrink_after_removal(&walk.guard);
+    }
+
+    /// Remove the entries while walking them as `iter` does, yielding the removed ones.
+    /// Entries not reached when the iterator is dropped are left in the table
+    pub fn drain<R>(
+        &self,
+        project: fn(usize, usize, K, V) -> R,
+    ) -> TableDrain<K, V, A, ALLOC, H, R> {
+        TableDrain {
+            walk: self.iter(|_, _, _, _| ()),
+            project,
+        }
+    }
+
+    /// Swap in a fresh chunk of the initial capacity by a migration that drops the entries of the
+    /// old chunk instead of copying them. Entries inserted during the clear may be kept.
+    /// Entries of a fixed table are removed one by one, their keys keep the slots
+    pub fn clear(&self) {
+        if self.fixed {
+            self.retain(|_, _, _, _| false);
+            return;
+        }
+        let guard = crossbeam_epoch::pin();
+        let backoff = crossbeam_utils::Backoff::new();
+        loop {
+            let (_, chunk_ptr) = self.settled_chunk(&guard);
+            match self.migrate_to(chunk_ptr, self.init_cap, true, &guard) {
+                ResizeResult::Done => {
+                    // Entries are only dropped as they are migrated, leave none to later operations
+                    self.settled_chunk(&guard);
+                    return;
+                }
+                ResizeResult::NoMemory => {
+                    self.retain(|_, _, _, _| false);
+                    return;
+                }
+                _ => backoff.spin(),
+            }
+        }
+    }
+
+    // Tombstone a live slot if `decide` returns something for it. With attachments, the slot is
+    // primed while `decide` looks at the pair so it cannot be replaced in between.
+    // Fails if the slot was moved away by a migration.
+    fn remove_slot_if<T, F: FnMut(usize, usize, &K, &V) -> Option<T>>(
+        &self,
+        chunk: &Chunk<K, V, A, ALLOC>,
+        idx: usize,
+        guard: &Guard,
+        mut decide: F,
+    ) -> Result<Option<T>, ()> {
+        let backoff = crossbeam_utils::Backoff::new();
+        let addr = chunk.entry_addr(idx);
+        loop {
+            let k = self.get_fast_key(addr);
+            let val = self.get_fast_value(addr);
+            let v = match val.parsed {
+                ParsedValue::Val(v) if v != 0 => v,
+                ParsedValue::Prime(_) => {
+                    backoff.spin();
+                    continue;
+                }
+                ParsedValue::Sentinel => return Err(()),
+                _ => return Ok(None),
+            };
+            let claimed = if Self::can_attach() {
+                val.raw | INV_VAL_BIT_MASK
+            } else {
+                val.raw
+            };
+            if claimed != val.raw && !self.cas_value(addr, val.raw, claimed).1 {
+                backoff.spin();
+                continue;
+            }
+            let primed = PrimedSlot {
+                addr: addr + mem::size_of::<usize>(),
+                claimed,
+                raw: val.raw,
+            };
+            let res = chunk
+                .attachment
+                .with_pair(idx, |key, value| decide(k, v, key, value));
+            if res.is_none() {
+                drop(primed);
+                return Ok(None);
+            }
+            mem::forget(primed);
+            // Erase while the pair is still owned, a tombstone can be filled right away
+            chunk.attachment.erase(idx, guard);
+            if !self.cas_tombstone(addr, claimed).1 {
+                // Only fast values can change under a decision, decide again
+                debug_assert!(!Self::can_attach());
+                backoff.spin();
+                continue;
+            }
+            chunk.empty_entries.fetch_add(1, Relaxed);
+            self.count.fetch_sub(1, Relaxed);
+            return Ok(res);
+        }
+    }
+
+    fn shrink_after_removal(&self, guard: &Guard) {
+        if !Self::is_copying(self.now_epoch()) {
+            self.check_shrink(self.chunk.load(Acquire, guard), guard);
+        }
+    }
+
+    /// Finish the migration in progress if any, returns the epoch and the only chunk
+    fn settled_chunk<'a>(&self, guard: &'a Guard) -> (usize, Shared<'a, ChunkPtr<K, V, A, ALLOC>>) {
+        let backoff = crossbeam_utils::Backoff::new();
+        loop {
+            let epoch = self.now_epoch();
+            let chunk_ptr = self.chunk.load(Acquire, guard);
+            if Self::is_copying(epoch) {
+                let new_chunk_ptr = self.new_chunk.load(Acquire, guard);
+                if Self::to_chunk_ref(epoch, &chunk_ptr, &new_chunk_ptr).is_some() {
+                    self.help_migration(chunk_ptr, new_chunk_ptr, None, guard);
+                }
+                backoff.spin();
+                continue;
+            }
+            return (epoch, chunk_ptr);
+        }
+    }
+
+    #[inline(always)]
+    fn chunk_raw(chunk_ptr: Shared<ChunkPtr<K, V, A, ALLOC>>) -> *const Chunk<K, V, A, ALLOC> {
+        let chunk: &Chunk<K, V, A, ALLOC> = unsafe { chunk_ptr.deref() };
+        chunk
+    }
+
+    // Read an entry for iteration, a primed value is waited until the migration or swap settles it
+    #[inline(always)]
+    fn settled_entry(&self, chunk: &Chunk<K, V, A, ALLOC>, idx: usize) -> (usize, Value) {
+        let backoff = crossbeam_utils::Backoff::new();
+        let addr = chunk.entry_addr(idx);
+        loop {
+            let k = self.get_fast_key(addr);
+            let val = self.get_fast_value(addr);
+            if let ParsedValue::Prime(_) = val.parsed {
+                backoff.spin();
+                continue;
+            }
+            return (k, val);
+        }
+    }
+
+    // Probe for live entries of the fast key of a moved slot in the chunk after the resize
+    fn follow_next(
+        &self,
+        follow: &mut Follow<K, V, A, ALLOC>,
+        guard: &Guard,
+    ) -> Option<(usize, usize)> {
+        loop {
+            let chunk = unsafe { &*follow.chunk };
+            if follow.counter >= chunk.capacity {
+                return None;
+            }
+            let idx = follow.idx & chunk.cap_mask();
+            let (k, val) = self.settled_entry(chunk, idx);
+            if k == EMPTY_KEY {
+                return None;
+            }
+            follow.idx = idx + 1;
+            follow.counter += 1;
+            if k != follow.fkey {
+                continue;
+            }
+            match val.parsed {
+                ParsedValue::Val(v) if v != 0 => return Some((idx, v)),
+                ParsedValue::Sentinel => {
+                    // Moved again, start over from the newest chunk
+                    *follow = Follow {
+                        fkey: follow.fkey,
+                        chunk: Self::chunk_raw(self.settled_chunk(guard).1),
+                        idx: hash::<H>(follow.fkey),
+                        counter: 0,
+                    };
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Resumable scan over the entries without holding a guard between calls, start from cursor 0
+    /// and pass the returned cursor to the next call until it comes back as 0.
+    /// The cursor walks home buckets in reverse binary order, as SCAN in Redis does, so every
+    /// entry present for the whole scan is returned at least once even if the chunk grows or
+    /// shrinks between calls. Entries may be returned more than once after a shrink.
+    /// A call returns once at least `limit` entries are collected or ten times as many buckets
+    /// are visited, entries of a bucket are never split across calls.
+    pub fn scan(&self, cursor: usize, limit: usize) -> (usize, Vec<(usize, usize, K, V)>) {
+        let guard = crossbeam_epoch::pin();
+        let limit = limit.max(1);
+        let mut cursor = cursor;
+        let mut visited = 0;
+        let mut res = vec![];
+        loop {
+            let (epoch, chunk_ptr) = self.settled_chunk(&guard);
+            let chunk = unsafe { chunk_ptr.deref() };
+            let cap_mask = chunk.cap_mask();
+            let len = res.len();
+            if !self.scan_bucket(chunk, cursor & cap_mask, &mut res) || self.epoch_changed(epoch) {
+                // A resize started during the bucket, scan it again from the next chunk
+                res.truncate(len);
+                continue;
+            }
+            cursor = next_cursor(cursor, cap_mask);
+            visited += 1;
+            if cursor == 0 || res.len() >= limit || visited >= limit.saturating_mul(10) {
+                return (cursor, res);
+            }
+        }
+    }
+
+    // Collect live entries whose home is the bucket, they are all in the run of used slots
+    // starting from it. Returns false if the chunk is being migrated.
+    fn scan_bucket(
+        &self,
+        chunk: &Chunk<K, V, A, ALLOC>,
+        bucket: usize,
+        res: &mut Vec<(usize, usize, K, V)>,
+    ) -> bool {
+        let cap_mask = chunk.cap_mask();
+        let mut idx = bucket;
+        for _ in 0..chunk.capacity {
+            let addr = chunk.entry_addr(idx);
+            let k = self.get_fast_key(addr);
+            if k == EMPTY_KEY {
+                break;
+            }
+            match self.get_fast_value(addr).parsed {
+                ParsedValue::Val(v) if v != 0 && hash::<H>(k) & cap_mask == bucket => {
+                    let (key, value) = chunk.attachment.get(idx);
+                    res.push((k, v, key, value));
+                }
+                ParsedValue::Prime(_) | ParsedValue::Sentinel => return false,
+                _ => {}
+            }
+            idx = (idx + 1) & cap_mask;
+        }
+        true
+    }
+
     #[inline(always)]
     fn get_fast_key(&self, entry_addr: usize) -> usize {
         debug_assert!(entry_addr > 0);
@@ -937,8 +1971,8 @@ impl<
             assert!(!self.new_chunk.load(Acquire, &guard).is_null());
             let chunk = self.chunk.load(Acquire, &guard);
             let chunk_ref = unsafe { chunk.deref() };
-            assert!(entry_addr >= chunk_ref.base);
-            assert!(entry_addr < chunk_ref.base + chunk_ref.total_size);
+            assert!(entry_addr >= chunk_ref.data_addr());
+            assert!(entry_addr < chunk_ref.data_addr() + chunk_ref.total_size);
         }
         let addr = entry_addr + mem::size_of::<usize>();
         let (val, done) = unsafe {
@@ -947,16 +1981,43 @@ impl<
         done || val == SENTINEL_VALUE
     }
 
+    // Unlike `cas_sentinel`, only succeeds if this thread is the one that put the sentinel
+    #[inline(always)]
+    fn cas_own_sentinel(&self, entry_addr: usize, original: usize) -> bool {
+        let addr = entry_addr + mem::size_of::<usize>();
+        unsafe { intrinsics::atomic_cxchg_acqrel(addr as *mut usize, original, SENTINEL_VALUE).1 }
+    }
+
     /// Failed return old shared
     fn check_migration<'a>(
         &self,
         old_chunk_ptr: Shared<'a, ChunkPtr<K, V, A, ALLOC>>,
         guard: &crossbeam_epoch::Guard,
     ) -> ResizeResult {
+        if self.fixed {
+            return ResizeResult::NoNeed;
+        }
         let old_chunk_ins = unsafe { old_chunk_ptr.deref() };
         let occupation = old_chunk_ins.occupation.load(Relaxed);
         let occu_limit = old_chunk_ins.occu_limit;
         if occupation <= occu_limit {
+            if !self.new_chunk.load(Acquire, guard).is_null() {
+                // A resize holds the lock and sized its chunk for the entries it saw, wait for it
+                return ResizeResult::SwapFailed;
+            }
+            return ResizeResult::NoNeed;
+        }
+        self.do_migration(old_chunk_ptr, guard)
+    }
+
+    /// Start a shrinking migration when live entries fall far below the chunk capacity
+    fn check_shrink<'a>(
+        &self,
+        old_chunk_ptr: Shared<'a, ChunkPtr<K, V, A, ALLOC>>,
+        guard: &crossbeam_epoch::Guard,
+    ) -> ResizeResult {
+        let old_chunk_ins = unsafe { old_chunk_ptr.deref() };
+        if self.fixed || self.shrink_capacity(old_chunk_ins).is_none() {
             return ResizeResult::NoNeed;
         }
         self.do_migration(old_chunk_ptr, guard)
@@ -971,26 +2032,42 @@ impl<
         let old_chunk_ins = unsafe { old_chunk_ptr.deref() };
         let empty_entries = old_chunk_ins.empty_entries.load(Relaxed);
         let old_cap = old_chunk_ins.capacity;
-        let new_cap = if empty_entries > (old_cap >> 1) {
+        let new_cap = if let Some(cap) = self.shrink_capacity(old_chunk_ins) {
+            // Most of the entries are gone, give memory back
+            cap
+        } else if empty_entries > self.policy.tombstone_threshold(old_cap) {
             // Clear tombstones
             old_cap
         } else {
-            let mut cap = old_cap << 1;
-            if cap < 2048 {
-                cap <<= 1;
-            }
-            if epoch < 5 {
-                cap <<= 1;
-            }
-            if timestamp() - self.timestamp.load(Acquire) < 1000 {
-                cap <<= 1;
-            }
-            cap
+            let elapsed = self
+                .policy
+                .now()
+                .saturating_sub(self.timestamp.load(Acquire));
+            // Probing masks hashes by the capacity, take no less than double as a power of 2
+            self.policy
+                .grow_capacity(old_cap, epoch, elapsed)
+                .max(old_cap << 1)
+                .next_power_of_two()
         };
         debug!(
             "New size for {:?} is {}, was {}",
             old_chunk_ptr, new_cap, old_cap
         );
+        self.migrate_to(old_chunk_ptr, new_cap, false, guard)
+    }
+
+    fn migrate_to<'a>(
+        &self,
+        old_chunk_ptr: Shared<'a, ChunkPtr<K, V, A, ALLOC>>,
+        new_cap: usize,
+        discard: bool,
+        guard: &crossbeam_epoch::Guard,
+    ) -> ResizeResult {
+        if self.fixed {
+            return ResizeResult::NoNeed;
+        }
+        let epoch = self.now_epoch();
+        let old_chunk_ins = unsafe { old_chunk_ptr.deref() };
         // Swap in old chunk as placeholder for the lock
         if let Err(_) = self
             .new_chunk
@@ -1013,27 +2090,123 @@ impl<
             return ResizeResult::ChunkChanged;
         }
         debug!("Resizing {:?}", old_chunk_ptr);
-        let new_chunk_ptr =
-            Owned::new(ChunkPtr::new(Chunk::alloc_chunk(new_cap))).into_shared(guard);
+        // The capacity was decided before the lock, entries may have been inserted since
+        let new_cap = if discard {
+            new_cap
+        } else {
+            new_cap.max(self.fit_capacity(self.len() + self.migration_headroom(old_chunk_ins)))
+        };
+        let new_chunk = match Chunk::try_alloc_chunk(
+            new_cap,
+            occupation_limit(&*self.policy, new_cap),
+            self.alloc.clone(),
+        ) {
+            Some(chunk) => chunk,
+            None => {
+                warn!("Give up on resize, no memory for capacity {}", new_cap);
+                self.new_chunk.store(Shared::null(), Release);
+                return ResizeResult::NoMemory;
+            }
+        };
+        let new_chunk_ptr = Owned::new(ChunkPtr::new(new_chunk)).into_shared(guard);
         let new_chunk_ins = unsafe { new_chunk_ptr.deref() };
         debug_assert_ne!(new_chunk_ptr, old_chunk_ptr);
+        old_chunk_ins.discard.store(discard, Release);
         self.new_chunk.store(new_chunk_ptr, Release); // Stump becasue we have the lock already
         dfence();
         let prev_epoch = self.epoch.fetch_add(1, AcqRel); // Increase epoch by one
         debug_assert_eq!(prev_epoch % 2, 0);
         dfence();
-        // Migrate entries
-        self.migrate_entries(old_chunk_ins, new_chunk_ins, guard);
-        // Assertion check
-        debug_assert_ne!(old_chunk_ins.ptr as usize, new_chunk_ins.base);
+        // Migrate entries, other threads that see the copying state will claim ranges as well
+        debug_assert_ne!(old_chunk_ins.ptr as usize, new_chunk_ins.data_addr());
         debug_assert_ne!(old_chunk_ins.ptr, unsafe { new_chunk_ptr.deref().ptr });
+        self.help_migration(old_chunk_ptr, new_chunk_ptr, self.migration_batch(), guard);
+        ResizeResult::Done
+    }
+
+    // The new chunk of a migration may not be able to take every entry yet to migrate besides
+    // the ones it already have. The old chunk cannot have more of them than its occupied slots,
+    // nor than its slots in ranges not done yet, whose copies are not counted in the new chunk.
+    // Once done, the new chunk holds the live entries and its own tombstones.
+    #[inline(always)]
+    fn filling_up(&self, chunk: &Chunk<K, V, A, ALLOC>, new_chunk: &Chunk<K, V, A, ALLOC>) -> bool {
+        let pending = chunk
+            .occupation
+            .load(Relaxed)
+            .min(chunk.capacity - chunk.migrated.load(Relaxed));
+        let by_chunks = new_chunk.occupation.load(Relaxed) + pending;
+        let by_entries = self.len() + new_chunk.empty_entries.load(Relaxed);
+        by_chunks.min(by_entries) >= new_chunk.occu_limit
+    }
+
+    #[inline(always)]
+    fn migration_batch(&self) -> Option<usize> {
+        self.policy.migration_batch().map(|n| n.max(1))
+    }
+
+    // Entries the operations spreading the migration of `chunk` may add before it is done, each
+    // of them migrates a batch first
+    #[inline(always)]
+    fn migration_headroom(&self, chunk: &Chunk<K, V, A, ALLOC>) -> usize {
+        match self.migration_batch() {
+            Some(batch) => (chunk.capacity + batch - 1) / batch,
+            None => 0,
+        }
+    }
+
+    /// Claim ranges of the old chunk and migrate them until no range is left, or only one
+    /// range of at most `batch` entries if given.
+    /// Returns true if this thread migrated the last range and finished the migration.
+    fn help_migration<'a>(
+        &self,
+        old_chunk_ptr: Shared<'a, ChunkPtr<K, V, A, ALLOC>>,
+        new_chunk_ptr: Shared<'a, ChunkPtr<K, V, A, ALLOC>>,
+        batch: Option<usize>,
+        guard: &crossbeam_epoch::Guard,
+    ) -> bool {
+        let old_chunk_ins = unsafe { old_chunk_ptr.deref() };
+        let new_chunk_ins = unsafe { new_chunk_ptr.deref() };
+        let old_cap = old_chunk_ins.capacity;
+        let range = batch.map_or(MIGRATION_RANGE, |n| n.min(MIGRATION_RANGE));
+        loop {
+            if old_chunk_ins.migrate_cursor.load(Acquire) >= old_cap {
+                return false;
+            }
+            let start = old_chunk_ins.migrate_cursor.fetch_add(range, AcqRel);
+            if start >= old_cap {
+                return false;
+            }
+            let end = (start + range).min(old_cap);
+            let effective_copy =
+                self.migrate_entries(old_chunk_ins, new_chunk_ins, start, end, guard);
+            new_chunk_ins.occupation.fetch_add(effective_copy, Relaxed);
+            let migrated = old_chunk_ins.migrated.fetch_add(end - start, AcqRel) + end - start;
+            if migrated == old_cap {
+                // Every range is done, this is the last thread touching the old chunk entries
+                self.finish_migration(old_chunk_ptr, new_chunk_ptr, guard);
+                return true;
+            }
+            if batch.is_some() {
+                return false;
+            }
+        }
+    }
+
+    fn finish_migration<'a>(
+        &self,
+        old_chunk_ptr: Shared<'a, ChunkPtr<K, V, A, ALLOC>>,
+        new_chunk_ptr: Shared<'a, ChunkPtr<K, V, A, ALLOC>>,
+        guard: &crossbeam_epoch::Guard,
+    ) {
+        let old_cap = unsafe { old_chunk_ptr.deref() }.capacity;
+        let new_cap = unsafe { new_chunk_ptr.deref() }.capacity;
         debug_assert!(!new_chunk_ptr.is_null());
         dfence();
         let prev_epoch = self.epoch.fetch_add(1, AcqRel); // Increase epoch by one
         debug_assert_eq!(prev_epoch % 2, 1);
         dfence();
         self.chunk.store(new_chunk_ptr, Release);
-        self.timestamp.store(timestamp(), Release);
+        self.timestamp.store(self.policy.now(), Release);
         dfence();
         unsafe {
             guard.defer_destroy(old_chunk_ptr);
@@ -1044,19 +2217,20 @@ impl<
             "Migration for {:?} completed, new chunk is {:?}, size from {} to {}",
             old_chunk_ptr, new_chunk_ptr, old_cap, new_cap
         );
-        ResizeResult::Done
     }
 
     fn migrate_entries(
         &self,
         old_chunk_ins: &Chunk<K, V, A, ALLOC>,
         new_chunk_ins: &Chunk<K, V, A, ALLOC>,
-        _guard: &crossbeam_epoch::Guard,
+        start: usize,
+        end: usize,
+        guard: &Guard,
     ) -> usize {
-        let mut old_address = old_chunk_ins.base as usize;
-        let boundary = old_address + chunk_size_of(old_chunk_ins.capacity);
+        let mut old_address = old_chunk_ins.entry_addr(start);
+        let boundary = old_chunk_ins.entry_addr(end);
         let mut effective_copy = 0;
-        let mut idx = 0;
+        let mut idx = start;
         let backoff = crossbeam_utils::Backoff::new();
         while old_address < boundary {
             // iterate the old chunk to extract entries that is NOT empty
@@ -1080,13 +2254,16 @@ impl<
                         new_chunk_ins,
                         old_address,
                         &mut effective_copy,
+                        guard,
                     ) {
                         continue;
                     }
                 }
                 ParsedValue::Prime(_) => {
-                    unreachable!("Shall not have prime in old table");
-                }
+                    // Other thread is moving or overwriting the pair, wait for it
+                    backoff.spin();
+                    continue;
+                }
                 ParsedValue::Sentinel => {
                     // Sentinel, skip
                     // Sentinel in old chunk implies its new value have already in the new chunk
@@ -1094,13 +2271,16 @@ impl<
                     trace!("Skip copy sentinel");
                 }
             }
-            old_address += ENTRY_SIZE;
+            old_address += Chunk::<K, V, A, ALLOC>::STRIDE;
             idx += 1;
             dfence();
         }
-        // resize finished, make changes on the numbers
-        debug!("Migrated {} entries to new chunk", effective_copy);
-        new_chunk_ins.occupation.fetch_add(effective_copy, Relaxed);
+        trace!(
+            "Migrated {} entries in range {}..{} to new chunk",
+            effective_copy,
+            start,
+            end
+        );
         return effective_copy;
     }
 
@@ -1114,8 +2294,9 @@ impl<
         new_chunk_ins: &Chunk<K, V, A, ALLOC>,
         old_address: usize,
         effective_copy: &mut usize,
+        guard: &Guard,
     ) -> bool {
-        debug_assert_ne!(old_chunk_ins.base, new_chunk_ins.base);
+        debug_assert_ne!(old_chunk_ins.data_addr(), new_chunk_ins.data_addr());
         if fkey == EMPTY_KEY {
             // Value have no key, insertion in progress
             return false;
@@ -1123,30 +2304,47 @@ impl<
         // Insert entry into new chunk, in case of failure, skip this entry
         // Value should be primed
         debug_assert_ne!(fvalue.raw & VAL_BIT_MASK, SENTINEL_VALUE);
-        let (key, value) = old_chunk_ins.attachment.get(old_idx);
+        let primed_fval = fvalue.raw | INV_VAL_BIT_MASK;
+        if !self.cas_value(old_address, fvalue.raw, primed_fval).1 {
+            // Changed by other thread, have to read it again
+            return false;
+        }
+        if old_chunk_ins.discard.load(Acquire) {
+            // The table is being cleared, drop the pair instead
+            let sentinel_placed = self.cas_sentinel(old_address, primed_fval);
+            debug_assert!(sentinel_placed);
+            old_chunk_ins.attachment.erase(old_idx, guard);
+            self.count.fetch_sub(1, Relaxed);
+            return true;
+        }
+        // The pair is owned by this thread while primed
+        let key = old_chunk_ins.attachment.key(old_idx);
         let inserted_addr = {
             // Make insertion for migration inlined, hopefully the ordering will be right
             let cap = new_chunk_ins.capacity;
-            let base = new_chunk_ins.base;
             let mut idx = hash::<H>(fkey);
             let cap_mask = new_chunk_ins.cap_mask();
             let mut count = 0;
             let mut res = None;
             while count < cap {
                 idx &= cap_mask;
-                let addr = base + idx * ENTRY_SIZE;
+                let addr = new_chunk_ins.entry_addr(idx);
                 let k = self.get_fast_key(addr);
-                if k == fkey && new_chunk_ins.attachment.probe(idx, &key) {
+                let v = self.get_fast_value(addr);
+                if self.key_matches(new_chunk_ins, idx, k, fkey, key, &v) {
                     // New value existed, skip with None result
+                    res = Some(None);
                     break;
                 } else if k == EMPTY_KEY {
                     // Try insert to this slot
                     let (val, done) = self.cas_value(addr, EMPTY_VALUE, fvalue.raw);
                     debug_assert_ne!(val & VAL_BIT_MASK, SENTINEL_VALUE);
                     if done {
-                        new_chunk_ins.attachment.set(idx, key, value);
+                        old_chunk_ins
+                            .attachment
+                            .transfer(old_idx, &new_chunk_ins.attachment, idx);
                         unsafe { intrinsics::atomic_store_rel(addr as *mut usize, fkey) }
-                        res = Some(addr);
+                        res = Some(Some(addr));
                         break;
                     }
                 }
@@ -1155,33 +2353,113 @@ impl<
             }
             res
         };
+        let inserted_addr = match inserted_addr {
+            Some(inserted_addr) => inserted_addr,
+            None => {
+                // New chunk is full, give the pair back and let the caller retry
+                warn!("New chunk is full on migrating key {}", fkey);
+                let unprimed = self.cas_value(old_address, primed_fval, fvalue.raw).1;
+                debug_assert!(unprimed);
+                return false;
+            }
+        };
         // CAS to ensure sentinel into old chunk (spec)
         // Use CAS for old threads may working on this one
         dfence(); // fence to ensure sentinel appears righr after pair copied to new chunk
         trace!("Copied key {} to new chunk", fkey);
-        if self.cas_sentinel(old_address, fvalue.raw) {
-            dfence();
-            if let Some(_new_entry_addr) = inserted_addr {
-                old_chunk_ins.attachment.erase(old_idx);
-                *effective_copy += 1;
-                return true;
-            }
+        let sentinel_placed = self.cas_sentinel(old_address, primed_fval);
+        debug_assert!(sentinel_placed);
+        dfence();
+        if let Some(_new_entry_addr) = inserted_addr {
+            *effective_copy += 1;
+        } else {
+            // Key existed in the new chunk, the old pair is of no use
+            old_chunk_ins.attachment.erase(old_idx, guard);
         }
-        false
+        true
     }
 
     pub fn map_is_copying(&self) -> bool {
         Self::is_copying(self.now_epoch())
     }
 
+    pub fn capacity(&self) -> usize {
+        let guard = crossbeam_epoch::pin();
+        let chunk = self.chunk.load(Acquire, &guard);
+        unsafe { chunk.deref() }.capacity
+    }
+
+    /// Migrate the entries into the smallest chunk that can hold them, also clears tombstones
+    pub fn shrink_to_fit(&self) {
+        if self.fixed {
+            return;
+        }
+        let guard = crossbeam_epoch::pin();
+        let backoff = crossbeam_utils::Backoff::new();
+        loop {
+            let epoch = self.now_epoch();
+            if Self::is_copying(epoch) {
+                // Finish the ongoing migration first, it may be left to following operations
+                let chunk_ptr = self.chunk.load(Acquire, &guard);
+                let new_chunk_ptr = self.new_chunk.load(Acquire, &guard);
+                if Self::to_chunk_ref(epoch, &chunk_ptr, &new_chunk_ptr).is_some() {
+                    self.help_migration(chunk_ptr, new_chunk_ptr, None, &guard);
+                }
+                backoff.spin();
+                continue;
+            }
+            let chunk_ptr = self.chunk.load(Acquire, &guard);
+            let chunk = unsafe { chunk_ptr.deref() };
+            let fit_cap = self.fit_capacity(self.len() + self.migration_headroom(chunk));
+            if fit_cap > chunk.capacity
+                || (fit_cap == chunk.capacity && chunk.empty_entries.load(Relaxed) == 0)
+            {
+                return;
+            }
+            match self.migrate_to(chunk_ptr, fit_cap, false, &guard) {
+                ResizeResult::Done | ResizeResult::NoMemory => return,
+                _ => {
+                    backoff.spin();
+                    continue;
+                }
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn shrink_capacity(&self, chunk: &Chunk<K, V, A, ALLOC>) -> Option<usize> {
+        let cap = chunk.capacity;
+        if cap <= self.init_cap || self.len() >= self.policy.shrink_threshold(cap) {
+            return None;
+        }
+        let new_cap = self
+            .fit_capacity(self.len() + self.migration_headroom(chunk))
+            .max(self.init_cap);
+        if new_cap < cap {
+            Some(new_cap)
+        } else {
+            None
+        }
+    }
+
+    // Smallest capacity that keeps the entries at most half full and under the load limit
+    #[inline(always)]
+    fn fit_capacity(&self, len: usize) -> usize {
+        let mut cap = (len << 1).next_power_of_two().max(2);
+        while len >= occupation_limit(&*self.policy, cap) {
+            cap <<= 1;
+        }
+        cap
+    }
+
     #[inline(always)]
     fn can_attach() -> bool {
-        can_attach::<K, V, A>()
+        can_attach::<K, V>()
     }
 }
 
 impl Value {
-    pub fn new<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default, H: Hasher + Default>(
+    pub fn new<K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone, H: Hasher + Default>(
         val: usize,
     ) -> Self {
         let res = {
@@ -1210,107 +2488,206 @@ impl Value {
     }
 }
 
-impl<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default> Chunk<K, V, A, ALLOC> {
-    fn alloc_chunk(capacity: usize) -> *mut Self {
-        let capacity = capacity;
+impl<K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone> Chunk<K, V, A, ALLOC> {
+    fn alloc_chunk(capacity: usize, occu_limit: usize, alloc: ALLOC) -> *mut Self {
+        let ptr = alloc_mem(&alloc, Self::total_size_of(capacity)) as *mut Self;
+        unsafe { Self::init(ptr, capacity, occu_limit, alloc) };
+        ptr
+    }
+
+    // `alloc_chunk` that gives none when the allocator has no memory left
+    fn try_alloc_chunk(capacity: usize, occu_limit: usize, alloc: ALLOC) -> Option<*mut Self> {
+        let ptr = try_alloc_mem(&alloc, Self::total_size_of(capacity))? as *mut Self;
+        unsafe { Self::init(ptr, capacity, occu_limit, alloc) };
+        Some(ptr)
+    }
+
+    // `total_size_of` that gives none when the size overflows or no allocation can have it
+    fn checked_size_of(capacity: usize) -> Option<usize> {
+        let self_size = mem::size_of::<Self>();
+        let self_size_aligned = self_size + align_padding(self_size, 64);
+        // Attachment heaps take the same size for every slot
+        let size = capacity
+            .checked_mul(Self::STRIDE + A::heap_size_of(1))?
+            .checked_add(self_size_aligned)?;
+        Layout::from_size_align(size, 64).ok()?;
+        Some(size)
+    }
+
+    // Bytes of a chunk of `capacity` slots, its entries and its attachment heap
+    fn total_size_of(capacity: usize) -> usize {
+        let self_size = mem::size_of::<Self>();
+        let self_size_aligned = self_size + align_padding(self_size, 64);
+        self_size_aligned + capacity * Self::STRIDE + A::heap_size_of(capacity)
+    }
+
+    // Write the chunk to zeroed memory of `total_size_of(capacity)` bytes, aligned to 64
+    unsafe fn init(ptr: *mut Self, capacity: usize, occu_limit: usize, alloc: ALLOC) {
         let self_size = mem::size_of::<Self>();
-        let self_align = align_padding(self_size, 64);
-        let self_size_aligned = self_size + self_align;
-        let chunk_size = chunk_size_of(capacity);
+        let self_size_aligned = self_size + align_padding(self_size, 64);
+        let chunk_size = capacity * Self::STRIDE;
         let attachment_heap = A::heap_size_of(capacity);
-        let total_size = self_size_aligned + chunk_size + attachment_heap;
-        let ptr = alloc_mem::<ALLOC>(total_size) as *mut Self;
-        let addr = ptr as usize;
-        let data_base = addr + self_size_aligned;
-        let attachment_base = data_base + chunk_size;
-        unsafe {
-            ptr::write(
-                ptr,
-                Self {
-                    base: data_base,
-                    capacity,
-                    occupation: AtomicUsize::new(0),
-                    empty_entries: AtomicUsize::new(0),
-                    occu_limit: occupation_limit(capacity),
-                    total_size,
-                    attachment: A::new(capacity, attachment_base, attachment_heap),
-                    shadow: PhantomData,
-                },
-            )
+        let total_size = Self::total_size_of(capacity);
+        let data_base = ptr as usize + self_size_aligned;
+        let attachment_base = if A::INLINE_SIZE == 0 {
+            data_base + chunk_size
+        } else {
+            data_base + ENTRY_SIZE
         };
-        ptr
+        let attachment_addr = ptr::addr_of_mut!((*ptr).attachment) as usize;
+        ptr::write(
+            ptr,
+            Self {
+                data: self_size_aligned,
+                capacity,
+                occupation: AtomicUsize::new(0),
+                empty_entries: AtomicUsize::new(0),
+                migrate_cursor: AtomicUsize::new(0),
+                migrated: AtomicUsize::new(0),
+                discard: AtomicBool::new(false),
+                occu_limit,
+                total_size,
+                attachment: A::new(
+                    capacity,
+                    attachment_addr,
+                    attachment_base,
+                    attachment_heap,
+                    &alloc,
+                ),
+                alloc,
+                shadow: PhantomData,
+            },
+        );
     }
 
     unsafe fn gc(ptr: *mut Chunk<K, V, A, ALLOC>) {
         debug_assert_ne!(ptr as usize, 0);
         let chunk = &*ptr;
+        if can_attach::<K, V>() {
+            // No one can reach the chunk now, release the pairs of live slots right away
+            for idx in 0..chunk.capacity {
+                let addr = chunk.entry_addr(idx) + mem::size_of::<usize>();
+                let val = *(addr as *const usize);
+                if Self::owns_pair(val) {
+                    drop(chunk.attachment.take(idx));
+                }
+            }
+        }
         chunk.attachment.dealloc();
-        dealloc_mem::<ALLOC>(ptr as usize, chunk.total_size);
+        // The allocator lives in the chunk, move it out before the memory is gone
+        let alloc = ptr::read(&chunk.alloc);
+        dealloc_mem(&alloc, ptr as usize, chunk.total_size);
     }
 
+    // Entries are spaced by the part of the attachment kept inline after each of them
+    const STRIDE: usize = ENTRY_SIZE + A::INLINE_SIZE;
+
     #[inline]
     fn cap_mask(&self) -> usize {
         self.capacity - 1
     }
+
+    #[inline(always)]
+    fn data_addr(&self) -> usize {
+        self as *const Self as usize + self.data
+    }
+
+    #[inline(always)]
+    fn entry_addr(&self, idx: usize) -> usize {
+        self.data_addr() + idx * Self::STRIDE
+    }
+
+    // Bring the entry the hash starts probing from into the cache, and its attachment slot
+    #[inline(always)]
+    fn prefetch(&self, hash: usize, attached: bool) {
+        let idx = hash & self.cap_mask();
+        unsafe { intrinsics::prefetch_read_data(self.entry_addr(idx) as *const u8, 3) }
+        if attached {
+            self.attachment.prefetch(idx);
+        }
+    }
+
+    #[inline(always)]
+    fn owns_pair(raw: usize) -> bool {
+        raw != EMPTY_VALUE && raw != TOMBSTONE_VALUE && raw & VAL_BIT_MASK != SENTINEL_VALUE
+    }
 }
 
-impl<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Clone
-    for Table<K, V, A, ALLOC, H>
+impl<
+        K: Clone + Hash + Eq,
+        V: Clone,
+        A: Attachment<K, V, ALLOC>,
+        ALLOC: Allocator + Clone,
+        H: Hasher + Default,
+    > Clone for Table<K, V, A, ALLOC, H>
 {
     fn clone(&self) -> Self {
-        let new_table = Table {
-            chunk: Default::default(),
-            new_chunk: Default::default(),
-            count: AtomicUsize::new(0),
-            epoch: AtomicUsize::new(0),
-            timestamp: AtomicU64::new(timestamp()),
-            mark: PhantomData,
-        };
         let guard = crossbeam_epoch::pin();
-        let old_chunk_ptr = self.chunk.load(Acquire, &guard);
-        let new_chunk_ptr = self.new_chunk.load(Acquire, &guard);
-        unsafe {
-            // Hold references first so they won't get reclaimed
-            let old_chunk = old_chunk_ptr.deref();
-            let old_total_size = old_chunk.total_size;
-
-            let cloned_old_ptr = alloc_mem::<ALLOC>(old_total_size) as *mut Chunk<K, V, A, ALLOC>;
-            debug_assert_ne!(cloned_old_ptr as usize, 0);
-            debug_assert_ne!(old_chunk.ptr as usize, 0);
-            libc::memcpy(
-                cloned_old_ptr as *mut c_void,
-                old_chunk.ptr as *const c_void,
-                old_total_size,
+        loop {
+            // Finish the migration so there is only one chunk to clone
+            let (epoch, chunk_ptr) = self.settled_chunk(&guard);
+            let chunk = unsafe { chunk_ptr.deref() };
+            let cloned_ptr = Chunk::<K, V, A, ALLOC>::alloc_chunk(
+                chunk.capacity,
+                chunk.occu_limit,
+                self.alloc.clone(),
             );
-            let cloned_old_ref = Owned::new(ChunkPtr::new(cloned_old_ptr));
-            new_table.chunk.store(cloned_old_ref, Release);
-
-            if new_chunk_ptr != Shared::null() {
-                let new_chunk = new_chunk_ptr.deref();
-                let new_total_size = new_chunk.total_size;
-                let cloned_new_ptr =
-                    alloc_mem::<ALLOC>(new_total_size) as *mut Chunk<K, V, A, ALLOC>;
+            unsafe {
+                let cloned = &*cloned_ptr;
+                debug_assert_ne!(cloned_ptr as usize, 0);
                 libc::memcpy(
-                    cloned_new_ptr as *mut c_void,
-                    new_chunk.ptr as *const c_void,
-                    new_total_size,
+                    cloned.data_addr() as *mut c_void,
+                    chunk.data_addr() as *const c_void,
+                    chunk.capacity * Chunk::<K, V, A, ALLOC>::STRIDE,
                 );
-                let cloned_new_ref = Owned::new(ChunkPtr::new(cloned_new_ptr));
-                new_table.new_chunk.store(cloned_new_ref, Release);
-            } else {
-                new_table.new_chunk.store(Shared::null(), Release);
+                // Every owning slot of the clone needs pairs of its own
+                for idx in 0..chunk.capacity {
+                    let addr = cloned.entry_addr(idx) + mem::size_of::<usize>();
+                    let val = *(addr as *const usize) & VAL_BIT_MASK;
+                    *(addr as *mut usize) = val;
+                    if Chunk::<K, V, A, ALLOC>::owns_pair(val) {
+                        let (key, value) = chunk.attachment.get(idx);
+                        cloned.attachment.set(idx, key, value);
+                    }
+                }
+                cloned
+                    .occupation
+                    .store(chunk.occupation.load(Acquire), Release);
+                cloned
+                    .empty_entries
+                    .store(chunk.empty_entries.load(Acquire), Release);
+            }
+            if self.epoch_changed(epoch) {
+                // The chunk have been migrated during the copy, try again
+                unsafe { Chunk::gc(cloned_ptr) };
+                continue;
             }
+            return Table {
+                chunk: Atomic::new(ChunkPtr::new(cloned_ptr)),
+                new_chunk: Atomic::null(),
+                count: AtomicUsize::new(self.len()),
+                epoch: AtomicUsize::new(0),
+                timestamp: AtomicU64::new(self.policy.now()),
+                init_cap: self.init_cap,
+                policy: self.policy.clone(),
+                alloc: self.alloc.clone(),
+                fixed: false,
+                mark: PhantomData,
+            };
         }
-        new_table.count.store(self.count.load(Acquire), Release);
-        new_table
     }
 }
 
-impl<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Drop
+impl<K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone, H: Hasher + Default> Drop
     for Table<K, V, A, ALLOC, H>
 {
     fn drop(&mut self) {
         let guard = crossbeam_epoch::pin();
+        if self.fixed {
+            // The chunk is left to whoever else maps it
+            unsafe { mem::forget(*self.chunk.load(Acquire, &guard).into_owned().into_box()) };
+            return;
+        }
         unsafe {
             guard.defer_destroy(self.chunk.load(Acquire, &guard));
             let new_chunk_ptr = self.new_chunk.load(Acquire, &guard);
@@ -1322,16 +2699,303 @@ impl<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default, H: Hasher + Defaul
     }
 }
 
-unsafe impl<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default> Send
+// Word tables own nothing outside of their chunks, a chunk kept in memory that outlives the
+// process, such as a file mapping, is all it takes to bring the table back
+impl<ALLOC: Allocator + Clone, H: Hasher + Default> Table<(), (), WordAttachment, ALLOC, H> {
+    /// Rebuild a table from the chunks left in persistent memory, given oldest first.
+    /// The newest chunk is the current one, unless an older chunk was not fully migrated when
+    /// the process stopped, which makes the newest one its migration target. The migration is
+    /// finished before returning. Chunks of no use to the table are returned to be released by
+    /// the caller, `None` if there is no chunk to recover.
+    pub(crate) unsafe fn recover(
+        chunks: &[usize],
+        init_cap: usize,
+        policy: Arc<dyn ResizePolicy>,
+        alloc: ALLOC,
+    ) -> Option<(Self, Vec<usize>)> {
+        type WordChunk<ALLOC> = Chunk<(), (), WordAttachment, ALLOC>;
+        let self_size = mem::size_of::<WordChunk<ALLOC>>();
+        let self_size_aligned = self_size + align_padding(self_size, 64);
+        let mut garbage = vec![];
+        let mut valid = vec![];
+        for &addr in chunks {
+            let ptr = addr as *mut WordChunk<ALLOC>;
+            let capacity = (*ptr).capacity;
+            let total_size = capacity
+                .checked_mul(ENTRY_SIZE)
+                .and_then(|size| size.checked_add(self_size_aligned));
+            if is_power_of_2(capacity)
+                && (*ptr).data == self_size_aligned
+                && Some((*ptr).total_size) == total_size
+            {
+                valid.push(ptr);
+            } else {
+                // Allocated but never written by `alloc_chunk`
+                garbage.push(addr);
+            }
+        }
+        let new_chunk = *valid.last()?;
+        let old_chunk = valid[..valid.len() - 1]
+            .iter()
+            .rev()
+            .find(|&&chunk| (*chunk).migrated.load(Acquire) < (*chunk).capacity)
+            .cloned();
+        for &chunk in &valid {
+            if chunk == new_chunk || Some(chunk) == old_chunk {
+                // The allocator is of the process that wrote the chunk
+                ptr::addr_of_mut!((*chunk).alloc).write(alloc.clone());
+            } else {
+                // Fully migrated, waiting for the epoch to release it when the process stopped
+                garbage.push(chunk as usize);
+            }
+        }
+        let table = Self {
+            chunk: Atomic::new(ChunkPtr::new(old_chunk.unwrap_or(new_chunk))),
+            new_chunk: match old_chunk {
+                Some(_) => Atomic::new(ChunkPtr::new(new_chunk)),
+                None => Atomic::null(),
+            },
+            count: AtomicUsize::new(0),
+            epoch: AtomicUsize::new(if old_chunk.is_some() { 1 } else { 0 }),
+            timestamp: AtomicU64::new(policy.now()),
+            init_cap,
+            policy,
+            alloc,
+            fixed: false,
+            mark: PhantomData,
+        };
+        for chunk in old_chunk.into_iter().chain(Some(new_chunk)) {
+            table.repair_chunk(&*chunk);
+        }
+        let guard = crossbeam_epoch::pin();
+        let chunk = table.settled_chunk(&guard).1.deref();
+        let live = (0..chunk.capacity)
+            .filter(
+                |idx| match table.get_fast_value(chunk.entry_addr(*idx)).parsed {
+                    ParsedValue::Val(v) => v != 0,
+                    _ => false,
+                },
+            )
+            .count();
+        table.count.store(live, Release);
+        Some((table, garbage))
+    }
+
+    // Undo the writes in flight when the process stopped. Primes are stripped, so entries
+    // being migrated or swapped are as before, and values without keys are emptied.
+    // The migration of an old chunk starts over, entries already copied are skipped.
+    fn repair_chunk(&self, chunk: &Chunk<(), (), WordAttachment, ALLOC>) {
+        let mut occupation = 0;
+        let mut empty_entries = 0;
+        for idx in 0..chunk.capacity {
+            let addr = chunk.entry_addr(idx);
+            let key = self.get_fast_key(addr);
+            let mut raw = self.get_fast_value(addr).raw & VAL_BIT_MASK;
+            if key == EMPTY_KEY && raw != EMPTY_VALUE && raw != SENTINEL_VALUE {
+                raw = EMPTY_VALUE;
+            }
+            unsafe {
+                intrinsics::atomic_store_rel((addr + mem::size_of::<usize>()) as *mut usize, raw)
+            }
+            if key != EMPTY_KEY {
+                occupation += 1;
+                if raw == TOMBSTONE_VALUE {
+                    empty_entries += 1;
+                }
+            }
+        }
+        chunk.occupation.store(occupation, Release);
+        chunk.empty_entries.store(empty_entries, Release);
+        chunk.migrate_cursor.store(0, Release);
+        chunk.migrated.store(0, Release);
+    }
+
+    /// Drop the table but leave its chunk in memory to be recovered, after finishing the
+    /// migration in progress
+    pub(crate) fn detach(self) {
+        let guard = crossbeam_epoch::pin();
+        let chunk = self.settled_chunk(&guard).1;
+        let table = mem::ManuallyDrop::new(self);
+        unsafe {
+            let chunk_ptr = *chunk.into_owned().into_box();
+            let raw = chunk_ptr.ptr;
+            mem::forget(chunk_ptr);
+            ptr::drop_in_place(ptr::addr_of_mut!((*raw).alloc));
+            drop(ptr::read(&table.policy));
+            drop(ptr::read(&table.alloc));
+        }
+    }
+}
+
+unsafe impl<K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone> Send
     for ChunkPtr<K, V, A, ALLOC>
 {
 }
-unsafe impl<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default> Sync
+unsafe impl<K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone> Sync
     for ChunkPtr<K, V, A, ALLOC>
 {
 }
 
-impl<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default> Drop for ChunkPtr<K, V, A, ALLOC> {
+// Put the value back to the slot primed for a decision unless it is forgotten. Dropped when the
+// decision unwinds as well, the key would be held by the prime forever
+struct PrimedSlot {
+    addr: usize,
+    claimed: usize,
+    raw: usize,
+}
+
+impl Drop for PrimedSlot {
+    fn drop(&mut self) {
+        if self.claimed != self.raw {
+            let (_, unprimed) = unsafe {
+                intrinsics::atomic_cxchg_acqrel(self.addr as *mut usize, self.claimed, self.raw)
+            };
+            debug_assert!(unprimed);
+        }
+    }
+}
+
+/// Lazy iterator over the entries of a table, see `Table::iter`.
+/// Chunks it walks are kept alive by its guard, drop it early to let them go.
+pub struct TableIter<
+    'a,
+    K,
+    V,
+    A: Attachment<K, V, ALLOC>,
+    ALLOC: Allocator + Clone,
+    H: Hasher + Default,
+    R,
+> {
+    table: &'a Table<K, V, A, ALLOC, H>,
+    chunk: *const Chunk<K, V, A, ALLOC>,
+    idx: usize,
+    follow: Option<Follow<K, V, A, ALLOC>>,
+    project: fn(usize, usize, &K, &V) -> R,
+    guard: Guard,
+}
+
+// Probe state for the entries of a slot moved away after the walk started
+struct Follow<K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone> {
+    fkey: usize,
+    chunk: *const Chunk<K, V, A, ALLOC>,
+    idx: usize,
+    counter: usize,
+}
+
+impl<
+        'a,
+        K: Clone + Hash + Eq,
+        V: Clone,
+        A: Attachment<K, V, ALLOC>,
+        ALLOC: Allocator + Clone,
+        H: Hasher + Default,
+        R,
+    > Iterator for TableIter<'a, K, V, A, ALLOC, H, R>
+{
+    type Item = R;
+
+    fn next(&mut self) -> Option<R> {
+        let (chunk, idx, k, v) = self.next_entry()?;
+        let chunk = unsafe { &*chunk };
+        let attachment = &chunk.attachment;
+        Some(attachment.with_pair(idx, |key, value| (self.project)(k, v, key, value)))
+    }
+}
+
+impl<
+        'a,
+        K: Clone + Hash + Eq,
+        V: Clone,
+        A: Attachment<K, V, ALLOC>,
+        ALLOC: Allocator + Clone,
+        H: Hasher + Default,
+        R,
+    > TableIter<'a, K, V, A, ALLOC, H, R>
+{
+    // Next live slot of the walk, as the chunk, index, fast key and fast value
+    fn next_entry(&mut self) -> Option<(*const Chunk<K, V, A, ALLOC>, usize, usize, usize)> {
+        loop {
+            if let Some(follow) = &mut self.follow {
+                if let Some((idx, v)) = self.table.follow_next(follow, &self.guard) {
+                    return Some((follow.chunk, idx, follow.fkey, v));
+                }
+                self.follow = None;
+            }
+            let chunk = unsafe { &*self.chunk };
+            if self.idx >= chunk.capacity {
+                return None;
+            }
+            let idx = self.idx;
+            self.idx += 1;
+            let (k, val) = self.table.settled_entry(chunk, idx);
+            if k == EMPTY_KEY {
+                continue;
+            }
+            match val.parsed {
+                ParsedValue::Val(0) | ParsedValue::Empty => {}
+                ParsedValue::Val(v) => return Some((self.chunk, idx, k, v)),
+                ParsedValue::Sentinel => self.follow_moved(k),
+                ParsedValue::Prime(_) => unreachable!(),
+            }
+        }
+    }
+
+    // A resize started after the walk, the entries of the key are in a newer chunk
+    fn follow_moved(&mut self, fkey: usize) {
+        self.follow = Some(Follow {
+            fkey,
+            chunk: Table::<K, V, A, ALLOC, H>::chunk_raw(self.table.settled_chunk(&self.guard).1),
+            idx: hash::<H>(fkey),
+            counter: 0,
+        });
+    }
+}
+
+/// Lazy iterator removing the entries it yields, see `Table::drain`
+pub struct TableDrain<
+    'a,
+    K,
+    V,
+    A: Attachment<K, V, ALLOC>,
+    ALLOC: Allocator + Clone,
+    H: Hasher + Default,
+    R,
+> {
+    walk: TableIter<'a, K, V, A, ALLOC, H, ()>,
+    project: fn(usize, usize, K, V) -> R,
+}
+
+impl<
+        'a,
+        K: Clone + Hash + Eq,
+        V: Clone,
+        A: Attachment<K, V, ALLOC>,
+        ALLOC: Allocator + Clone,
+        H: Hasher + Default,
+        R,
+    > Iterator for TableDrain<'a, K, V, A, ALLOC, H, R>
+{
+    type Item = R;
+
+    fn next(&mut self) -> Option<R> {
+        let table = self.walk.table;
+        while let Some((chunk, idx, k, _)) = self.walk.next_entry() {
+            let chunk = unsafe { &*chunk };
+            let removed = table.remove_slot_if(chunk, idx, &self.walk.guard, |k, v, key, value| {
+                Some((k, v, key.clone(), value.clone()))
+            });
+            match removed {
+                Ok(Some((k, v, key, value))) => return Some((self.project)(k, v, key, value)),
+                Ok(None) => {}
+                Err(()) => self.walk.follow_moved(k),
+            }
+        }
+        table.shrink_after_removal(&self.walk.guard);
+        None
+    }
+}
+
+impl<K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone> Drop for ChunkPtr<K, V, A, ALLOC> {
     fn drop(&mut self) {
         debug_assert_ne!(self.ptr as usize, 0);
 
@@ -1341,7 +3005,9 @@ impl<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default> Drop for ChunkPtr<
     }
 }
 
-impl<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default> Deref for ChunkPtr<K, V, A, ALLOC> {
+impl<K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone> Deref
+    for ChunkPtr<K, V, A, ALLOC>
+{
     type Target = Chunk<K, V, A, ALLOC>;
 
     fn deref(&self) -> &Self::Target {
@@ -1350,7 +3016,7 @@ impl<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default> Deref for ChunkPtr
     }
 }
 
-impl<K, V, A: Attachment<K, V>, ALLOC: GlobalAlloc + Default> ChunkPtr<K, V, A, ALLOC> {
+impl<K, V, A: Attachment<K, V, ALLOC>, ALLOC: Allocator + Clone> ChunkPtr<K, V, A, ALLOC> {
     fn new(ptr: *mut Chunk<K, V, A, ALLOC>) -> Self {
         debug_assert_ne!(ptr as usize, 0);
         Self { ptr }
@@ -1363,13 +3029,8 @@ fn is_power_of_2(x: usize) -> bool {
 }
 
 #[inline(always)]
-fn occupation_limit(cap: usize) -> usize {
-    (cap as f64 * 0.75f64) as usize
-}
-
-#[inline(always)]
-fn chunk_size_of(cap: usize) -> usize {
-    cap * ENTRY_SIZE
+fn occupation_limit(policy: &dyn ResizePolicy, cap: usize) -> usize {
+    ((cap as f64 * policy.max_load_factor()) as usize).min(cap - 1)
 }
 
 #[inline(always)]
@@ -1383,7 +3044,18 @@ pub fn hash<H: Hasher + Default>(num: usize) -> usize {
 pub fn hash_key<K: Hash, H: Hasher + Default>(key: &K) -> usize {
     let mut hasher = H::default();
     key.hash(&mut hasher);
-    hasher.finish() as usize
+    // Zero is the empty key word
+    (hasher.finish() as usize).max(1)
+}
+
+// Reverse binary increment of the cursor over the buckets of the mask, home buckets already
+// visited are still covered in a chunk of any other power of two size
+#[inline(always)]
+fn next_cursor(cursor: usize, cap_mask: usize) -> usize {
+    (cursor | !cap_mask)
+        .reverse_bits()
+        .wrapping_add(1)
+        .reverse_bits()
 }
 
 #[inline(always)]
@@ -1392,29 +3064,70 @@ fn dfence() {
     fence(SeqCst);
 }
 
-const fn can_attach<K, V, A: Attachment<K, V>>() -> bool {
+const fn can_attach<K, V>() -> bool {
     mem::size_of::<(K, V)>() != 0
 }
 
-pub trait Attachment<K, V> {
+// A slot owns its key and value only while its fast value is live or primed, tombstones and
+// sentinels own nothing. Pairs are never written in place, they live in cells the slots point to.
+// Replaced cells are released through the epoch, so a reader always sees a whole pair.
+pub trait Attachment<K, V, ALLOC: Allocator + Clone> {
+    // Bytes kept right after each entry, in the cache line of the entry. When it is not zero,
+    // `new` is given the address after the first entry and the slots are spaced by the entries
+    const INLINE_SIZE: usize = 0;
     fn heap_size_of(cap: usize) -> usize;
-    fn new(cap: usize, heap_ptr: usize, heap_size: usize) -> Self;
+    // `at` is the address the attachment is written to in its chunk, where it stays. Slots
+    // found from there, rather than from `heap_ptr`, are found wherever the chunk is mapped
+    fn new(cap: usize, at: usize, heap_ptr: usize, heap_size: usize, alloc: &ALLOC) -> Self;
     fn get(&self, index: usize) -> (K, V);
+    // Fill a slot that owns nothing
     fn set(&self, index: usize, key: K, value: V);
-    fn erase(&self, index: usize);
+    // Replace the pair of an owning slot
+    fn replace(&self, index: usize, key: K, value: V, guard: &Guard);
+    // Release the pair of an owning slot once no reader can see it
+    fn erase(&self, index: usize, guard: &Guard);
+    // Hand the pair of an owning slot to an empty slot of another chunk
+    fn transfer(&self, index: usize, dest: &Self, dest_index: usize);
+    // Move the pair out of an owning slot no reader can reach
+    fn take(&self, index: usize) -> (K, V);
+    // Key and value of an owning slot. The key stays put until released through the epoch, so
+    // does the value in a `StableAttachment`. Readers that do not own the slot look at the pair
+    // by `with_pair` otherwise
+    fn key(&self, index: usize) -> &K;
+    fn value(&self, index: usize) -> &V;
+    // Clone of the value of an owning slot for readers that do not own it
+    #[inline(always)]
+    fn clone_value(&self, index: usize) -> V
+    where
+        V: Clone,
+    {
+        self.value(index).clone()
+    }
+    // Look at the pair of an owning slot as a reader that does not own it
+    #[inline(always)]
+    fn with_pair<R, F: FnOnce(&K, &V) -> R>(&self, index: usize, func: F) -> R {
+        func(self.key(index), self.value(index))
+    }
     fn dealloc(&self);
     fn probe(&self, index: usize, probe_key: &K) -> bool;
+    // Hint that the slot is about to be probed
+    #[inline(always)]
+    fn prefetch(&self, _index: usize) {}
 }
 
+// Attachments never writing a value in place, a value read from a slot stays whole until it is
+// released through the epoch. Only their values can be borrowed out of the table
+pub trait StableAttachment<K, V, ALLOC: Allocator + Clone>: Attachment<K, V, ALLOC> {}
+
 pub struct WordAttachment;
 
 // this attachment basically do nothing and sized zero
-impl Attachment<(), ()> for WordAttachment {
+impl<ALLOC: Allocator + Clone> Attachment<(), (), ALLOC> for WordAttachment {
     fn heap_size_of(_cap: usize) -> usize {
         0
     }
 
-    fn new(_cap: usize, _heap_ptr: usize, _heap_size: usize) -> Self {
+    fn new(_cap: usize, _at: usize, _heap_ptr: usize, _heap_size: usize, _alloc: &ALLOC) -> Self {
         Self
     }
 
@@ -1427,1053 +3140,4401 @@ impl Attachment<(), ()> for WordAttachment {
     fn set(&self, _index: usize, _key: (), _value: ()) {}
 
     #[inline(always)]
-    fn erase(&self, _index: usize) {}
+    fn replace(&self, _index: usize, _key: (), _value: (), _guard: &Guard) {}
 
     #[inline(always)]
-    fn dealloc(&self) {}
+    fn erase(&self, _index: usize, _guard: &Guard) {}
 
     #[inline(always)]
-    fn probe(&self, _index: usize, _value: &()) -> bool {
-        true
-    }
-}
-
-pub type WordTable<H, ALLOC> = Table<(), (), WordAttachment, H, ALLOC>;
-
-pub struct WordObjectAttachment<T, A: GlobalAlloc + Default> {
-    obj_chunk: usize,
-    obj_size: usize,
-    shadow: PhantomData<(T, A)>,
-}
-
-impl<T: Clone, A: GlobalAlloc + Default> Attachment<(), T> for WordObjectAttachment<T, A> {
-    fn heap_size_of(cap: usize) -> usize {
-        let obj_size = mem::size_of::<T>();
-        cap * obj_size
-    }
-
-    fn new(_cap: usize, heap_ptr: usize, _heap_size: usize) -> Self {
-        Self {
-            obj_chunk: heap_ptr,
-            obj_size: mem::size_of::<T>(),
-            shadow: PhantomData,
-        }
-    }
+    fn transfer(&self, _index: usize, _dest: &Self, _dest_index: usize) {}
 
     #[inline(always)]
-    fn get(&self, index: usize) -> ((), T) {
-        let addr = self.addr_by_index(index);
-        let v = unsafe { (*(addr as *mut T)).clone() };
-        ((), v)
+    fn take(&self, _index: usize) -> ((), ()) {
+        ((), ())
     }
 
     #[inline(always)]
-    fn set(&self, index: usize, _key: (), val: T) {
-        let addr = self.addr_by_index(index);
-        unsafe { ptr::write(addr as *mut T, val) }
+    fn key(&self, _index: usize) -> &() {
+        &()
     }
 
     #[inline(always)]
-    fn erase(&self, index: usize) {
-        drop(self.addr_by_index(index) as *mut T)
+    fn value(&self, _index: usize) -> &() {
+        &()
     }
 
     #[inline(always)]
     fn dealloc(&self) {}
 
+    #[inline(always)]
     fn probe(&self, _index: usize, _value: &()) -> bool {
         true
     }
 }
 
-pub type HashTable<K, V, ALLOC> =
-    Table<K, V, HashKVAttachment<K, V, ALLOC>, ALLOC, PassthroughHasher>;
+impl<ALLOC: Allocator + Clone> StableAttachment<(), (), ALLOC> for WordAttachment {}
 
-pub struct HashKVAttachment<K, V, A: GlobalAlloc + Default> {
-    obj_chunk: usize,
-    obj_size: usize,
-    shadow: PhantomData<(K, V, A)>,
+pub type WordTable<H, ALLOC> = Table<(), (), WordAttachment, H, ALLOC>;
+
+// Key and value words kept as they are next to each other, the entry only holds the hash and
+// the flags. Slots are written before the entry is published, values are replaced in place while
+// primed. Readers that do not own the slot take the value word by an atomic load
+pub struct WordPairAttachment {
+    // Offset of the slots from the attachment
+    offset: usize,
 }
 
-impl<K: Clone + Hash + Eq, V: Clone, A: GlobalAlloc + Default> Attachment<K, V>
-    for HashKVAttachment<K, V, A>
-{
+impl WordPairAttachment {
+    #[inline(always)]
+    fn slot_addr(&self, index: usize) -> *mut usize {
+        (self as *const Self as usize + self.offset + index * ENTRY_SIZE) as *mut usize
+    }
+
+    #[inline(always)]
+    fn store(&self, index: usize, key: usize, value: usize) {
+        let addr = self.slot_addr(index);
+        unsafe {
+            intrinsics::atomic_store_rel(addr, key);
+            intrinsics::atomic_store_rel(addr.add(1), value);
+        }
+    }
+}
+
+impl<ALLOC: Allocator + Clone> Attachment<usize, usize, ALLOC> for WordPairAttachment {
     fn heap_size_of(cap: usize) -> usize {
-        let obj_size = mem::size_of::<(K, V)>();
-        cap * obj_size
+        cap * ENTRY_SIZE
     }
 
-    fn new(_cap: usize, heap_ptr: usize, _heap_size: usize) -> Self {
+    fn new(_cap: usize, at: usize, heap_ptr: usize, _heap_size: usize, _alloc: &ALLOC) -> Self {
         Self {
-            obj_chunk: heap_ptr,
-            obj_size: mem::size_of::<(K, V)>(),
-            shadow: PhantomData,
+            offset: heap_ptr - at,
         }
     }
 
     #[inline(always)]
-    fn get(&self, index: usize) -> (K, V) {
-        let addr = self.addr_by_index(index);
-        unsafe { (*(addr as *mut (K, V))).clone() }
+    fn get(&self, index: usize) -> (usize, usize) {
+        let addr = self.slot_addr(index);
+        unsafe {
+            (
+                intrinsics::atomic_load_acq(addr),
+                intrinsics::atomic_load_acq(addr.add(1)),
+            )
+        }
     }
 
     #[inline(always)]
-    fn set(&self, index: usize, key: K, val: V) {
-        let addr = self.addr_by_index(index);
-        unsafe { ptr::write(addr as *mut (K, V), (key, val)) }
+    fn set(&self, index: usize, key: usize, value: usize) {
+        self.store(index, key, value)
     }
 
     #[inline(always)]
-    fn erase(&self, index: usize) {
-        drop(self.addr_by_index(index) as *mut (K, V))
+    fn replace(&self, index: usize, _key: usize, value: usize, _guard: &Guard) {
+        unsafe { intrinsics::atomic_store_rel(self.slot_addr(index).add(1), value) }
     }
 
     #[inline(always)]
-    fn dealloc(&self) {}
+    fn erase(&self, _index: usize, _guard: &Guard) {}
 
-    fn probe(&self, index: usize, key: &K) -> bool {
-        let addr = self.addr_by_index(index);
-        let pos_key = unsafe { &*(addr as *mut K) };
-        pos_key == key
+    #[inline(always)]
+    fn transfer(&self, index: usize, dest: &Self, dest_index: usize) {
+        let (key, value) = Attachment::<usize, usize, ALLOC>::get(self, index);
+        dest.store(dest_index, key, value)
     }
-}
 
-pub trait Map<K, V: Clone> {
-    fn with_capacity(cap: usize) -> Self;
-    fn get(&self, key: &K) -> Option<V>;
-    fn insert(&self, key: &K, value: V) -> Option<V>;
-    // Return None if insertion successful
-    fn try_insert(&self, key: &K, value: V) -> Option<V>;
-    fn remove(&self, key: &K) -> Option<V>;
-    fn entries(&self) -> Vec<(K, V)>;
-    fn contains_key(&self, key: &K) -> bool;
-    fn len(&self) -> usize;
-    // The func function should  be pure and have no side effect
-    fn get_or_insert<F: Fn() -> V>(&self, key: &K, func: F) -> V {
-        loop {
-            if self.contains_key(key) {
-                if let Some(value) = self.get(key) {
-                    return value;
-                }
-            } else {
-                let value = func();
-                if let Some(value) = self.try_insert(key, value.clone()) {
-                    return value;
-                }
-                return value;
-            }
-        }
+    #[inline(always)]
+    fn take(&self, index: usize) -> (usize, usize) {
+        Attachment::<usize, usize, ALLOC>::get(self, index)
     }
-}
 
-const NUM_FIX: usize = 5;
-const PLACEHOLDER_VAL: usize = NUM_FIX + 1;
+    #[inline(always)]
+    fn key(&self, index: usize) -> &usize {
+        unsafe { &*self.slot_addr(index) }
+    }
 
-impl<K: Clone + Hash + Eq, V: Clone, A: GlobalAlloc + Default> HashKVAttachment<K, V, A> {
-    fn addr_by_index(&self, index: usize) -> usize {
-        self.obj_chunk + index * self.obj_size
+    #[inline(always)]
+    fn value(&self, index: usize) -> &usize {
+        unsafe { &*self.slot_addr(index).add(1) }
     }
-}
 
-pub struct HashMap<
-    K: Clone + Hash + Eq,
-    V: Clone,
-    ALLOC: GlobalAlloc + Default = System,
-    H: Hasher + Default = DefaultHasher,
-> {
-    table: HashTable<K, V, ALLOC>,
-    shadow: PhantomData<H>,
-}
+    #[inline(always)]
+    fn clone_value(&self, index: usize) -> usize {
+        unsafe { intrinsics::atomic_load_acq(self.slot_addr(index).add(1)) }
+    }
 
-impl<K: Clone + Hash + Eq, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default>
-    HashMap<K, V, ALLOC, H>
-{
-    pub fn insert_with_op(&self, op: InsertOp, key: &K, value: V) -> Option<V> {
-        let hash = hash_key::<K, H>(&key);
-        self.table
-            .insert(op, key, Some(value), hash, PLACEHOLDER_VAL)
-            .map(|(_, v)| v)
+    #[inline(always)]
+    fn with_pair<R, F: FnOnce(&usize, &usize) -> R>(&self, index: usize, func: F) -> R {
+        let (key, value) = Attachment::<usize, usize, ALLOC>::get(self, index);
+        func(&key, &value)
     }
 
-    pub fn write(&self, key: &K) -> Option<HashMapWriteGuard<K, V, ALLOC, H>> {
-        HashMapWriteGuard::new(&self.table, key)
+    #[inline(always)]
+    fn dealloc(&self) {}
+
+    #[inline(always)]
+    fn probe(&self, index: usize, key: &usize) -> bool {
+        unsafe { intrinsics::atomic_load_acq(self.slot_addr(index)) == *key }
     }
-    pub fn read(&self, key: &K) -> Option<HashMapReadGuard<K, V, ALLOC, H>> {
-        HashMapReadGuard::new(&self.table, key)
+
+    #[inline(always)]
+    fn prefetch(&self, index: usize) {
+        unsafe { intrinsics::prefetch_read_data(self.slot_addr(index) as *const u8, 3) }
     }
 }
 
-impl<K: Clone + Hash + Eq, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Map<K, V>
-    for HashMap<K, V, ALLOC, H>
-{
-    fn with_capacity(cap: usize) -> Self {
+pub type FullWordTable<ALLOC> = Table<usize, usize, WordPairAttachment, ALLOC, PassthroughHasher>;
+
+// Slots of pointers to objects allocated one by one, an object is never changed once published
+struct Cells<T, A: Allocator + Clone> {
+    base: usize,
+    alloc: A,
+    shadow: PhantomData<T>,
+}
+
+impl<T, A: Allocator + Clone> Cells<T, A> {
+    fn heap_size_of(cap: usize) -> usize {
+        cap * mem::size_of::<usize>()
+    }
+
+    fn new(heap_ptr: usize, alloc: &A) -> Self {
         Self {
-            table: Table::with_capacity(cap),
+            base: heap_ptr,
+            alloc: alloc.clone(),
             shadow: PhantomData,
         }
     }
 
     #[inline(always)]
-    fn get(&self, key: &K) -> Option<V> {
-        let hash = hash_key::<K, H>(key);
-        self.table.get(key, hash, true).map(|v| v.1.unwrap())
+    fn slot_addr(&self, index: usize) -> *mut usize {
+        (self.base + index * mem::size_of::<usize>()) as *mut usize
     }
 
     #[inline(always)]
-    fn insert(&self, key: &K, value: V) -> Option<V> {
-        self.insert_with_op(InsertOp::Insert, key, value)
+    fn get(&self, index: usize) -> &T {
+        unsafe { &*(intrinsics::atomic_load_acq(self.slot_addr(index)) as *const T) }
     }
 
     #[inline(always)]
-    fn try_insert(&self, key: &K, value: V) -> Option<V> {
-        self.insert_with_op(InsertOp::TryInsert, key, value)
+    fn retire_cell(&self, cell: usize, guard: &Guard) {
+        if Layout::new::<T>().size() == 0 && !mem::needs_drop::<T>() {
+            return;
+        }
+        let alloc = self.alloc.clone();
+        unsafe { guard.defer_unchecked(move || drop(take_obj(&alloc, cell as *mut T))) }
     }
 
     #[inline(always)]
-    fn remove(&self, key: &K) -> Option<V> {
-        let hash = hash_key::<K, H>(&key);
-        self.table.remove(key, hash).map(|(_, v)| v)
+    fn set(&self, index: usize, obj: T) {
+        let cell = alloc_obj(&self.alloc, obj) as usize;
+        unsafe { intrinsics::atomic_store_rel(self.slot_addr(index), cell) }
     }
 
     #[inline(always)]
-    fn entries(&self) -> Vec<(K, V)> {
-        self.table
-            .entries()
-            .into_iter()
-            .map(|(_, _, k, v)| (k, v))
-            .collect()
+    fn replace(&self, index: usize, obj: T, guard: &Guard) {
+        let cell = alloc_obj(&self.alloc, obj) as usize;
+        let prev = unsafe { intrinsics::atomic_xchg_acqrel(self.slot_addr(index), cell) };
+        self.retire_cell(prev, guard);
     }
 
     #[inline(always)]
-    fn contains_key(&self, key: &K) -> bool {
-        let hash = hash_key::<K, H>(&key);
-        self.table.get(key, hash, false).is_some()
+    fn erase(&self, index: usize, guard: &Guard) {
+        // The slot keeps pointing to the cell for readers that are still on it
+        let cell = unsafe { intrinsics::atomic_load_acq(self.slot_addr(index)) };
+        self.retire_cell(cell, guard);
     }
 
     #[inline(always)]
-    fn len(&self) -> usize {
-        self.table.len()
+    fn transfer(&self, index: usize, dest: &Self, dest_index: usize) {
+        let cell = unsafe { intrinsics::atomic_load_acq(self.slot_addr(index)) };
+        unsafe { intrinsics::atomic_store_rel(dest.slot_addr(dest_index), cell) }
     }
-}
 
-impl<T, A: GlobalAlloc + Default> WordObjectAttachment<T, A> {
-    fn addr_by_index(&self, index: usize) -> usize {
-        self.obj_chunk + index * self.obj_size
+    #[inline(always)]
+    fn take(&self, index: usize) -> T {
+        let cell = unsafe { intrinsics::atomic_load_acq(self.slot_addr(index)) };
+        unsafe { take_obj(&self.alloc, cell as *mut T) }
     }
-}
 
-type ObjectTable<V, ALLOC, H> = Table<(), V, WordObjectAttachment<V, ALLOC>, ALLOC, H>;
+    #[inline(always)]
+    fn prefetch(&self, index: usize) {
+        unsafe { intrinsics::prefetch_read_data(self.slot_addr(index) as *const u8, 3) }
+    }
+}
 
-#[derive(Clone)]
-pub struct ObjectMap<
-    V: Clone,
-    ALLOC: GlobalAlloc + Default = System,
-    H: Hasher + Default = DefaultHasher,
-> {
-    table: ObjectTable<V, ALLOC, H>,
+pub struct WordObjectAttachment<T, A: Allocator + Clone> {
+    cells: Cells<T, A>,
 }
 
-impl<V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> ObjectMap<V, ALLOC, H> {
-    fn insert_with_op(&self, op: InsertOp, key: &usize, value: V) -> Option<V> {
-        self.table
-            .insert(op, &(), Some(value), key + NUM_FIX, PLACEHOLDER_VAL)
-            .map(|(_, v)| v)
+impl<T: Clone, A: Allocator + Clone> Attachment<(), T, A> for WordObjectAttachment<T, A> {
+    fn heap_size_of(cap: usize) -> usize {
+        Cells::<T, A>::heap_size_of(cap)
     }
 
-    pub fn read(&self, key: usize) -> Option<ObjectMapReadGuard<V, ALLOC, H>> {
-        ObjectMapReadGuard::new(&self.table, key)
+    fn new(_cap: usize, _at: usize, heap_ptr: usize, _heap_size: usize, alloc: &A) -> Self {
+        Self {
+            cells: Cells::new(heap_ptr, alloc),
+        }
     }
 
-    pub fn write(&self, key: usize) -> Option<ObjectMapWriteGuard<V, ALLOC, H>> {
-        ObjectMapWriteGuard::new(&self.table, key)
+    #[inline(always)]
+    fn get(&self, index: usize) -> ((), T) {
+        ((), self.cells.get(index).clone())
     }
-}
 
-impl<V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Map<usize, V>
-    for ObjectMap<V, ALLOC, H>
-{
-    fn with_capacity(cap: usize) -> Self {
-        Self {
-            table: Table::with_capacity(cap),
-        }
+    #[inline(always)]
+    fn set(&self, index: usize, _key: (), val: T) {
+        self.cells.set(index, val)
     }
 
     #[inline(always)]
-    fn get(&self, key: &usize) -> Option<V> {
-        self.table
-            .get(&(), key + NUM_FIX, true)
-            .map(|v| v.1.unwrap())
+    fn replace(&self, index: usize, _key: (), val: T, guard: &Guard) {
+        self.cells.replace(index, val, guard)
     }
 
     #[inline(always)]
-    fn insert(&self, key: &usize, value: V) -> Option<V> {
-        self.insert_with_op(InsertOp::Insert, key, value)
+    fn erase(&self, index: usize, guard: &Guard) {
+        self.cells.erase(index, guard)
     }
 
     #[inline(always)]
-    fn try_insert(&self, key: &usize, value: V) -> Option<V> {
-        self.insert_with_op(InsertOp::TryInsert, key, value)
+    fn transfer(&self, index: usize, dest: &Self, dest_index: usize) {
+        self.cells.transfer(index, &dest.cells, dest_index)
     }
 
     #[inline(always)]
-    fn remove(&self, key: &usize) -> Option<V> {
-        self.table.remove(&(), key + NUM_FIX).map(|(_, v)| v)
+    fn take(&self, index: usize) -> ((), T) {
+        ((), self.cells.take(index))
     }
 
     #[inline(always)]
-    fn entries(&self) -> Vec<(usize, V)> {
-        self.table
-            .entries()
-            .into_iter()
-            .map(|(_, k, _, v)| (k - NUM_FIX, v))
-            .collect()
+    fn key(&self, _index: usize) -> &() {
+        &()
     }
 
     #[inline(always)]
-    fn contains_key(&self, key: &usize) -> bool {
-        self.table.get(&(), key + NUM_FIX, false).is_some()
+    fn value(&self, index: usize) -> &T {
+        self.cells.get(index)
     }
 
     #[inline(always)]
-    fn len(&self) -> usize {
-        self.table.len()
+    fn dealloc(&self) {}
+
+    fn probe(&self, _index: usize, _value: &()) -> bool {
+        true
     }
-}
 
-#[derive(Clone)]
-pub struct WordMap<ALLOC: GlobalAlloc + Default = System, H: Hasher + Default = DefaultHasher> {
-    table: WordTable<ALLOC, H>,
+    #[inline(always)]
+    fn prefetch(&self, index: usize) {
+        self.cells.prefetch(index)
+    }
 }
 
-impl<ALLOC: GlobalAlloc + Default, H: Hasher + Default> WordMap<ALLOC, H> {
-    fn insert_with_op(&self, op: InsertOp, key: &usize, value: usize) -> Option<usize> {
-        self.table
-            .insert(op, &(), None, key + NUM_FIX, value + NUM_FIX)
-            .map(|(v, _)| v)
-    }
+impl<T: Clone, A: Allocator + Clone> StableAttachment<(), T, A> for WordObjectAttachment<T, A> {}
 
-    pub fn get_from_mutex(&self, key: &usize) -> Option<usize> {
-        self.get(key).map(|v| v & WORD_MUTEX_DATA_BIT_MASK)
-    }
+pub type HashTable<K, V, ALLOC> =
+    Table<K, V, HashKVAttachment<K, V, ALLOC>, ALLOC, PassthroughHasher>;
+
+pub struct HashKVAttachment<K, V, A: Allocator + Clone> {
+    cells: Cells<(K, V), A>,
 }
 
-impl<ALLOC: GlobalAlloc + Default, H: Hasher + Default> Map<usize, usize> for WordMap<ALLOC, H> {
-    fn with_capacity(cap: usize) -> Self {
+impl<K: Clone + Hash + Eq, V: Clone, A: Allocator + Clone> Attachment<K, V, A>
+    for HashKVAttachment<K, V, A>
+{
+    fn heap_size_of(cap: usize) -> usize {
+        Cells::<(K, V), A>::heap_size_of(cap)
+    }
+
+    fn new(_cap: usize, _at: usize, heap_ptr: usize, _heap_size: usize, alloc: &A) -> Self {
         Self {
-            table: Table::with_capacity(cap),
+            cells: Cells::new(heap_ptr, alloc),
         }
     }
 
     #[inline(always)]
-    fn get(&self, key: &usize) -> Option<usize> {
-        self.table
-            .get(&(), key + NUM_FIX, false)
-            .map(|v| v.0 - NUM_FIX)
+    fn get(&self, index: usize) -> (K, V) {
+        self.cells.get(index).clone()
     }
 
     #[inline(always)]
-    fn insert(&self, key: &usize, value: usize) -> Option<usize> {
-        self.insert_with_op(InsertOp::UpsertFast, key, value)
+    fn set(&self, index: usize, key: K, val: V) {
+        self.cells.set(index, (key, val))
     }
 
     #[inline(always)]
-    fn try_insert(&self, key: &usize, value: usize) -> Option<usize> {
-        self.insert_with_op(InsertOp::TryInsert, key, value)
+    fn replace(&self, index: usize, key: K, val: V, guard: &Guard) {
+        self.cells.replace(index, (key, val), guard)
     }
 
     #[inline(always)]
-    fn remove(&self, key: &usize) -> Option<usize> {
-        self.table
-            .remove(&(), key + NUM_FIX)
-            .map(|(v, _)| v - NUM_FIX)
+    fn erase(&self, index: usize, guard: &Guard) {
+        self.cells.erase(index, guard)
     }
-    fn entries(&self) -> Vec<(usize, usize)> {
-        self.table
-            .entries()
-            .into_iter()
-            .map(|(k, v, _, _)| (k - NUM_FIX, v - NUM_FIX))
-            .collect()
+
+    #[inline(always)]
+    fn transfer(&self, index: usize, dest: &Self, dest_index: usize) {
+        self.cells.transfer(index, &dest.cells, dest_index)
     }
 
     #[inline(always)]
-    fn contains_key(&self, key: &usize) -> bool {
-        self.get(key).is_some()
+    fn take(&self, index: usize) -> (K, V) {
+        self.cells.take(index)
     }
 
     #[inline(always)]
-    fn len(&self) -> usize {
-        self.table.len()
+    fn key(&self, index: usize) -> &K {
+        &self.cells.get(index).0
+    }
+
+    #[inline(always)]
+    fn value(&self, index: usize) -> &V {
+        &self.cells.get(index).1
+    }
+
+    #[inline(always)]
+    fn dealloc(&self) {}
+
+    fn probe(&self, index: usize, key: &K) -> bool {
+        &self.cells.get(index).0 == key
+    }
+
+    #[inline(always)]
+    fn prefetch(&self, index: usize) {
+        self.cells.prefetch(index)
     }
 }
 
-const WORD_MUTEX_DATA_BIT_MASK: usize = !0 << 2 >> 2;
+impl<K: Clone + Hash + Eq, V: Clone, A: Allocator + Clone> StableAttachment<K, V, A>
+    for HashKVAttachment<K, V, A>
+{
+}
 
-pub struct WordMutexGuard<
-    'a,
-    ALLOC: GlobalAlloc + Default = System,
-    H: Hasher + Default = DefaultHasher,
-> {
-    table: &'a WordTable<ALLOC, H>,
-    key: usize,
-    value: usize,
+// Inline slot of a version word and the pair, padded so entries stay on power of 2 boundaries
+const fn inline_size_of<K, V>() -> usize {
+    (ENTRY_SIZE + mem::size_of::<usize>() + mem::size_of::<(K, V)>()).next_power_of_two()
+        - ENTRY_SIZE
 }
 
-impl<'a, ALLOC: GlobalAlloc + Default, H: Hasher + Default> WordMutexGuard<'a, ALLOC, H> {
-    fn create(table: &'a WordTable<ALLOC, H>, key: usize) -> Option<Self> {
-        let key = key + NUM_FIX;
-        let value = 0;
-        match table.insert(
-            InsertOp::TryInsert,
-            &(),
-            Some(()),
-            key,
-            value | MUTEX_BIT_MASK,
-        ) {
-            None | Some((TOMBSTONE_VALUE, ())) | Some((EMPTY_VALUE, ())) => {
-                trace!("Created locked key {}", key);
-                Some(Self { table, key, value })
-            }
-            _ => {
-                trace!("Cannot create locked key {} ", key);
-                None
-            }
-        }
+// Pairs of plain data kept right after their entries. Up to 40 bytes of key and value share the
+// cache line of the entry. Values are written in place, the version word is odd while a pair is
+// being written and readers copy the pair again if the version changed under them.
+// The key stays put while the slot owns it, the value is only stable for the owner of the slot.
+// It is no `StableAttachment`, readers are given copies of the pair
+pub struct InlineKVAttachment<K, V> {
+    // Offset of the first slot from the attachment
+    offset: usize,
+    shadow: PhantomData<(K, V)>,
+}
+
+impl<K: Copy, V: Copy> InlineKVAttachment<K, V> {
+    const STRIDE: usize = ENTRY_SIZE + inline_size_of::<K, V>();
+
+    #[inline(always)]
+    fn slot_addr(&self, index: usize) -> usize {
+        self as *const Self as usize + self.offset + index * Self::STRIDE
     }
-    fn new(table: &'a WordTable<ALLOC, H>, key: usize) -> Option<Self> {
-        let key = key + NUM_FIX;
+
+    #[inline(always)]
+    fn version(&self, index: usize) -> &AtomicUsize {
+        unsafe { &*(self.slot_addr(index) as *const AtomicUsize) }
+    }
+
+    #[inline(always)]
+    fn pair(&self, index: usize) -> *mut (K, V) {
+        (self.slot_addr(index) + mem::size_of::<usize>()) as *mut (K, V)
+    }
+
+    #[inline(always)]
+    fn write<F: FnOnce(*mut (K, V))>(&self, index: usize, func: F) {
+        let version = self.version(index);
+        let ver = version.load(Relaxed);
+        version.store(ver + 1, Relaxed);
+        fence(Release);
+        func(self.pair(index));
+        version.store(ver + 2, Release);
+    }
+
+    #[inline(always)]
+    fn read(&self, index: usize) -> (K, V) {
         let backoff = crossbeam_utils::Backoff::new();
-        let guard = crossbeam_epoch::pin();
-        let value;
+        let version = self.version(index);
         loop {
-            let swap_res = table.swap(
-                key,
-                &(),
-                move |fast_value| {
-                    trace!("The key {} have value {}", key, fast_value);
-                    let locked_val = fast_value | MUTEX_BIT_MASK;
-                    if fast_value == locked_val {
-                        // Locked, unchanged
-                        trace!("The key {} have locked, unchanged and try again", key);
-                        None
-                    } else {
-                        // Obtain lock
-                        trace!(
-                            "The key {} have obtained, with value {}",
-                            key,
-                            fast_value & WORD_MUTEX_DATA_BIT_MASK
-                        );
-                        Some(locked_val)
-                    }
-                },
-                &guard,
-            );
-            match swap_res {
-                SwapResult::Succeed(val, _idx, _chunk) => {
-                    trace!("Lock on key {} succeed with value {}", key, val);
-                    value = val & WORD_MUTEX_DATA_BIT_MASK;
-                    break;
-                }
-                SwapResult::Failed | SwapResult::Aborted => {
-                    trace!("Lock on key {} failed, retry", key);
-                    backoff.spin();
-                    continue;
-                }
-                SwapResult::NotFound => {
-                    trace!("Cannot found key {} to lock", key);
-                    return None;
+            let ver = version.load(Acquire);
+            if ver & 1 == 0 {
+                let pair = unsafe {
+                    ptr::read_volatile(self.pair(index) as *const mem::MaybeUninit<(K, V)>)
+                };
+                fence(Acquire);
+                if version.load(Relaxed) == ver {
+                    return unsafe { pair.assume_init() };
                 }
             }
+            backoff.spin();
         }
-        debug_assert_ne!(value, 0);
-        let value = value - NUM_FIX;
-        Some(Self { table, key, value })
     }
+}
 
-    pub fn remove(self) -> usize {
-        trace!("Removing {}", self.key);
-        let res = self.table.remove(&(), self.key).unwrap().0;
-        mem::forget(self);
-        res | MUTEX_BIT_MASK
+impl<K: Copy + Eq, V: Copy, A: Allocator + Clone> Attachment<K, V, A> for InlineKVAttachment<K, V> {
+    const INLINE_SIZE: usize = inline_size_of::<K, V>();
+
+    fn heap_size_of(_cap: usize) -> usize {
+        0
     }
-}
 
-impl<'a, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Deref for WordMutexGuard<'a, ALLOC, H> {
-    type Target = usize;
+    fn new(_cap: usize, at: usize, heap_ptr: usize, _heap_size: usize, _alloc: &A) -> Self {
+        assert!(mem::align_of::<(K, V)>() <= mem::align_of::<usize>());
+        Self {
+            offset: heap_ptr - at,
+            shadow: PhantomData,
+        }
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.value
+    #[inline(always)]
+    fn get(&self, index: usize) -> (K, V) {
+        self.read(index)
     }
-}
 
-impl<'a, ALLOC: GlobalAlloc + Default, H: Hasher + Default> DerefMut
-    for WordMutexGuard<'a, ALLOC, H>
-{
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.value
+    #[inline(always)]
+    fn set(&self, index: usize, key: K, value: V) {
+        self.write(index, |pair| unsafe { ptr::write(pair, (key, value)) })
     }
-}
 
-impl<'a, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Drop for WordMutexGuard<'a, ALLOC, H> {
-    fn drop(&mut self) {
-        self.value += NUM_FIX;
-        trace!(
-            "Release lock for key {} with value {}",
-            self.key,
-            self.value
-        );
-        self.table.insert(
-            InsertOp::UpsertFast,
-            &(),
-            None,
-            self.key,
-            self.value & WORD_MUTEX_DATA_BIT_MASK,
-        );
+    #[inline(always)]
+    fn replace(&self, index: usize, _key: K, value: V, _guard: &Guard) {
+        self.write(index, |pair| unsafe {
+            ptr::addr_of_mut!((*pair).1).write(value)
+        })
     }
-}
 
-impl<ALLOC: GlobalAlloc + Default, H: Hasher + Default> WordMap<ALLOC, H> {
-    pub fn lock(&self, key: usize) -> Option<WordMutexGuard<ALLOC, H>> {
-        WordMutexGuard::new(&self.table, key)
+    #[inline(always)]
+    fn erase(&self, _index: usize, _guard: &Guard) {}
+
+    #[inline(always)]
+    fn transfer(&self, index: usize, dest: &Self, dest_index: usize) {
+        let (key, value) = self.read(index);
+        Attachment::<K, V, A>::set(dest, dest_index, key, value)
     }
-    pub fn try_insert_locked(&self, key: usize) -> Option<WordMutexGuard<ALLOC, H>> {
-        WordMutexGuard::create(&self.table, key)
+
+    #[inline(always)]
+    fn take(&self, index: usize) -> (K, V) {
+        self.read(index)
     }
-}
 
-pub struct HashMapReadGuard<
-    'a,
-    K: Clone + Eq + Hash,
-    V: Clone,
-    ALLOC: GlobalAlloc + Default = System,
-    H: Hasher + Default = DefaultHasher,
-> {
-    table: &'a HashTable<K, V, ALLOC>,
-    hash: usize,
-    key: K,
-    value: V,
-    _mark: PhantomData<H>,
+    #[inline(always)]
+    fn key(&self, index: usize) -> &K {
+        unsafe { &(*self.pair(index)).0 }
+    }
+
+    #[inline(always)]
+    fn value(&self, index: usize) -> &V {
+        unsafe { &(*self.pair(index)).1 }
+    }
+
+    #[inline(always)]
+    fn clone_value(&self, index: usize) -> V {
+        self.read(index).1
+    }
+
+    #[inline(always)]
+    fn with_pair<R, F: FnOnce(&K, &V) -> R>(&self, index: usize, func: F) -> R {
+        let (key, value) = self.read(index);
+        func(&key, &value)
+    }
+
+    #[inline(always)]
+    fn dealloc(&self) {}
+
+    #[inline(always)]
+    fn probe(&self, index: usize, key: &K) -> bool {
+        Attachment::<K, V, A>::key(self, index) == key
+    }
 }
 
-impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default>
-    HashMapReadGuard<'a, K, V, ALLOC, H>
-{
-    fn new(table: &'a HashTable<K, V, ALLOC>, key: &K) -> Option<Self> {
-        let backoff = crossbeam_utils::Backoff::new();
-        let guard = crossbeam_epoch::pin();
-        let hash = hash_key::<K, H>(&key);
-        let value: V;
+pub trait Map<K, V: Clone> {
+    fn get(&self, key: &K) -> Option<V>;
+    fn insert(&self, key: &K, value: V) -> Option<V>;
+    // Return None if insertion successful
+    fn try_insert(&self, key: &K, value: V) -> Option<V>;
+    fn remove(&self, key: &K) -> Option<V>;
+    fn entries(&self) -> Vec<(K, V)>;
+    fn contains_key(&self, key: &K) -> bool;
+    fn len(&self) -> usize;
+    // The func function should  be pure and have no side effect
+    fn get_or_insert<F: Fn() -> V>(&self, key: &K, func: F) -> V {
         loop {
-            let swap_res = table.swap(
-                hash,
-                key,
-                move |fast_value| {
-                    if fast_value != PLACEHOLDER_VAL - 1 {
-                        // Not write locked, can bump it by one
-                        trace!("Key hash {} is not write locked, will read lock", hash);
-                        Some(fast_value + 1)
-                    } else {
-                        trace!("Key hash {} is write locked, unchanged", hash);
-                        None
-                    }
-                },
-                &guard,
-            );
-            match swap_res {
-                SwapResult::Succeed(_, idx, chunk) => {
-                    let chunk_ref = unsafe { chunk.deref() };
-                    let (_, v) = chunk_ref.attachment.get(idx);
-                    value = v;
-                    break;
-                }
-                SwapResult::Failed | SwapResult::Aborted => {
-                    trace!("Lock on key hash {} failed, retry", hash);
-                    backoff.spin();
-                    continue;
+            if self.contains_key(key) {
+                if let Some(value) = self.get(key) {
+                    return value;
                 }
-                SwapResult::NotFound => {
-                    debug!("Cannot found hash key {} to lock", hash);
-                    return None;
+            } else {
+                let value = func();
+                if let Some(value) = self.try_insert(key, value.clone()) {
+                    return value;
                 }
+                return value;
             }
         }
-        Some(Self {
-            table,
-            key: key.clone(),
-            value,
-            hash,
-            _mark: Default::default(),
-        })
     }
 }
 
-impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Deref
-    for HashMapReadGuard<'a, K, V, ALLOC, H>
+/// A key of a map to get or initialize in one probe, see `HashMap::entry`.
+/// Nothing happens until one of the `or_*` functions is called
+#[must_use]
+pub struct Entry<
+    'a,
+    K,
+    V,
+    A: Attachment<K, V, ALLOC>,
+    ALLOC: Allocator + Clone,
+    H: Hasher + Default,
+    M = fn(&mut V),
+> {
+    table: &'a Table<K, V, A, ALLOC, H>,
+    key: &'a K,
+    fkey: usize,
+    modify: Option<M>,
+}
+
+impl<
+        'a,
+        K: Clone + Hash + Eq,
+        V: Clone,
+        A: Attachment<K, V, ALLOC>,
+        ALLOC: Allocator + Clone,
+        H: Hasher + Default,
+        M: FnOnce(&mut V),
+    > Entry<'a, K, V, A, ALLOC, H, M>
 {
-    type Target = V;
+    /// Change the value in place if the key is present, before it is returned
+    pub fn and_modify<F: FnOnce(&mut V)>(
+        self,
+        func: F,
+    ) -> Entry<'a, K, V, A, ALLOC, H, impl FnOnce(&mut V)> {
+        let modify = self.modify;
+        Entry {
+            table: self.table,
+            key: self.key,
+            fkey: self.fkey,
+            modify: Some(move |value: &mut V| {
+                if let Some(modify) = modify {
+                    modify(value);
+                }
+                func(value);
+            }),
+        }
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.value
+    pub fn or_insert(self, value: V) -> V {
+        self.or_insert_with(|| value)
+    }
+
+    /// Return the value of the key, or insert the one `init` makes. `init` is called at most
+    /// once per key, concurrent callers wait for the value it makes and return it instead
+    pub fn or_insert_with<F: FnOnce() -> V>(self, init: F) -> V {
+        // The table owns the slot before calling any of them, so they are never called twice
+        let modified = self.modify.is_some();
+        let modify = Cell::new(self.modify);
+        let init = Cell::new(Some(init));
+        let modify_once = |value: &mut V| modify.take().unwrap()(value);
+        self.table.get_or_init(
+            self.key,
+            self.fkey,
+            PLACEHOLDER_VAL,
+            if modified { Some(&modify_once) } else { None },
+            &|| init.take().unwrap()(),
+        )
+    }
+
+    pub fn or_default(self) -> V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
     }
 }
 
-impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Drop
-    for HashMapReadGuard<'a, K, V, ALLOC, H>
-{
-    fn drop(&mut self) {
-        trace!("Release read lock for hash key {}", self.hash);
-        let guard = crossbeam_epoch::pin();
-        self.table.swap(
-            self.hash,
-            &self.key,
-            |fast_value| {
-                debug_assert!(fast_value > PLACEHOLDER_VAL);
-                Some(fast_value - 1)
-            },
-            &guard,
-        );
+const NUM_FIX: usize = 5;
+const PLACEHOLDER_VAL: usize = NUM_FIX + 1;
+
+/// A key or a value that a word keyed map cannot represent, see `WordMap::MAX_KEY`,
+/// `WordMap::MAX_VALUE` and `ObjectMap::MAX_KEY`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangeError {
+    Key(usize),
+    Value(usize),
+}
+
+impl std::fmt::Display for RangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RangeError::Key(key) => write!(f, "key {} is out of range", key),
+            RangeError::Value(value) => write!(f, "value {} is out of range", value),
+        }
     }
 }
 
-pub struct HashMapWriteGuard<
-    'a,
-    K: Clone + Eq + Hash,
+impl std::error::Error for RangeError {}
+
+/// No slot is left for a new key in a table that cannot grow, see `shm`, or whose allocator
+/// has no memory left for a larger chunk, see `persistent`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FullError;
+
+impl std::fmt::Display for FullError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no slot is left for a new key")
+    }
+}
+
+impl std::error::Error for FullError {}
+
+/// Why a fallible insert did not insert
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InsertError {
+    Range(RangeError),
+    Full(FullError),
+}
+
+impl std::fmt::Display for InsertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InsertError::Range(e) => write!(f, "{}", e),
+            InsertError::Full(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for InsertError {}
+
+impl From<RangeError> for InsertError {
+    fn from(e: RangeError) -> Self {
+        InsertError::Range(e)
+    }
+}
+
+impl From<FullError> for InsertError {
+    fn from(e: FullError) -> Self {
+        InsertError::Full(e)
+    }
+}
+
+// Keys of `WordMap` and `ObjectMap` are shifted past the reserved key words
+#[inline(always)]
+fn fix_key(key: usize) -> Result<usize, RangeError> {
+    key.checked_add(NUM_FIX).ok_or(RangeError::Key(key))
+}
+
+// Writes cannot go on with a key that does not fit
+#[inline(always)]
+fn write_key(key: usize) -> usize {
+    fix_key(key).unwrap_or_else(|e| panic!("{}", e))
+}
+
+// Values of `WordMap` are shifted past the reserved value words and leave the top bits to the
+// prime and mutex flags
+#[inline(always)]
+fn fix_value(value: usize) -> Result<usize, RangeError> {
+    if value <= WORD_MUTEX_DATA_BIT_MASK - NUM_FIX {
+        Ok(value + NUM_FIX)
+    } else {
+        Err(RangeError::Value(value))
+    }
+}
+
+// Value word that `WordMap::insert` writes as earlier versions did, values out of range lose
+// their top bits to the entry flags and never land on the markers
+#[inline(always)]
+fn masked_value(value: usize) -> usize {
+    (value.wrapping_add(NUM_FIX) & WORD_MUTEX_DATA_BIT_MASK).max(NUM_FIX)
+}
+
+// Run a batch on the keys in range, the keys out of range are never found
+fn fixed_batch<R>(
+    keys: &[usize],
+    run: impl FnOnce(Vec<(&(), usize)>) -> Vec<Option<R>>,
+) -> Vec<Option<R>> {
+    let fixed = keys
+        .iter()
+        .map(|key| fix_key(*key).ok())
+        .collect::<Vec<_>>();
+    let mut results = run(fixed.iter().flatten().map(|key| (&(), *key)).collect()).into_iter();
+    fixed
+        .into_iter()
+        .map(|key| key.and_then(|_| results.next().unwrap()))
+        .collect()
+}
+
+pub struct HashMap<
+    K: Clone + Hash + Eq,
     V: Clone,
-    ALLOC: GlobalAlloc + Default = System,
+    ALLOC: Allocator + Clone = System,
     H: Hasher + Default = DefaultHasher,
 > {
-    table: &'a HashTable<K, V, ALLOC>,
-    hash: usize,
-    key: K,
-    value: V,
-    _mark: PhantomData<H>,
+    table: HashTable<K, V, ALLOC>,
+    shadow: PhantomData<H>,
 }
 
-impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default>
-    HashMapWriteGuard<'a, K, V, ALLOC, H>
+impl<K: Clone + Hash + Eq, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default>
+    HashMap<K, V, ALLOC, H>
 {
-    fn new(table: &'a HashTable<K, V, ALLOC>, key: &K) -> Option<Self> {
-        let backoff = crossbeam_utils::Backoff::new();
-        let guard = crossbeam_epoch::pin();
+    pub fn insert_with_op(&self, op: InsertOp, key: &K, value: V) -> Option<V> {
         let hash = hash_key::<K, H>(&key);
-        let value: V;
-        loop {
-            let swap_res = table.swap(
-                hash,
-                key,
-                move |fast_value| {
-                    if fast_value == PLACEHOLDER_VAL {
-                        // Not write locked, can bump it by one
-                        trace!("Key hash {} is write lockable, will write lock", hash);
-                        Some(fast_value - 1)
-                    } else {
-                        trace!("Key hash {} is write locked, unchanged", hash);
-                        None
-                    }
-                },
-                &guard,
-            );
-            match swap_res {
-                SwapResult::Succeed(_, idx, chunk) => {
-                    let chunk_ref = unsafe { chunk.deref() };
-                    let (_, v) = chunk_ref.attachment.get(idx);
-                    value = v;
-                    break;
-                }
-                SwapResult::Failed | SwapResult::Aborted => {
-                    trace!("Lock on key hash {} failed, retry", hash);
-                    backoff.spin();
-                    continue;
-                }
-                SwapResult::NotFound => {
-                    debug!("Cannot found hash key {} to lock", hash);
-                    return None;
-                }
-            }
+        self.table
+            .insert(op, key, Some(value), hash, PLACEHOLDER_VAL)
+            .map(|(_, v)| v)
+    }
+
+    pub fn write(&self, key: &K) -> Option<HashMapWriteGuard<K, V, ALLOC, H>> {
+        HashMapWriteGuard::new(&self.table, key)
+    }
+
+    /// Get or initialize the value of the key in one probe of the table
+    pub fn entry<'a>(
+        &'a self,
+        key: &'a K,
+    ) -> Entry<'a, K, V, HashKVAttachment<K, V, ALLOC>, ALLOC, PassthroughHasher> {
+        Entry {
+            table: &self.table,
+            key,
+            fkey: hash_key::<K, H>(key),
+            modify: None,
         }
-        Some(Self {
-            table,
-            key: key.clone(),
-            value,
-            hash,
-            _mark: Default::default(),
+    }
+    pub fn read(&self, key: &K) -> Option<HashMapReadGuard<K, V, ALLOC, H>> {
+        HashMapReadGuard::new(&self.table, key)
+    }
+
+    /// Borrow the value without cloning, it is kept alive by the guard even if it gets
+    /// replaced or removed in the meantime
+    pub fn get_ref<'a>(&'a self, key: &K, guard: &'a Guard) -> Option<&'a V> {
+        let hash = hash_key::<K, H>(key);
+        self.table
+            .get_ref(key, hash, true, guard)
+            .map(|v| v.1.unwrap())
+    }
+
+    pub fn get_with<R, F: FnOnce(&V) -> R>(&self, key: &K, func: F) -> Option<R> {
+        let guard = crossbeam_epoch::pin();
+        self.get_ref(key, &guard).map(func)
+    }
+
+    /// Get the values of the keys in one go, their entries are prefetched ahead of the lookups
+    /// and the epoch is pinned once for all of them
+    pub fn get_many(&self, keys: &[K]) -> Vec<Option<V>> {
+        let keys = keys
+            .iter()
+            .map(|key| (key, hash_key::<K, H>(key)))
+            .collect::<Vec<_>>();
+        self.table
+            .get_many(&keys, true)
+            .into_iter()
+            .map(|v| v.map(|(_, v)| v.unwrap()))
+            .collect()
+    }
+
+    /// Batch of `insert`, see `get_many`
+    pub fn insert_many(&self, entries: &[(K, V)]) -> Vec<Option<V>> {
+        let entries = entries
+            .iter()
+            .map(|(key, value)| {
+                let hash = hash_key::<K, H>(key);
+                (key, Some(value.clone()), hash, PLACEHOLDER_VAL)
+            })
+            .collect();
+        self.table
+            .insert_many(InsertOp::Insert, entries)
+            .into_iter()
+            .map(|v| v.map(|(_, v)| v))
+            .collect()
+    }
+
+    /// Batch of `remove`, see `get_many`
+    pub fn remove_many(&self, keys: &[K]) -> Vec<Option<V>> {
+        let keys = keys
+            .iter()
+            .map(|key| (key, hash_key::<K, H>(key)))
+            .collect::<Vec<_>>();
+        self.table
+            .remove_many(&keys)
+            .into_iter()
+            .map(|v| v.map(|(_, v)| v))
+            .collect()
+    }
+
+    /// Replace the value of the key by `func` applied on it, as one atomic step so concurrent
+    /// updates are never lost. `func` should have no side effects, it can be called more than once.
+    /// The key is held while `func` runs, writing it from `func` deadlocks, a panic leaves the
+    /// value as it was. Returns the new value, or none if the key is absent
+    pub fn update<F: Fn(&V) -> V>(&self, key: &K, func: F) -> Option<V> {
+        self.compute(key, |value| value.map(&func))
+    }
+
+    /// Atomic `update` that also covers absent keys, `func` returning none removes the entry.
+    /// Same as `update`, `func` shall not write the key. Returns the new value
+    pub fn compute<F: Fn(Option<&V>) -> Option<V>>(&self, key: &K, func: F) -> Option<V> {
+        let hash = hash_key::<K, H>(key);
+        self.table.compute(key, hash, PLACEHOLDER_VAL, &func).1
+    }
+
+    /// Insert the value, or atomically combine it with the existing one by `func`, which shall
+    /// not write the key, see `update`. Returns the new value
+    pub fn merge<F: Fn(&V, &V) -> V>(&self, key: &K, value: V, func: F) -> V {
+        self.compute(key, |old| match old {
+            Some(old) => Some(func(old, &value)),
+            None => Some(value.clone()),
         })
+        .unwrap()
     }
 
-    pub fn remove(self) -> V {
-        let res = self.table.remove(&self.key, self.hash).unwrap().1;
-        mem::forget(self);
-        res
+    /// Iterate over clones of the entries without collecting them, see `Table::iter`
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.table.iter(|_, _, k, v| (k.clone(), v.clone()))
     }
-}
 
-impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Deref
-    for HashMapWriteGuard<'a, K, V, ALLOC, H>
-{
-    type Target = V;
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.table.iter(|_, _, k, _| k.clone())
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.value
+    pub fn values(&self) -> impl Iterator<Item = V> + '_ {
+        self.table.iter(|_, _, _, v| v.clone())
+    }
+
+    /// Resumable scan from the cursor, see `Table::scan`
+    pub fn scan(&self, cursor: usize, limit: usize) -> (usize, Vec<(K, V)>) {
+        let (cursor, entries) = self.table.scan(cursor, limit);
+        (
+            cursor,
+            entries.into_iter().map(|(_, _, k, v)| (k, v)).collect(),
+        )
+    }
+
+    /// Keep only the entries `keep` returns true for, see `Table::retain`
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&self, mut keep: F) {
+        self.table.retain(|_, _, k, v| keep(k, v))
+    }
+
+    /// Remove every entry, see `Table::clear`
+    pub fn clear(&self) {
+        self.table.clear()
+    }
+
+    /// Remove the entries as they are yielded, see `Table::drain`
+    pub fn drain(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.table.drain(|_, _, k, v| (k, v))
+    }
+
+    pub fn shrink_to_fit(&self) {
+        self.table.shrink_to_fit()
     }
 }
 
-impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> DerefMut
-    for HashMapWriteGuard<'a, K, V, ALLOC, H>
+impl<K: Clone + Hash + Eq, V: Clone, ALLOC: Allocator + Clone + Default, H: Hasher + Default>
+    HashMap<K, V, ALLOC, H>
 {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.value
+    pub fn with_capacity(cap: usize) -> Self {
+        Self {
+            table: Table::with_capacity(cap),
+            shadow: PhantomData,
+        }
     }
 }
 
-impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Drop
-    for HashMapWriteGuard<'a, K, V, ALLOC, H>
+impl<K: Clone + Hash + Eq, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> Map<K, V>
+    for HashMap<K, V, ALLOC, H>
 {
-    fn drop(&mut self) {
-        trace!("Release read lock for hash key {}", self.hash);
-        let hash = hash_key::<K, H>(&self.key);
-        self.table.insert(
-            InsertOp::Insert,
-            &self.key,
-            Some(self.value.clone()),
-            hash,
-            PLACEHOLDER_VAL,
-        );
+    #[inline(always)]
+    fn get(&self, key: &K) -> Option<V> {
+        let hash = hash_key::<K, H>(key);
+        self.table.get(key, hash, true).map(|v| v.1.unwrap())
+    }
+
+    #[inline(always)]
+    fn insert(&self, key: &K, value: V) -> Option<V> {
+        self.insert_with_op(InsertOp::Insert, key, value)
+    }
+
+    #[inline(always)]
+    fn try_insert(&self, key: &K, value: V) -> Option<V> {
+        self.insert_with_op(InsertOp::TryInsert, key, value)
+    }
+
+    #[inline(always)]
+    fn remove(&self, key: &K) -> Option<V> {
+        let hash = hash_key::<K, H>(&key);
+        self.table.remove(key, hash).map(|(_, v)| v)
+    }
+
+    #[inline(always)]
+    fn entries(&self) -> Vec<(K, V)> {
+        self.table
+            .entries()
+            .into_iter()
+            .map(|(_, _, k, v)| (k, v))
+            .collect()
+    }
+
+    #[inline(always)]
+    fn contains_key(&self, key: &K) -> bool {
+        let hash = hash_key::<K, H>(&key);
+        self.table.get(key, hash, false).is_some()
+    }
+
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    fn get_or_insert<F: Fn() -> V>(&self, key: &K, func: F) -> V {
+        self.entry(key).or_insert_with(func)
     }
 }
 
-pub struct ObjectMapReadGuard<
-    'a,
-    V: Clone,
-    ALLOC: GlobalAlloc + Default = System,
+pub type InlineTable<K, V, ALLOC> = Table<K, V, InlineKVAttachment<K, V>, ALLOC, PassthroughHasher>;
+
+/// `HashMap` for small plain data keys and values, kept in the cache line of their entries by
+/// `InlineKVAttachment`. Values are copied out of the table, they cannot be borrowed
+pub struct InlineMap<
+    K: Copy + Hash + Eq,
+    V: Copy,
+    ALLOC: Allocator + Clone = System,
     H: Hasher + Default = DefaultHasher,
 > {
-    table: &'a ObjectTable<V, ALLOC, H>,
-    key: usize,
-    value: V,
-    _mark: PhantomData<H>,
+    table: InlineTable<K, V, ALLOC>,
+    shadow: PhantomData<H>,
 }
 
-impl<'a, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default>
-    ObjectMapReadGuard<'a, V, ALLOC, H>
+impl<K: Copy + Hash + Eq, V: Copy, ALLOC: Allocator + Clone + Default, H: Hasher + Default>
+    InlineMap<K, V, ALLOC, H>
 {
-    fn new(table: &'a ObjectTable<V, ALLOC, H>, key: usize) -> Option<Self> {
-        let backoff = crossbeam_utils::Backoff::new();
-        let guard = crossbeam_epoch::pin();
-        let hash = hash_key::<usize, H>(&key);
-        let value: V;
-        loop {
-            let swap_res = table.swap(
-                key,
-                &(),
-                move |fast_value| {
-                    if fast_value != PLACEHOLDER_VAL - 1 {
-                        // Not write locked, can bump it by one
-                        trace!("Key {} is not write locked, will read lock", hash);
-                        Some(fast_value + 1)
-                    } else {
-                        trace!("Key {} is write locked, unchanged", hash);
-                        None
-                    }
-                },
-                &guard,
-            );
-            match swap_res {
-                SwapResult::Succeed(_, idx, chunk) => {
-                    let chunk_ref = unsafe { chunk.deref() };
-                    let (_, v) = chunk_ref.attachment.get(idx);
-                    value = v;
-                    break;
-                }
-                SwapResult::Failed | SwapResult::Aborted => {
-                    trace!("Lock on key {} failed, retry", hash);
-                    backoff.spin();
-                    continue;
-                }
-                SwapResult::NotFound => {
-                    debug!("Cannot found hash key {} to lock", hash);
-                    return None;
-                }
-            }
+    pub fn with_capacity(cap: usize) -> Self {
+        Self {
+            table: Table::with_capacity(cap),
+            shadow: PhantomData,
         }
-        Some(Self {
-            table,
-            key,
-            value,
-            _mark: Default::default(),
-        })
     }
 }
 
-impl<'a, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Deref
-    for ObjectMapReadGuard<'a, V, ALLOC, H>
+impl<K: Copy + Hash + Eq, V: Copy, ALLOC: Allocator + Clone, H: Hasher + Default>
+    InlineMap<K, V, ALLOC, H>
 {
-    type Target = V;
+    fn insert_with_op(&self, op: InsertOp, key: &K, value: V) -> Option<V> {
+        let hash = hash_key::<K, H>(key);
+        self.table
+            .insert(op, key, Some(value), hash, PLACEHOLDER_VAL)
+            .map(|(_, v)| v)
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.value
+    /// Map on a chunk other processes may map as well, see `Table::fixed_at`
+    pub(crate) unsafe fn fixed_at(addr: usize, cap: usize, init: bool, alloc: ALLOC) -> Self {
+        Self {
+            table: Table::fixed_at(addr, cap, init, alloc),
+            shadow: PhantomData,
+        }
     }
-}
 
-impl<'a, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Drop
-    for ObjectMapReadGuard<'a, V, ALLOC, H>
-{
-    fn drop(&mut self) {
-        trace!("Release read lock for hash key {}", self.key);
-        let guard = crossbeam_epoch::pin();
-        self.table.swap(
-            self.key,
-            &(),
-            |fast_value| {
-                debug_assert!(fast_value > PLACEHOLDER_VAL);
-                Some(fast_value - 1)
-            },
-            &guard,
-        );
+    pub(crate) fn fixed_size(cap: usize) -> usize {
+        InlineTable::<K, V, ALLOC>::fixed_size(cap)
+    }
+
+    /// See `Table::fallible_insert`
+    pub(crate) fn fallible_insert(
+        &self,
+        op: InsertOp,
+        key: &K,
+        value: V,
+    ) -> Result<Option<V>, FullError> {
+        let hash = hash_key::<K, H>(key);
+        let res = self
+            .table
+            .fallible_insert(op, key, Some(value), hash, PLACEHOLDER_VAL)?;
+        Ok(res.map(|(_, v)| v))
+    }
+
+    /// See `HashMap::get_many`
+    pub fn get_many(&self, keys: &[K]) -> Vec<Option<V>> {
+        let keys = keys
+            .iter()
+            .map(|key| (key, hash_key::<K, H>(key)))
+            .collect::<Vec<_>>();
+        self.table
+            .get_many(&keys, true)
+            .into_iter()
+            .map(|v| v.map(|(_, v)| v.unwrap()))
+            .collect()
+    }
+
+    /// See `HashMap::update`
+    pub fn update<F: Fn(&V) -> V>(&self, key: &K, func: F) -> Option<V> {
+        self.compute(key, |value| value.map(&func))
+    }
+
+    /// See `HashMap::compute`
+    pub fn compute<F: Fn(Option<&V>) -> Option<V>>(&self, key: &K, func: F) -> Option<V> {
+        let hash = hash_key::<K, H>(key);
+        self.table.compute(key, hash, PLACEHOLDER_VAL, &func).1
+    }
+
+    /// Iterate over the entries without collecting them, see `Table::iter`. Values can be
+    /// replaced in place, each of them is read again by its key
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.keys()
+            .filter_map(move |key| self.get(&key).map(|value| (key, value)))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.table.iter(|_, _, k, _| *k)
+    }
+
+    /// Keep only the entries `keep` returns true for, see `Table::retain`
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&self, mut keep: F) {
+        self.table.retain(|_, _, k, v| keep(k, v))
+    }
+
+    /// Remove every entry, see `Table::clear`
+    pub fn clear(&self) {
+        self.table.clear()
+    }
+
+    /// Remove the entries as they are yielded, see `Table::drain`
+    pub fn drain(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.table.drain(|_, _, k, v| (k, v))
+    }
+
+    pub fn shrink_to_fit(&self) {
+        self.table.shrink_to_fit()
     }
 }
 
-pub struct ObjectMapWriteGuard<
-    'a,
+impl<K: Copy + Hash + Eq, V: Copy, ALLOC: Allocator + Clone, H: Hasher + Default> Map<K, V>
+    for InlineMap<K, V, ALLOC, H>
+{
+    #[inline(always)]
+    fn get(&self, key: &K) -> Option<V> {
+        let hash = hash_key::<K, H>(key);
+        self.table.get(key, hash, true).map(|v| v.1.unwrap())
+    }
+
+    #[inline(always)]
+    fn insert(&self, key: &K, value: V) -> Option<V> {
+        self.insert_with_op(InsertOp::Insert, key, value)
+    }
+
+    #[inline(always)]
+    fn try_insert(&self, key: &K, value: V) -> Option<V> {
+        self.insert_with_op(InsertOp::TryInsert, key, value)
+    }
+
+    #[inline(always)]
+    fn remove(&self, key: &K) -> Option<V> {
+        let hash = hash_key::<K, H>(key);
+        self.table.remove(key, hash).map(|(_, v)| v)
+    }
+
+    fn entries(&self) -> Vec<(K, V)> {
+        self.table
+            .entries()
+            .into_iter()
+            .map(|(_, _, k, v)| (k, v))
+            .collect()
+    }
+
+    #[inline(always)]
+    fn contains_key(&self, key: &K) -> bool {
+        let hash = hash_key::<K, H>(key);
+        self.table.get(key, hash, false).is_some()
+    }
+
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.table.len()
+    }
+}
+
+type ObjectTable<V, ALLOC, H> = Table<(), V, WordObjectAttachment<V, ALLOC>, ALLOC, H>;
+
+#[derive(Clone)]
+pub struct ObjectMap<
     V: Clone,
-    ALLOC: GlobalAlloc + Default = System,
+    ALLOC: Allocator + Clone = System,
     H: Hasher + Default = DefaultHasher,
 > {
-    table: &'a ObjectTable<V, ALLOC, H>,
-    key: usize,
-    value: V,
-    _mark: PhantomData<H>,
+    table: ObjectTable<V, ALLOC, H>,
 }
 
-impl<'a, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default>
-    ObjectMapWriteGuard<'a, V, ALLOC, H>
-{
-    fn new(table: &'a ObjectTable<V, ALLOC, H>, key: usize) -> Option<Self> {
-        let backoff = crossbeam_utils::Backoff::new();
-        let guard = crossbeam_epoch::pin();
-        let value: V;
-        let key = key + NUM_FIX;
-        loop {
-            let swap_res = table.swap(
-                key,
-                &(),
-                move |fast_value| {
-                    if fast_value == PLACEHOLDER_VAL {
-                        // Not write locked, can bump it by one
-                        trace!("Key {} is write lockable, will write lock", key);
-                        Some(fast_value - 1)
-                    } else {
-                        trace!("Key {} is write locked, unchanged", key);
-                        None
-                    }
-                },
-                &guard,
-            );
-            match swap_res {
-                SwapResult::Succeed(_, idx, chunk) => {
-                    let chunk_ref = unsafe { chunk.deref() };
-                    let (_, v) = chunk_ref.attachment.get(idx);
-                    value = v;
-                    break;
-                }
-                SwapResult::Failed | SwapResult::Aborted => {
-                    trace!("Lock on key {} failed, retry", key);
-                    backoff.spin();
-                    continue;
-                }
-                SwapResult::NotFound => {
-                    debug!("Cannot found key {} to lock", key);
-                    return None;
-                }
-            }
+impl<V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> ObjectMap<V, ALLOC, H> {
+    /// The largest key the map can hold, writing a larger key panics and reading it finds
+    /// nothing. `HashMap<usize, V>` takes every key
+    pub const MAX_KEY: usize = usize::MAX - NUM_FIX;
+
+    fn insert_with_op(&self, op: InsertOp, key: &usize, value: V) -> Option<V> {
+        self.table
+            .insert(op, &(), Some(value), write_key(*key), PLACEHOLDER_VAL)
+            .map(|(_, v)| v)
+    }
+
+    /// Insert, or give an error instead of panicking when the key is out of range
+    pub fn checked_insert(&self, key: &usize, value: V) -> Result<Option<V>, RangeError> {
+        fix_key(*key)?;
+        Ok(self.insert(key, value))
+    }
+
+    pub fn read(&self, key: usize) -> Option<ObjectMapReadGuard<V, ALLOC, H>> {
+        ObjectMapReadGuard::new(&self.table, fix_key(key).ok()?)
+    }
+
+    pub fn write(&self, key: usize) -> Option<ObjectMapWriteGuard<V, ALLOC, H>> {
+        ObjectMapWriteGuard::new(&self.table, fix_key(key).ok()?)
+    }
+
+    /// See `HashMap::entry`
+    pub fn entry(&self, key: &usize) -> Entry<'_, (), V, WordObjectAttachment<V, ALLOC>, ALLOC, H> {
+        Entry {
+            table: &self.table,
+            key: &(),
+            fkey: write_key(*key),
+            modify: None,
         }
-        Some(Self {
-            table,
-            key,
-            value,
-            _mark: Default::default(),
+    }
+
+    /// Borrow the value without cloning, it is kept alive by the guard even if it gets
+    /// replaced or removed in the meantime
+    pub fn get_ref<'a>(&'a self, key: &usize, guard: &'a Guard) -> Option<&'a V> {
+        self.table
+            .get_ref(&(), fix_key(*key).ok()?, true, guard)
+            .map(|v| v.1.unwrap())
+    }
+
+    pub fn get_with<R, F: FnOnce(&V) -> R>(&self, key: &usize, func: F) -> Option<R> {
+        let guard = crossbeam_epoch::pin();
+        self.get_ref(key, &guard).map(func)
+    }
+
+    /// See `HashMap::get_many`
+    pub fn get_many(&self, keys: &[usize]) -> Vec<Option<V>> {
+        fixed_batch(keys, |keys| {
+            self.table
+                .get_many(&keys, true)
+                .into_iter()
+                .map(|v| v.map(|(_, v)| v.unwrap()))
+                .collect()
         })
     }
 
-    pub fn remove(self) -> V {
-        let res = self.table.remove(&(), self.key).unwrap().1;
-        mem::forget(self);
-        res
+    /// See `HashMap::insert_many`
+    pub fn insert_many(&self, entries: &[(usize, V)]) -> Vec<Option<V>> {
+        let entries = entries
+            .iter()
+            .map(|(key, value)| (&(), Some(value.clone()), write_key(*key), PLACEHOLDER_VAL))
+            .collect();
+        self.table
+            .insert_many(InsertOp::Insert, entries)
+            .into_iter()
+            .map(|v| v.map(|(_, v)| v))
+            .collect()
     }
-}
 
-impl<'a, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Deref
-    for ObjectMapWriteGuard<'a, V, ALLOC, H>
-{
-    type Target = V;
+    /// See `HashMap::remove_many`
+    pub fn remove_many(&self, keys: &[usize]) -> Vec<Option<V>> {
+        fixed_batch(keys, |keys| {
+            self.table
+                .remove_many(&keys)
+                .into_iter()
+                .map(|v| v.map(|(_, v)| v))
+                .collect()
+        })
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.value
+    /// Atomic read-modify-write on the value of the key, see `HashMap::update`
+    pub fn update<F: Fn(&V) -> V>(&self, key: &usize, func: F) -> Option<V> {
+        self.compute(key, |value| value.map(&func))
     }
-}
 
-impl<'a, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> DerefMut
-    for ObjectMapWriteGuard<'a, V, ALLOC, H>
-{
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.value
+    /// See `HashMap::compute`
+    pub fn compute<F: Fn(Option<&V>) -> Option<V>>(&self, key: &usize, func: F) -> Option<V> {
+        self.table
+            .compute(&(), write_key(*key), PLACEHOLDER_VAL, &func)
+            .1
     }
-}
 
-impl<'a, V: Clone, ALLOC: GlobalAlloc + Default, H: Hasher + Default> Drop
-    for ObjectMapWriteGuard<'a, V, ALLOC, H>
-{
-    fn drop(&mut self) {
-        trace!("Release read lock for key {}", self.key);
-        self.table.insert(
-            InsertOp::Insert,
-            &(),
-            Some(self.value.clone()),
-            self.key,
-            PLACEHOLDER_VAL,
-        );
+    /// See `HashMap::merge`
+    pub fn merge<F: Fn(&V, &V) -> V>(&self, key: &usize, value: V, func: F) -> V {
+        self.compute(key, |old| match old {
+            Some(old) => Some(func(old, &value)),
+            None => Some(value.clone()),
+        })
+        .unwrap()
     }
-}
 
-pub struct HashSet<
-    T: Clone + Hash + Eq,
-    ALLOC: GlobalAlloc + Default = System,
-    H: Hasher + Default = DefaultHasher,
-> {
-    table: HashTable<T, (), ALLOC>,
-    shadow: PhantomData<H>,
+    /// Iterate over clones of the entries without collecting them, see `Table::iter`
+    pub fn iter(&self) -> impl Iterator<Item = (usize, V)> + '_ {
+        self.table.iter(|k, _, _, v| (k - NUM_FIX, v.clone()))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = usize> + '_ {
+        self.table.iter(|k, _, _, _| k - NUM_FIX)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = V> + '_ {
+        self.table.iter(|_, _, _, v| v.clone())
+    }
+
+    /// Resumable scan from the cursor, see `Table::scan`
+    pub fn scan(&self, cursor: usize, limit: usize) -> (usize, Vec<(usize, V)>) {
+        let (cursor, entries) = self.table.scan(cursor, limit);
+        let entries = entries
+            .into_iter()
+            .map(|(k, _, _, v)| (k - NUM_FIX, v))
+            .collect();
+        (cursor, entries)
+    }
+
+    /// Keep only the entries `keep` returns true for, see `Table::retain`
+    pub fn retain<F: FnMut(usize, &V) -> bool>(&self, mut keep: F) {
+        self.table.retain(|k, _, _, v| keep(k - NUM_FIX, v))
+    }
+
+    /// Remove every entry, see `Table::clear`
+    pub fn clear(&self) {
+        self.table.clear()
+    }
+
+    /// Remove the entries as they are yielded, see `Table::drain`
+    pub fn drain(&self) -> impl Iterator<Item = (usize, V)> + '_ {
+        self.table.drain(|k, _, _, v| (k - NUM_FIX, v))
+    }
+
+    pub fn shrink_to_fit(&self) {
+        self.table.shrink_to_fit()
+    }
 }
 
-impl<T: Clone + Hash + Eq, ALLOC: GlobalAlloc + Default, H: Hasher + Default> HashSet<T, ALLOC, H> {
+impl<V: Clone, ALLOC: Allocator + Clone + Default, H: Hasher + Default> ObjectMap<V, ALLOC, H> {
     pub fn with_capacity(cap: usize) -> Self {
         Self {
             table: Table::with_capacity(cap),
-            shadow: PhantomData,
         }
     }
+}
 
-    pub fn contains(&self, item: &T) -> bool {
-        let hash = hash_key::<T, H>(item);
-        self.table.get(item, hash, false).is_some()
+impl<V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> Map<usize, V>
+    for ObjectMap<V, ALLOC, H>
+{
+    #[inline(always)]
+    fn get(&self, key: &usize) -> Option<V> {
+        self.table
+            .get(&(), fix_key(*key).ok()?, true)
+            .map(|v| v.1.unwrap())
+    }
+
+    #[inline(always)]
+    fn insert(&self, key: &usize, value: V) -> Option<V> {
+        self.insert_with_op(InsertOp::Insert, key, value)
+    }
+
+    #[inline(always)]
+    fn try_insert(&self, key: &usize, value: V) -> Option<V> {
+        self.insert_with_op(InsertOp::TryInsert, key, value)
+    }
+
+    #[inline(always)]
+    fn remove(&self, key: &usize) -> Option<V> {
+        self.table.remove(&(), fix_key(*key).ok()?).map(|(_, v)| v)
+    }
+
+    #[inline(always)]
+    fn entries(&self) -> Vec<(usize, V)> {
+        self.table
+            .entries()
+            .into_iter()
+            .map(|(_, k, _, v)| (k - NUM_FIX, v))
+            .collect()
+    }
+
+    #[inline(always)]
+    fn contains_key(&self, key: &usize) -> bool {
+        match fix_key(*key) {
+            Ok(key) => self.table.get(&(), key, false).is_some(),
+            Err(_) => false,
+        }
+    }
+
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    fn get_or_insert<F: Fn() -> V>(&self, key: &usize, func: F) -> V {
+        self.entry(key).or_insert_with(func)
+    }
+}
+
+/// `usize` to `usize` map keeping the values in the entries, values are stored past the words
+/// reserved for the entry markers and below the entry flags.
+///
+/// `insert`, `try_insert` and `insert_many` keep the words of earlier versions: values above
+/// `MAX_VALUE` are stored with their top bits lost and the replaced values are returned plus 5.
+/// Keys above `MAX_KEY` used to wrap around onto the entry markers, they are not written any
+/// more and the calls return none for them. `checked_insert` and `checked_try_insert` return a
+/// `RangeError` for keys and values out of range and the replaced values as they were written,
+/// `FullWordMap` takes every word
+#[derive(Clone)]
+pub struct WordMap<ALLOC: Allocator + Clone = System, H: Hasher + Default = DefaultHasher> {
+    table: WordTable<ALLOC, H>,
+}
+
+impl<ALLOC: Allocator + Clone, H: Hasher + Default> WordMap<ALLOC, H> {
+    /// The largest key the map can hold, a larger key is never written and reading it finds
+    /// nothing. `FullWordMap` takes every key and value
+    pub const MAX_KEY: usize = usize::MAX - NUM_FIX;
+    /// The largest value the map can hold, the two top bits are taken by the entry flags and 5
+    /// words by the entry markers
+    pub const MAX_VALUE: usize = WORD_MUTEX_DATA_BIT_MASK - NUM_FIX;
+
+    fn insert_with_op(&self, op: InsertOp, key: &usize, value: usize) -> Option<usize> {
+        self.table
+            .insert(op, &(), None, fix_key(*key).ok()?, masked_value(value))
+            .map(|(v, _)| v)
+    }
+
+    pub(crate) fn checked_insert_with_op(
+        &self,
+        op: InsertOp,
+        key: &usize,
+        value: usize,
+    ) -> Result<Option<usize>, RangeError> {
+        let (key, value) = (fix_key(*key)?, fix_value(value)?);
+        Ok(self
+            .table
+            .insert(op, &(), None, key, value)
+            .map(|(v, _)| v - NUM_FIX))
+    }
+
+    /// Insert, or give an error when the key or the value is out of range. Returns the replaced
+    /// value as it was written
+    pub fn checked_insert(&self, key: &usize, value: usize) -> Result<Option<usize>, RangeError> {
+        self.checked_insert_with_op(InsertOp::UpsertFast, key, value)
+    }
+
+    /// Insert if the key is absent, or give an error when the key or the value is out of range.
+    /// Returns the present value as it was written
+    pub fn checked_try_insert(
+        &self,
+        key: &usize,
+        value: usize,
+    ) -> Result<Option<usize>, RangeError> {
+        self.checked_insert_with_op(InsertOp::TryInsert, key, value)
+    }
+
+    pub fn get_from_mutex(&self, key: &usize) -> Option<usize> {
+        self.get(key).map(|v| v & WORD_MUTEX_DATA_BIT_MASK)
+    }
+
+    /// See `HashMap::get_many`
+    pub fn get_many(&self, keys: &[usize]) -> Vec<Option<usize>> {
+        fixed_batch(keys, |keys| {
+            self.table
+                .get_many(&keys, false)
+                .into_iter()
+                .map(|v| v.map(|(v, _)| v - NUM_FIX))
+                .collect()
+        })
+    }
+
+    /// See `HashMap::insert_many`
+    pub fn insert_many(&self, entries: &[(usize, usize)]) -> Vec<Option<usize>> {
+        let fixed = entries
+            .iter()
+            .map(|(key, value)| fix_key(*key).ok().map(|key| (key, masked_value(*value))))
+            .collect::<Vec<_>>();
+        let entries = fixed
+            .iter()
+            .flatten()
+            .map(|(key, value)| (&(), None, *key, *value))
+            .collect();
+        let mut results = self
+            .table
+            .insert_many(InsertOp::UpsertFast, entries)
+            .into_iter();
+        fixed
+            .into_iter()
+            .map(|entry| entry.and_then(|_| results.next().unwrap().map(|(v, _)| v)))
+            .collect()
+    }
+
+    /// See `HashMap::remove_many`
+    pub fn remove_many(&self, keys: &[usize]) -> Vec<Option<usize>> {
+        fixed_batch(keys, |keys| {
+            self.table
+                .remove_many(&keys)
+                .into_iter()
+                .map(|v| v.map(|(v, _)| v - NUM_FIX))
+                .collect()
+        })
+    }
+
+    /// Replace the value of the key by `new` if it is `current`, as `AtomicUsize::compare_exchange`.
+    /// Returns the previous value on success, or the actual value and none if the key is absent
+    pub fn compare_exchange(
+        &self,
+        key: &usize,
+        current: usize,
+        new: usize,
+    ) -> Result<usize, Option<usize>> {
+        self.fetch_update(key, |value| if value == current { Some(new) } else { None })
+    }
+
+    /// Replace the value of the key by what `func` returns for it in one atomic step, or keep it
+    /// if `func` returns none. `func` can be called more than once on contention, new values
+    /// above `MAX_VALUE` are declined as well. Returns the previous value if it was replaced, the
+    /// value that was declined or none if the key is absent
+    pub fn fetch_update<F: Fn(usize) -> Option<usize>>(
+        &self,
+        key: &usize,
+        func: F,
+    ) -> Result<usize, Option<usize>> {
+        let key = match fix_key(*key) {
+            Ok(key) => key,
+            Err(_) => return Err(None),
+        };
+        let guard = crossbeam_epoch::pin();
+        let backoff = crossbeam_utils::Backoff::new();
+        let seen = Cell::new(None);
+        loop {
+            let swap_res = self.table.swap(
+                key,
+                &(),
+                |fast_value| {
+                    if fast_value & MUTEX_BIT_MASK == MUTEX_BIT_MASK {
+                        // Locked by a mutex guard, which writes its own value back
+                        seen.set(None);
+                        return None;
+                    }
+                    let value = fast_value - NUM_FIX;
+                    seen.set(Some(value));
+                    func(value).and_then(|new| fix_value(new).ok())
+                },
+                &guard,
+            );
+            match swap_res {
+                SwapResult::Succeed(fast_value, _, _) => return Ok(fast_value - NUM_FIX),
+                SwapResult::NotFound => return Err(None),
+                SwapResult::Aborted if seen.get().is_some() => return Err(seen.get()),
+                SwapResult::Aborted | SwapResult::Failed => {
+                    backoff.spin();
+                    continue;
+                }
+            }
+        }
+    }
+
+    /// Add to the value of the key. Returns the previous value, or none if the key is absent
+    pub fn fetch_add(&self, key: &usize, val: usize) -> Option<usize> {
+        self.fetch_update(key, |v| Some(v + val)).ok()
+    }
+
+    /// Subtract from the value of the key. Returns the previous value, or none if the key is
+    /// absent
+    pub fn fetch_sub(&self, key: &usize, val: usize) -> Option<usize> {
+        self.fetch_update(key, |v| Some(v - val)).ok()
+    }
+
+    /// Bitwise or on the value of the key. Returns the previous value, or none if the key is absent
+    pub fn fetch_or(&self, key: &usize, val: usize) -> Option<usize> {
+        self.fetch_update(key, |v| Some(v | val)).ok()
+    }
+
+    /// Bitwise and on the value of the key. Returns the previous value, or none if the key is
+    /// absent
+    pub fn fetch_and(&self, key: &usize, val: usize) -> Option<usize> {
+        self.fetch_update(key, |v| Some(v & val)).ok()
+    }
+
+    /// Iterate over the entries without collecting them, see `Table::iter`
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.table.iter(|k, v, _, _| (k - NUM_FIX, v - NUM_FIX))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = usize> + '_ {
+        self.table.iter(|k, _, _, _| k - NUM_FIX)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = usize> + '_ {
+        self.table.iter(|_, v, _, _| v - NUM_FIX)
+    }
+
+    /// Resumable scan from the cursor, see `Table::scan`
+    pub fn scan(&self, cursor: usize, limit: usize) -> (usize, Vec<(usize, usize)>) {
+        let (cursor, entries) = self.table.scan(cursor, limit);
+        let entries = entries
+            .into_iter()
+            .map(|(k, v, _, _)| (k - NUM_FIX, v - NUM_FIX))
+            .collect();
+        (cursor, entries)
+    }
+
+    /// Keep only the entries `keep` returns true for, see `Table::retain`
+    pub fn retain<F: FnMut(usize, usize) -> bool>(&self, mut keep: F) {
+        self.table
+            .retain(|k, v, _, _| keep(k - NUM_FIX, v - NUM_FIX))
+    }
+
+    /// Remove every entry, see `Table::clear`
+    pub fn clear(&self) {
+        self.table.clear()
+    }
+
+    /// Remove the entries as they are yielded, see `Table::drain`
+    pub fn drain(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.table.drain(|k, v, _, _| (k - NUM_FIX, v - NUM_FIX))
+    }
+
+    pub fn shrink_to_fit(&self) {
+        self.table.shrink_to_fit()
+    }
+
+    /// Map over the chunks left in persistent memory, see `Table::recover`
+    pub(crate) unsafe fn recover(
+        chunks: &[usize],
+        init_cap: usize,
+        policy: Arc<dyn ResizePolicy>,
+        alloc: ALLOC,
+    ) -> Option<(Self, Vec<usize>)> {
+        Table::recover(chunks, init_cap, policy, alloc)
+            .map(|(table, garbage)| (Self { table }, garbage))
+    }
+
+    pub(crate) fn detach(self) {
+        self.table.detach()
+    }
+
+    /// Map on a chunk other processes may map as well, see `Table::fixed_at`
+    pub(crate) unsafe fn fixed_at(addr: usize, cap: usize, init: bool, alloc: ALLOC) -> Self {
+        Self {
+            table: Table::fixed_at(addr, cap, init, alloc),
+        }
+    }
+
+    pub(crate) fn fixed_size(cap: usize) -> usize {
+        WordTable::<ALLOC, H>::fixed_size(cap)
+    }
+
+    /// See `Table::fallible_insert`, keys and values out of range are errors as well
+    pub(crate) fn fallible_insert(
+        &self,
+        op: InsertOp,
+        key: &usize,
+        value: usize,
+    ) -> Result<Option<usize>, InsertError> {
+        let res = self
+            .table
+            .fallible_insert(op, &(), None, fix_key(*key)?, fix_value(value)?)?;
+        Ok(res.map(|(v, _)| v - NUM_FIX))
+    }
+}
+
+impl<ALLOC: Allocator + Clone + Default, H: Hasher + Default> WordMap<ALLOC, H> {
+    pub fn with_capacity(cap: usize) -> Self {
+        Self {
+            table: Table::with_capacity(cap),
+        }
+    }
+}
+
+impl<ALLOC: Allocator + Clone, H: Hasher + Default> Map<usize, usize> for WordMap<ALLOC, H> {
+    #[inline(always)]
+    fn get(&self, key: &usize) -> Option<usize> {
+        self.table
+            .get(&(), fix_key(*key).ok()?, false)
+            .map(|v| v.0 - NUM_FIX)
+    }
+
+    #[inline(always)]
+    fn insert(&self, key: &usize, value: usize) -> Option<usize> {
+        self.insert_with_op(InsertOp::UpsertFast, key, value)
+    }
+
+    #[inline(always)]
+    fn try_insert(&self, key: &usize, value: usize) -> Option<usize> {
+        self.insert_with_op(InsertOp::TryInsert, key, value)
+    }
+
+    #[inline(always)]
+    fn remove(&self, key: &usize) -> Option<usize> {
+        self.table
+            .remove(&(), fix_key(*key).ok()?)
+            .map(|(v, _)| v - NUM_FIX)
+    }
+    fn entries(&self) -> Vec<(usize, usize)> {
+        self.table
+            .entries()
+            .into_iter()
+            .map(|(k, v, _, _)| (k - NUM_FIX, v - NUM_FIX))
+            .collect()
+    }
+
+    #[inline(always)]
+    fn contains_key(&self, key: &usize) -> bool {
+        self.get(key).is_some()
+    }
+
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.table.len()
+    }
+}
+
+/// `usize` to `usize` map taking every key and value. The words are kept in the attachment and
+/// the entry holds the hash of the key, so it does not have the in place atomics and the mutex
+/// of `WordMap`
+pub struct FullWordMap<ALLOC: Allocator + Clone = System, H: Hasher + Default = DefaultHasher> {
+    table: FullWordTable<ALLOC>,
+    shadow: PhantomData<H>,
+}
+
+impl<ALLOC: Allocator + Clone, H: Hasher + Default> FullWordMap<ALLOC, H> {
+    fn insert_with_op(&self, op: InsertOp, key: &usize, value: usize) -> Option<usize> {
+        let hash = hash_key::<usize, H>(key);
+        self.table
+            .insert(op, key, Some(value), hash, PLACEHOLDER_VAL)
+            .map(|(_, v)| v)
+    }
+
+    /// Atomic update of the value, see `HashMap::compute`
+    pub fn compute<F: Fn(Option<&usize>) -> Option<usize>>(
+        &self,
+        key: &usize,
+        func: F,
+    ) -> Option<usize> {
+        let hash = hash_key::<usize, H>(key);
+        self.table.compute(key, hash, PLACEHOLDER_VAL, &func).1
+    }
+
+    /// Iterate over the entries without collecting them, see `Table::iter`
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.table.iter(|_, _, k, v| (*k, *v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = usize> + '_ {
+        self.table.iter(|_, _, k, _| *k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = usize> + '_ {
+        self.table.iter(|_, _, _, v| *v)
+    }
+
+    /// Keep only the entries `keep` returns true for, see `Table::retain`
+    pub fn retain<F: FnMut(usize, usize) -> bool>(&self, mut keep: F) {
+        self.table.retain(|_, _, k, v| keep(*k, *v))
+    }
+
+    /// Remove every entry, see `Table::clear`
+    pub fn clear(&self) {
+        self.table.clear()
+    }
+
+    /// Remove the entries as they are yielded, see `Table::drain`
+    pub fn drain(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.table.drain(|_, _, k, v| (k, v))
+    }
+
+    pub fn shrink_to_fit(&self) {
+        self.table.shrink_to_fit()
+    }
+}
+
+impl<ALLOC: Allocator + Clone + Default, H: Hasher + Default> FullWordMap<ALLOC, H> {
+    pub fn with_capacity(cap: usize) -> Self {
+        Self {
+            table: Table::with_capacity(cap),
+            shadow: PhantomData,
+        }
+    }
+}
+
+impl<ALLOC: Allocator + Clone, H: Hasher + Default> Map<usize, usize> for FullWordMap<ALLOC, H> {
+    #[inline(always)]
+    fn get(&self, key: &usize) -> Option<usize> {
+        let hash = hash_key::<usize, H>(key);
+        self.table.get(key, hash, true).map(|v| v.1.unwrap())
+    }
+
+    #[inline(always)]
+    fn insert(&self, key: &usize, value: usize) -> Option<usize> {
+        self.insert_with_op(InsertOp::Insert, key, value)
+    }
+
+    #[inline(always)]
+    fn try_insert(&self, key: &usize, value: usize) -> Option<usize> {
+        self.insert_with_op(InsertOp::TryInsert, key, value)
+    }
+
+    #[inline(always)]
+    fn remove(&self, key: &usize) -> Option<usize> {
+        let hash = hash_key::<usize, H>(key);
+        self.table.remove(key, hash).map(|(_, v)| v)
+    }
+
+    fn entries(&self) -> Vec<(usize, usize)> {
+        self.table
+            .entries()
+            .into_iter()
+            .map(|(_, _, k, v)| (k, v))
+            .collect()
+    }
+
+    #[inline(always)]
+    fn contains_key(&self, key: &usize) -> bool {
+        let hash = hash_key::<usize, H>(key);
+        self.table.get(key, hash, false).is_some()
+    }
+
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.table.len()
+    }
+}
+
+const WORD_MUTEX_DATA_BIT_MASK: usize = !0 << 2 >> 2;
+
+pub struct WordMutexGuard<
+    'a,
+    ALLOC: Allocator + Clone = System,
+    H: Hasher + Default = DefaultHasher,
+> {
+    table: &'a WordTable<ALLOC, H>,
+    key: usize,
+    value: usize,
+    origin: usize,
+}
+
+impl<'a, ALLOC: Allocator + Clone, H: Hasher + Default> WordMutexGuard<'a, ALLOC, H> {
+    fn create(table: &'a WordTable<ALLOC, H>, key: usize) -> Option<Self> {
+        let key = write_key(key);
+        let value = 0;
+        match table.insert(
+            InsertOp::TryInsert,
+            &(),
+            Some(()),
+            key,
+            value | MUTEX_BIT_MASK,
+        ) {
+            None | Some((TOMBSTONE_VALUE, ())) | Some((EMPTY_VALUE, ())) => {
+                trace!("Created locked key {}", key);
+                Some(Self {
+                    table,
+                    key,
+                    value,
+                    origin: value,
+                })
+            }
+            _ => {
+                trace!("Cannot create locked key {} ", key);
+                None
+            }
+        }
+    }
+    fn new(table: &'a WordTable<ALLOC, H>, key: usize) -> Option<Self> {
+        let key = fix_key(key).ok()?;
+        let backoff = crossbeam_utils::Backoff::new();
+        let guard = crossbeam_epoch::pin();
+        let value;
+        loop {
+            let swap_res = table.swap(
+                key,
+                &(),
+                move |fast_value| {
+                    trace!("The key {} have value {}", key, fast_value);
+                    let locked_val = fast_value | MUTEX_BIT_MASK;
+                    if fast_value == locked_val {
+                        // Locked, unchanged
+                        trace!("The key {} have locked, unchanged and try again", key);
+                        None
+                    } else {
+                        // Obtain lock
+                        trace!(
+                            "The key {} have obtained, with value {}",
+                            key,
+                            fast_value & WORD_MUTEX_DATA_BIT_MASK
+                        );
+                        Some(locked_val)
+                    }
+                },
+                &guard,
+            );
+            match swap_res {
+                SwapResult::Succeed(val, _idx, _chunk) => {
+                    trace!("Lock on key {} succeed with value {}", key, val);
+                    value = val & WORD_MUTEX_DATA_BIT_MASK;
+                    break;
+                }
+                SwapResult::Failed | SwapResult::Aborted => {
+                    trace!("Lock on key {} failed, retry", key);
+                    backoff.spin();
+                    continue;
+                }
+                SwapResult::NotFound => {
+                    trace!("Cannot found key {} to lock", key);
+                    return None;
+                }
+            }
+        }
+        debug_assert_ne!(value, 0);
+        let value = value - NUM_FIX;
+        Some(Self {
+            table,
+            key,
+            value,
+            origin: value,
+        })
+    }
+
+    pub fn remove(self) -> usize {
+        trace!("Removing {}", self.key);
+        let res = self.table.remove(&(), self.key).unwrap().0;
+        mem::forget(self);
+        res | MUTEX_BIT_MASK
+    }
+}
+
+impl<'a, ALLOC: Allocator + Clone, H: Hasher + Default> Deref for WordMutexGuard<'a, ALLOC, H> {
+    type Target = usize;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<'a, ALLOC: Allocator + Clone, H: Hasher + Default> DerefMut for WordMutexGuard<'a, ALLOC, H> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.value
+    }
+}
+
+impl<'a, ALLOC: Allocator + Clone, H: Hasher + Default> Drop for WordMutexGuard<'a, ALLOC, H> {
+    fn drop(&mut self) {
+        // A value out of range would clobber the flags, the entry gets its value from before
+        // the lock back instead
+        let value = fix_value(self.value);
+        trace!("Release lock for key {} with value {:?}", self.key, value);
+        self.table.insert(
+            InsertOp::UpsertFast,
+            &(),
+            None,
+            self.key,
+            value.unwrap_or(self.origin + NUM_FIX),
+        );
+        if let Err(e) = value {
+            if !std::thread::panicking() {
+                panic!("{}", e);
+            }
+        }
+    }
+}
+
+impl<ALLOC: Allocator + Clone, H: Hasher + Default> WordMap<ALLOC, H> {
+    pub fn lock(&self, key: usize) -> Option<WordMutexGuard<ALLOC, H>> {
+        WordMutexGuard::new(&self.table, key)
+    }
+    pub fn try_insert_locked(&self, key: usize) -> Option<WordMutexGuard<ALLOC, H>> {
+        WordMutexGuard::create(&self.table, key)
+    }
+}
+
+pub struct HashMapReadGuard<
+    'a,
+    K: Clone + Eq + Hash,
+    V: Clone,
+    ALLOC: Allocator + Clone = System,
+    H: Hasher + Default = DefaultHasher,
+> {
+    table: &'a HashTable<K, V, ALLOC>,
+    hash: usize,
+    key: K,
+    value: V,
+    _mark: PhantomData<H>,
+}
+
+impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default>
+    HashMapReadGuard<'a, K, V, ALLOC, H>
+{
+    fn new(table: &'a HashTable<K, V, ALLOC>, key: &K) -> Option<Self> {
+        let backoff = crossbeam_utils::Backoff::new();
+        let guard = crossbeam_epoch::pin();
+        let hash = hash_key::<K, H>(&key);
+        let value: V;
+        loop {
+            let swap_res = table.swap(
+                hash,
+                key,
+                move |fast_value| {
+                    if fast_value != PLACEHOLDER_VAL - 1 {
+                        // Not write locked, can bump it by one
+                        trace!("Key hash {} is not write locked, will read lock", hash);
+                        Some(fast_value + 1)
+                    } else {
+                        trace!("Key hash {} is write locked, unchanged", hash);
+                        None
+                    }
+                },
+                &guard,
+            );
+            match swap_res {
+                SwapResult::Succeed(_, idx, chunk) => {
+                    let chunk_ref = unsafe { chunk.deref() };
+                    let (_, v) = chunk_ref.attachment.get(idx);
+                    value = v;
+                    break;
+                }
+                SwapResult::Failed | SwapResult::Aborted => {
+                    trace!("Lock on key hash {} failed, retry", hash);
+                    backoff.spin();
+                    continue;
+                }
+                SwapResult::NotFound => {
+                    debug!("Cannot found hash key {} to lock", hash);
+                    return None;
+                }
+            }
+        }
+        Some(Self {
+            table,
+            key: key.clone(),
+            value,
+            hash,
+            _mark: Default::default(),
+        })
+    }
+}
+
+impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> Deref
+    for HashMapReadGuard<'a, K, V, ALLOC, H>
+{
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> Drop
+    for HashMapReadGuard<'a, K, V, ALLOC, H>
+{
+    fn drop(&mut self) {
+        trace!("Release read lock for hash key {}", self.hash);
+        let guard = crossbeam_epoch::pin();
+        self.table.swap(
+            self.hash,
+            &self.key,
+            |fast_value| {
+                debug_assert!(fast_value > PLACEHOLDER_VAL);
+                Some(fast_value - 1)
+            },
+            &guard,
+        );
+    }
+}
+
+pub struct HashMapWriteGuard<
+    'a,
+    K: Clone + Eq + Hash,
+    V: Clone,
+    ALLOC: Allocator + Clone = System,
+    H: Hasher + Default = DefaultHasher,
+> {
+    table: &'a HashTable<K, V, ALLOC>,
+    hash: usize,
+    key: K,
+    value: V,
+    _mark: PhantomData<H>,
+}
+
+impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default>
+    HashMapWriteGuard<'a, K, V, ALLOC, H>
+{
+    fn new(table: &'a HashTable<K, V, ALLOC>, key: &K) -> Option<Self> {
+        let backoff = crossbeam_utils::Backoff::new();
+        let guard = crossbeam_epoch::pin();
+        let hash = hash_key::<K, H>(&key);
+        let value: V;
+        loop {
+            let swap_res = table.swap(
+                hash,
+                key,
+                move |fast_value| {
+                    if fast_value == PLACEHOLDER_VAL {
+                        // Not write locked, can bump it by one
+                        trace!("Key hash {} is write lockable, will write lock", hash);
+                        Some(fast_value - 1)
+                    } else {
+                        trace!("Key hash {} is write locked, unchanged", hash);
+                        None
+                    }
+                },
+                &guard,
+            );
+            match swap_res {
+                SwapResult::Succeed(_, idx, chunk) => {
+                    let chunk_ref = unsafe { chunk.deref() };
+                    let (_, v) = chunk_ref.attachment.get(idx);
+                    value = v;
+                    break;
+                }
+                SwapResult::Failed | SwapResult::Aborted => {
+                    trace!("Lock on key hash {} failed, retry", hash);
+                    backoff.spin();
+                    continue;
+                }
+                SwapResult::NotFound => {
+                    debug!("Cannot found hash key {} to lock", hash);
+                    return None;
+                }
+            }
+        }
+        Some(Self {
+            table,
+            key: key.clone(),
+            value,
+            hash,
+            _mark: Default::default(),
+        })
+    }
+
+    pub fn remove(self) -> V {
+        let res = self.table.remove(&self.key, self.hash).unwrap().1;
+        mem::forget(self);
+        res
+    }
+}
+
+impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> Deref
+    for HashMapWriteGuard<'a, K, V, ALLOC, H>
+{
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> DerefMut
+    for HashMapWriteGuard<'a, K, V, ALLOC, H>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.value
+    }
+}
+
+impl<'a, K: Clone + Eq + Hash, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> Drop
+    for HashMapWriteGuard<'a, K, V, ALLOC, H>
+{
+    fn drop(&mut self) {
+        trace!("Release read lock for hash key {}", self.hash);
+        let hash = hash_key::<K, H>(&self.key);
+        self.table.insert(
+            InsertOp::Insert,
+            &self.key,
+            Some(self.value.clone()),
+            hash,
+            PLACEHOLDER_VAL,
+        );
+    }
+}
+
+pub struct ObjectMapReadGuard<
+    'a,
+    V: Clone,
+    ALLOC: Allocator + Clone = System,
+    H: Hasher + Default = DefaultHasher,
+> {
+    table: &'a ObjectTable<V, ALLOC, H>,
+    key: usize,
+    value: V,
+    _mark: PhantomData<H>,
+}
+
+impl<'a, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default>
+    ObjectMapReadGuard<'a, V, ALLOC, H>
+{
+    fn new(table: &'a ObjectTable<V, ALLOC, H>, key: usize) -> Option<Self> {
+        let backoff = crossbeam_utils::Backoff::new();
+        let guard = crossbeam_epoch::pin();
+        let hash = hash_key::<usize, H>(&key);
+        let value: V;
+        loop {
+            let swap_res = table.swap(
+                key,
+                &(),
+                move |fast_value| {
+                    if fast_value != PLACEHOLDER_VAL - 1 {
+                        // Not write locked, can bump it by one
+                        trace!("Key {} is not write locked, will read lock", hash);
+                        Some(fast_value + 1)
+                    } else {
+                        trace!("Key {} is write locked, unchanged", hash);
+                        None
+                    }
+                },
+                &guard,
+            );
+            match swap_res {
+                SwapResult::Succeed(_, idx, chunk) => {
+                    let chunk_ref = unsafe { chunk.deref() };
+                    let (_, v) = chunk_ref.attachment.get(idx);
+                    value = v;
+                    break;
+                }
+                SwapResult::Failed | SwapResult::Aborted => {
+                    trace!("Lock on key {} failed, retry", hash);
+                    backoff.spin();
+                    continue;
+                }
+                SwapResult::NotFound => {
+                    debug!("Cannot found hash key {} to lock", hash);
+                    return None;
+                }
+            }
+        }
+        Some(Self {
+            table,
+            key,
+            value,
+            _mark: Default::default(),
+        })
+    }
+}
+
+impl<'a, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> Deref
+    for ObjectMapReadGuard<'a, V, ALLOC, H>
+{
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<'a, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> Drop
+    for ObjectMapReadGuard<'a, V, ALLOC, H>
+{
+    fn drop(&mut self) {
+        trace!("Release read lock for hash key {}", self.key);
+        let guard = crossbeam_epoch::pin();
+        self.table.swap(
+            self.key,
+            &(),
+            |fast_value| {
+                debug_assert!(fast_value > PLACEHOLDER_VAL);
+                Some(fast_value - 1)
+            },
+            &guard,
+        );
+    }
+}
+
+pub struct ObjectMapWriteGuard<
+    'a,
+    V: Clone,
+    ALLOC: Allocator + Clone = System,
+    H: Hasher + Default = DefaultHasher,
+> {
+    table: &'a ObjectTable<V, ALLOC, H>,
+    key: usize,
+    value: V,
+    _mark: PhantomData<H>,
+}
+
+impl<'a, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default>
+    ObjectMapWriteGuard<'a, V, ALLOC, H>
+{
+    fn new(table: &'a ObjectTable<V, ALLOC, H>, key: usize) -> Option<Self> {
+        let backoff = crossbeam_utils::Backoff::new();
+        let guard = crossbeam_epoch::pin();
+        let value: V;
+        loop {
+            let swap_res = table.swap(
+                key,
+                &(),
+                move |fast_value| {
+                    if fast_value == PLACEHOLDER_VAL {
+                        // Not write locked, can bump it by one
+                        trace!("Key {} is write lockable, will write lock", key);
+                        Some(fast_value - 1)
+                    } else {
+                        trace!("Key {} is write locked, unchanged", key);
+                        None
+                    }
+                },
+                &guard,
+            );
+            match swap_res {
+                SwapResult::Succeed(_, idx, chunk) => {
+                    let chunk_ref = unsafe { chunk.deref() };
+                    let (_, v) = chunk_ref.attachment.get(idx);
+                    value = v;
+                    break;
+                }
+                SwapResult::Failed | SwapResult::Aborted => {
+                    trace!("Lock on key {} failed, retry", key);
+                    backoff.spin();
+                    continue;
+                }
+                SwapResult::NotFound => {
+                    debug!("Cannot found key {} to lock", key);
+                    return None;
+                }
+            }
+        }
+        Some(Self {
+            table,
+            key,
+            value,
+            _mark: Default::default(),
+        })
+    }
+
+    pub fn remove(self) -> V {
+        let res = self.table.remove(&(), self.key).unwrap().1;
+        mem::forget(self);
+        res
+    }
+}
+
+impl<'a, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> Deref
+    for ObjectMapWriteGuard<'a, V, ALLOC, H>
+{
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<'a, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> DerefMut
+    for ObjectMapWriteGuard<'a, V, ALLOC, H>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.value
+    }
+}
+
+impl<'a, V: Clone, ALLOC: Allocator + Clone, H: Hasher + Default> Drop
+    for ObjectMapWriteGuard<'a, V, ALLOC, H>
+{
+    fn drop(&mut self) {
+        trace!("Release read lock for key {}", self.key);
+        self.table.insert(
+            InsertOp::Insert,
+            &(),
+            Some(self.value.clone()),
+            self.key,
+            PLACEHOLDER_VAL,
+        );
+    }
+}
+
+pub struct HashSet<
+    T: Clone + Hash + Eq,
+    ALLOC: Allocator + Clone = System,
+    H: Hasher + Default = DefaultHasher,
+> {
+    table: HashTable<T, (), ALLOC>,
+    shadow: PhantomData<H>,
+}
+
+impl<T: Clone + Hash + Eq, ALLOC: Allocator + Clone + Default, H: Hasher + Default>
+    HashSet<T, ALLOC, H>
+{
+    pub fn with_capacity(cap: usize) -> Self {
+        Self {
+            table: Table::with_capacity(cap),
+            shadow: PhantomData,
+        }
+    }
+}
+
+impl<T: Clone + Hash + Eq, ALLOC: Allocator + Clone, H: Hasher + Default> HashSet<T, ALLOC, H> {
+    pub fn contains(&self, item: &T) -> bool {
+        let hash = hash_key::<T, H>(item);
+        self.table.get(item, hash, false).is_some()
+    }
+
+    pub fn insert(&self, item: &T) -> bool {
+        let hash = hash_key::<T, H>(item);
+        self.table
+            .insert(InsertOp::TryInsert, item, Some(()), hash, !0)
+            .is_none()
+    }
+
+    pub fn remove(&self, item: &T) -> bool {
+        let hash = hash_key::<T, H>(item);
+        self.table.remove(item, hash).is_some()
+    }
+
+    pub fn items(&self) -> std::collections::HashSet<T> {
+        self.table
+            .entries()
+            .into_iter()
+            .map(|(_, _, item, _)| item)
+            .collect()
+    }
+
+    /// Iterate over clones of the items without collecting them, see `Table::iter`
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        self.table.iter(|_, _, item, _| item.clone())
+    }
+
+    /// Resumable scan from the cursor, see `Table::scan`
+    pub fn scan(&self, cursor: usize, limit: usize) -> (usize, Vec<T>) {
+        let (cursor, entries) = self.table.scan(cursor, limit);
+        (
+            cursor,
+            entries.into_iter().map(|(_, _, item, _)| item).collect(),
+        )
+    }
+
+    /// Keep only the items `keep` returns true for, see `Table::retain`
+    pub fn retain<F: FnMut(&T) -> bool>(&self, mut keep: F) {
+        self.table.retain(|_, _, item, _| keep(item))
+    }
+
+    /// Remove every item, see `Table::clear`
+    pub fn clear(&self) {
+        self.table.clear()
+    }
+
+    /// Remove the items as they are yielded, see `Table::drain`
+    pub fn drain(&self) -> impl Iterator<Item = T> + '_ {
+        self.table.drain(|_, _, item, _| item)
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn shrink_to_fit(&self) {
+        self.table.shrink_to_fit()
+    }
+}
+
+/// Set of `usize` on `WordTable`, items are the key words of the entries so there is no
+/// attachment to look at
+pub struct WordSet<ALLOC: Allocator + Clone = System, H: Hasher + Default = DefaultHasher> {
+    table: WordTable<ALLOC, H>,
+}
+
+impl<ALLOC: Allocator + Clone + Default, H: Hasher + Default> WordSet<ALLOC, H> {
+    pub fn with_capacity(cap: usize) -> Self {
+        Self {
+            table: Table::with_capacity(cap),
+        }
+    }
+}
+
+impl<ALLOC: Allocator + Clone, H: Hasher + Default> WordSet<ALLOC, H> {
+    /// The largest item the set can hold, inserting a larger item panics
+    pub const MAX_ITEM: usize = usize::MAX - NUM_FIX;
+
+    pub fn contains(&self, item: &usize) -> bool {
+        match fix_key(*item) {
+            Ok(key) => self.table.get(&(), key, false).is_some(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn insert(&self, item: &usize) -> bool {
+        self.table
+            .insert(
+                InsertOp::TryInsert,
+                &(),
+                Some(()),
+                write_key(*item),
+                PLACEHOLDER_VAL,
+            )
+            .is_none()
+    }
+
+    /// Insert, or give an error instead of panicking when the item is out of range
+    pub fn checked_insert(&self, item: &usize) -> Result<bool, RangeError> {
+        fix_key(*item)?;
+        Ok(self.insert(item))
+    }
+
+    pub fn remove(&self, item: &usize) -> bool {
+        match fix_key(*item) {
+            Ok(key) => self.table.remove(&(), key).is_some(),
+            Err(_) => false,
+        }
+    }
+
+    /// Iterate over the items without collecting them, see `Table::iter`
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.table.iter(|k, _, _, _| k - NUM_FIX)
+    }
+
+    /// Resumable scan from the cursor, see `Table::scan`
+    pub fn scan(&self, cursor: usize, limit: usize) -> (usize, Vec<usize>) {
+        let (cursor, entries) = self.table.scan(cursor, limit);
+        (
+            cursor,
+            entries
+                .into_iter()
+                .map(|(k, _, _, _)| k - NUM_FIX)
+                .collect(),
+        )
+    }
+
+    /// Keep only the items `keep` returns true for, see `Table::retain`
+    pub fn retain<F: FnMut(usize) -> bool>(&self, mut keep: F) {
+        self.table.retain(|k, _, _, _| keep(k - NUM_FIX))
+    }
+
+    /// Remove every item, see `Table::clear`
+    pub fn clear(&self) {
+        self.table.clear()
+    }
+
+    /// Remove the items as they are yielded, see `Table::drain`
+    pub fn drain(&self) -> impl Iterator<Item = usize> + '_ {
+        self.table.drain(|k, _, _, _| k - NUM_FIX)
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn shrink_to_fit(&self) {
+        self.table.shrink_to_fit()
+    }
+}
+
+const DEFAULT_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BuildError {
+    /// The capacity cannot be rounded up to a power of 2 or the chunk size overflows
+    CapacityOverflow,
+    /// The load factor of the resize policy is not in (0, 1]
+    InvalidLoadFactor(f64),
+    /// The allocator has no memory for the initial chunk
+    AllocFailed,
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::CapacityOverflow => write!(f, "capacity overflow"),
+            BuildError::InvalidLoadFactor(lf) => write!(f, "invalid load factor {}", lf),
+            BuildError::AllocFailed => write!(f, "memory allocation failed"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Configures and builds any of the maps with validated parameters.
+/// Capacities are rounded up to power of 2 instead of panicking, and building gives an error
+/// instead of aborting when the initial chunk is too large or cannot be allocated.
+pub struct MapBuilder<ALLOC = System, H = DefaultHasher> {
+    capacity: Option<usize>,
+    expected_elements: Option<usize>,
+    policy: Arc<dyn ResizePolicy>,
+    alloc: ALLOC,
+    mark: PhantomData<H>,
+}
+
+impl MapBuilder {
+    pub fn new() -> Self {
+        Self {
+            capacity: None,
+            expected_elements: None,
+            policy: Arc::new(DefaultResizePolicy),
+            alloc: System,
+            mark: PhantomData,
+        }
+    }
+}
+
+impl Default for MapBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<ALLOC: Allocator + Clone, H: Hasher + Default> MapBuilder<ALLOC, H> {
+    /// Number of slots in the initial chunk, rounded up to power of 2
+    pub fn capacity(mut self, cap: usize) -> Self {
+        self.capacity = Some(cap);
+        self
+    }
+
+    /// Number of entries the map shall hold without resizing, under the load factor of the
+    /// resize policy
+    pub fn expected_elements(mut self, num: usize) -> Self {
+        self.expected_elements = Some(num);
+        self
+    }
+
+    pub fn resize_policy(mut self, policy: Arc<dyn ResizePolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn hasher<NH: Hasher + Default>(self) -> MapBuilder<ALLOC, NH> {
+        MapBuilder {
+            capacity: self.capacity,
+            expected_elements: self.expected_elements,
+            policy: self.policy,
+            alloc: self.alloc,
+            mark: PhantomData,
+        }
+    }
+
+    /// Allocator for the chunks, each map built gets a clone of it
+    pub fn allocator<NA: Allocator + Clone>(self, alloc: NA) -> MapBuilder<NA, H> {
+        MapBuilder {
+            capacity: self.capacity,
+            expected_elements: self.expected_elements,
+            policy: self.policy,
+            alloc,
+            mark: PhantomData,
+        }
+    }
+
+    /// The power of 2 capacity of the initial chunk after validation
+    pub fn chunk_capacity(&self) -> Result<usize, BuildError> {
+        let load_factor = self.policy.max_load_factor();
+        if !(load_factor > 0.0 && load_factor <= 1.0) {
+            return Err(BuildError::InvalidLoadFactor(load_factor));
+        }
+        let mut cap = match (self.capacity, self.expected_elements) {
+            (None, None) => DEFAULT_CAPACITY,
+            (cap, expected) => cap.unwrap_or(0).max(expected.unwrap_or(0)),
+        }
+        .max(2)
+        .checked_next_power_of_two()
+        .ok_or(BuildError::CapacityOverflow)?;
+        if let Some(expected) = self.expected_elements {
+            while occupation_limit(&*self.policy, cap) < expected {
+                cap = cap.checked_mul(2).ok_or(BuildError::CapacityOverflow)?;
+            }
+        }
+        if cap.checked_mul(ENTRY_SIZE).is_none() {
+            return Err(BuildError::CapacityOverflow);
+        }
+        Ok(cap)
+    }
+
+    fn table<K: Clone + Hash + Eq, V: Clone, A: Attachment<K, V, ALLOC>, TH: Hasher + Default>(
+        &self,
+    ) -> Result<Table<K, V, A, ALLOC, TH>, BuildError> {
+        let cap = self.chunk_capacity()?;
+        // The entries of the map take more than the bare entry words checked above
+        Chunk::<K, V, A, ALLOC>::checked_size_of(cap).ok_or(BuildError::CapacityOverflow)?;
+        let occu_limit = occupation_limit(&*self.policy, cap);
+        let chunk = Chunk::try_alloc_chunk(cap, occu_limit, self.alloc.clone())
+            .ok_or(BuildError::AllocFailed)?;
+        Ok(Table::with_chunk(
+            chunk,
+            cap,
+            self.policy.clone(),
+            self.alloc.clone(),
+        ))
+    }
+
+    pub fn word_map(&self) -> Result<WordMap<ALLOC, H>, BuildError> {
+        Ok(WordMap {
+            table: self.table()?,
+        })
+    }
+
+    pub fn full_word_map(&self) -> Result<FullWordMap<ALLOC, H>, BuildError> {
+        Ok(FullWordMap {
+            table: self.table()?,
+            shadow: PhantomData,
+        })
+    }
+
+    pub fn pod_map<V: WordRepr>(&self) -> Result<PodMap<V, ALLOC, H>, BuildError> {
+        Ok(PodMap::with_map(self.word_map()?))
+    }
+
+    pub fn object_map<V: Clone>(&self) -> Result<ObjectMap<V, ALLOC, H>, BuildError> {
+        Ok(ObjectMap {
+            table: self.table()?,
+        })
+    }
+
+    pub fn hash_map<K: Clone + Hash + Eq, V: Clone>(
+        &self,
+    ) -> Result<HashMap<K, V, ALLOC, H>, BuildError> {
+        Ok(HashMap {
+            table: self.table()?,
+            shadow: PhantomData,
+        })
+    }
+
+    pub fn inline_map<K: Copy + Hash + Eq, V: Copy>(
+        &self,
+    ) -> Result<InlineMap<K, V, ALLOC, H>, BuildError> {
+        Ok(InlineMap {
+            table: self.table()?,
+            shadow: PhantomData,
+        })
+    }
+
+    pub fn owned_map<K: Clone + Hash + Eq, V>(
+        &self,
+    ) -> Result<OwnedMap<K, V, ALLOC, H>, BuildError> {
+        Ok(OwnedMap::with_map(self.hash_map()?, self.alloc.clone()))
+    }
+
+    pub fn hash_set<T: Clone + Hash + Eq>(&self) -> Result<HashSet<T, ALLOC, H>, BuildError> {
+        Ok(HashSet {
+            table: self.table()?,
+            shadow: PhantomData,
+        })
+    }
+
+    pub fn word_set(&self) -> Result<WordSet<ALLOC, H>, BuildError> {
+        Ok(WordSet {
+            table: self.table()?,
+        })
+    }
+
+    pub fn linked_object_map<T>(&self) -> Result<LinkedObjectMap<T, ALLOC, H>, BuildError> {
+        Ok(LinkedObjectMap::with_map(self.object_map()?))
+    }
+}
+
+// Size hints come from the input, they should not allocate a huge chunk up front
+#[cfg(feature = "serde")]
+const MAX_DESERIALIZE_HINT: usize = 4096;
+
+// Builder for the map a deserializer fills in, sized by the hint of the input
+#[cfg(feature = "serde")]
+pub(crate) fn deserialize_builder<ALLOC: Allocator + Clone + Default, H: Hasher + Default>(
+    size_hint: Option<usize>,
+) -> MapBuilder<ALLOC, H> {
+    let builder = MapBuilder::new().hasher::<H>().allocator(ALLOC::default());
+    match size_hint {
+        Some(num) => builder.expected_elements(num.min(MAX_DESERIALIZE_HINT)),
+        None => builder,
+    }
+}
+
+#[inline(always)]
+fn alloc_mem<A: Allocator>(alloc: &A, size: usize) -> usize {
+    try_alloc_mem(alloc, size)
+        .unwrap_or_else(|| handle_alloc_error(Layout::from_size_align(size, 64).unwrap()))
+}
+
+#[inline(always)]
+fn try_alloc_mem<A: Allocator>(alloc: &A, size: usize) -> Option<usize> {
+    let align = 64;
+    let layout = Layout::from_size_align(size, align).unwrap();
+    // must be all zeroed
+    let addr = alloc.allocate_zeroed(layout).ok()?.cast::<u8>().as_ptr() as usize;
+    debug_assert_eq!(addr % 64, 0);
+    Some(addr)
+}
+
+#[inline(always)]
+fn dealloc_mem<A: Allocator>(alloc: &A, ptr: usize, size: usize) {
+    let align = 64;
+    let layout = Layout::from_size_align(size, align).unwrap();
+    unsafe { alloc.deallocate(NonNull::new_unchecked(ptr as *mut u8), layout) }
+}
+
+// Move an object into memory of its own
+#[inline(always)]
+pub(crate) fn alloc_obj<T, A: Allocator>(alloc: &A, obj: T) -> *mut T {
+    let layout = Layout::new::<T>();
+    let ptr = if layout.size() == 0 {
+        NonNull::<T>::dangling().as_ptr()
+    } else {
+        match alloc.allocate(layout) {
+            Ok(ptr) => ptr.cast::<T>().as_ptr(),
+            Err(_) => handle_alloc_error(layout),
+        }
+    };
+    unsafe { ptr::write(ptr, obj) };
+    ptr
+}
+
+// Move an object out of memory from `alloc_obj` and free the memory
+#[inline(always)]
+pub(crate) unsafe fn take_obj<T, A: Allocator>(alloc: &A, ptr: *mut T) -> T {
+    let layout = Layout::new::<T>();
+    let obj = ptr::read(ptr);
+    if layout.size() != 0 {
+        alloc.deallocate(NonNull::new_unchecked(ptr as *mut u8), layout);
+    }
+    obj
+}
+
+pub struct PassthroughHasher {
+    num: u64,
+}
+
+impl Hasher for PassthroughHasher {
+    fn finish(&self) -> u64 {
+        self.num
+    }
+
+    fn write(&mut self, _bytes: &[u8]) {
+        unimplemented!()
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.num = i as u64
+    }
+}
+
+impl Default for PassthroughHasher {
+    fn default() -> Self {
+        Self { num: 0 }
+    }
+}
+
+fn timestamp() -> u64 {
+    let start = SystemTime::now();
+    let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap();
+    since_the_epoch.as_millis() as u64
+}
+
+// Maps are serialized from `iter` while writers keep going, so entries changed during the walk
+// may or may not be in the output. Deserialization inserts the entries into a new map.
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use super::*;
+    use serde::de::{Deserialize, Deserializer, Error, MapAccess, SeqAccess, Visitor};
+    use serde::ser::{Serialize, Serializer};
+    use std::fmt;
+
+    impl<ALLOC: Allocator + Clone, H: Hasher + Default> Serialize for WordMap<ALLOC, H> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.iter())
+        }
+    }
+
+    struct WordMapVisitor<ALLOC, H>(PhantomData<(ALLOC, H)>);
+
+    impl<'de, ALLOC: Allocator + Clone + Default, H: Hasher + Default> Visitor<'de>
+        for WordMapVisitor<ALLOC, H>
+    {
+        type Value = WordMap<ALLOC, H>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a map of words to words")
+        }
+
+        fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Self::Value, M::Error> {
+            let map = deserialize_builder(access.size_hint())
+                .word_map()
+                .map_err(M::Error::custom)?;
+            while let Some((key, value)) = access.next_entry()? {
+                map.checked_insert(&key, value).map_err(M::Error::custom)?;
+            }
+            Ok(map)
+        }
+    }
+
+    impl<'de, ALLOC: Allocator + Clone + Default, H: Hasher + Default> Deserialize<'de>
+        for WordMap<ALLOC, H>
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_map(WordMapVisitor(PhantomData))
+        }
+    }
+
+    impl<V: Clone + Serialize, ALLOC: Allocator + Clone, H: Hasher + Default> Serialize
+        for ObjectMap<V, ALLOC, H>
+    {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.iter())
+        }
+    }
+
+    struct ObjectMapVisitor<V, ALLOC, H>(PhantomData<(V, ALLOC, H)>);
+
+    impl<
+            'de,
+            V: Clone + Deserialize<'de>,
+            ALLOC: Allocator + Clone + Default,
+            H: Hasher + Default,
+        > Visitor<'de> for ObjectMapVisitor<V, ALLOC, H>
+    {
+        type Value = ObjectMap<V, ALLOC, H>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a map of words to objects")
+        }
+
+        fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Self::Value, M::Error> {
+            let map = deserialize_builder(access.size_hint())
+                .object_map()
+                .map_err(M::Error::custom)?;
+            while let Some((key, value)) = access.next_entry()? {
+                map.checked_insert(&key, value).map_err(M::Error::custom)?;
+            }
+            Ok(map)
+        }
+    }
+
+    impl<
+            'de,
+            V: Clone + Deserialize<'de>,
+            ALLOC: Allocator + Clone + Default,
+            H: Hasher + Default,
+        > Deserialize<'de> for ObjectMap<V, ALLOC, H>
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_map(ObjectMapVisitor(PhantomData))
+        }
+    }
+
+    impl<
+            K: Clone + Hash + Eq + Serialize,
+            V: Clone + Serialize,
+            ALLOC: Allocator + Clone,
+            H: Hasher + Default,
+        > Serialize for HashMap<K, V, ALLOC, H>
+    {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.iter())
+        }
+    }
+
+    struct HashMapVisitor<K, V, ALLOC, H>(PhantomData<(K, V, ALLOC, H)>);
+
+    impl<
+            'de,
+            K: Clone + Hash + Eq + Deserialize<'de>,
+            V: Clone + Deserialize<'de>,
+            ALLOC: Allocator + Clone + Default,
+            H: Hasher + Default,
+        > Visitor<'de> for HashMapVisitor<K, V, ALLOC, H>
+    {
+        type Value = HashMap<K, V, ALLOC, H>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a map")
+        }
+
+        fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Self::Value, M::Error> {
+            let map = deserialize_builder(access.size_hint())
+                .hash_map()
+                .map_err(M::Error::custom)?;
+            while let Some((key, value)) = access.next_entry()? {
+                map.insert(&key, value);
+            }
+            Ok(map)
+        }
+    }
+
+    impl<
+            'de,
+            K: Clone + Hash + Eq + Deserialize<'de>,
+            V: Clone + Deserialize<'de>,
+            ALLOC: Allocator + Clone + Default,
+            H: Hasher + Default,
+        > Deserialize<'de> for HashMap<K, V, ALLOC, H>
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_map(HashMapVisitor(PhantomData))
+        }
+    }
+
+    impl<T: Clone + Hash + Eq + Serialize, ALLOC: Allocator + Clone, H: Hasher + Default> Serialize
+        for HashSet<T, ALLOC, H>
+    {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(self.iter())
+        }
+    }
+
+    struct HashSetVisitor<T, ALLOC, H>(PhantomData<(T, ALLOC, H)>);
+
+    impl<
+            'de,
+            T: Clone + Hash + Eq + Deserialize<'de>,
+            ALLOC: Allocator + Clone + Default,
+            H: Hasher + Default,
+        > Visitor<'de> for HashSetVisitor<T, ALLOC, H>
+    {
+        type Value = HashSet<T, ALLOC, H>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a sequence")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+            let set = deserialize_builder(access.size_hint())
+                .hash_set()
+                .map_err(A::Error::custom)?;
+            while let Some(item) = access.next_element()? {
+                set.insert(&item);
+            }
+            Ok(set)
+        }
+    }
+
+    impl<
+            'de,
+            T: Clone + Hash + Eq + Deserialize<'de>,
+            ALLOC: Allocator + Clone + Default,
+            H: Hasher + Default,
+        > Deserialize<'de> for HashSet<T, ALLOC, H>
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_seq(HashSetVisitor(PhantomData))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::map::*;
+    use alloc::sync::Arc;
+    use chashmap::CHashMap;
+    use rayon::prelude::*;
+    use std::collections::HashMap;
+    use std::thread;
+    use std::{
+        alloc::{AllocError, System},
+        sync::{Mutex, RwLock},
+    };
+    use test::Bencher;
+
+    #[test]
+    fn will_not_overflow() {
+        let _ = env_logger::try_init();
+        let table = WordMap::<System>::with_capacity(16);
+        for i in 50..60 {
+            assert_eq!(table.insert(&i, i), None);
+        }
+        for i in 50..60 {
+            assert_eq!(table.get(&i), Some(i));
+        }
+        for i in 50..60 {
+            assert_eq!(table.remove(&i), Some(i));
+        }
+    }
+
+    #[test]
+    fn resize() {
+        let _ = env_logger::try_init();
+        let map = WordMap::<System>::with_capacity(16);
+        for i in 5..2048 {
+            map.insert(&i, i * 2);
+        }
+        for i in 5..2048 {
+            match map.get(&i) {
+                Some(r) => assert_eq!(r, i * 2),
+                None => panic!("{}", i),
+            }
+        }
+    }
+
+    #[test]
+    fn shrink() {
+        let _ = env_logger::try_init();
+        let map = WordMap::<System>::with_capacity(16);
+        for i in 5..4096 {
+            map.insert(&i, i * 2);
+        }
+        let grown_cap = map.table.capacity();
+        for i in 5..4000 {
+            assert_eq!(map.remove(&i), Some(i * 2));
+        }
+        assert!(map.table.capacity() < grown_cap);
+        for i in 4000..4096 {
+            assert_eq!(map.get(&i), Some(i * 2));
+        }
+        for i in 5..4000 {
+            assert_eq!(map.get(&i), None);
+        }
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let _ = env_logger::try_init();
+        let map = super::HashMap::<u32, Obj>::with_capacity(4096);
+        for i in 5..100u32 {
+            map.insert(&i, Obj::new(i as usize));
+        }
+        for i in 5..50u32 {
+            map.remove(&i);
+        }
+        map.shrink_to_fit();
+        assert_eq!(map.table.capacity(), 128);
+        for i in 50..100u32 {
+            map.get(&i).unwrap().validate(i as usize);
+        }
+        assert_eq!(map.len(), 50);
+    }
+
+    struct DenseDoublingPolicy;
+
+    impl ResizePolicy for DenseDoublingPolicy {
+        fn max_load_factor(&self) -> f64 {
+            0.9
+        }
+
+        fn grow_capacity(&self, cap: usize, _epoch: usize, _elapsed: u64) -> usize {
+            cap << 1
+        }
+
+        fn now(&self) -> u64 {
+            0
+        }
+    }
+
+    #[test]
+    fn resize_policy() {
+        let _ = env_logger::try_init();
+        let map = MapBuilder::new()
+            .capacity(16)
+            .resize_policy(Arc::new(DenseDoublingPolicy))
+            .word_map()
+            .unwrap();
+        // Occupation limit of 16 slots under 0.9 load factor is 14, it is checked before insertion
+        for i in 5..20 {
+            map.insert(&i, i);
+        }
+        assert_eq!(map.table.capacity(), 16);
+        map.insert(&20, 20);
+        assert_eq!(map.table.capacity(), 32);
+        for i in 21..2048 {
+            map.insert(&i, i);
+        }
+        assert_eq!(map.table.capacity(), 4096);
+        for i in 5..2048 {
+            assert_eq!(map.get(&i), Some(i));
+        }
+    }
+
+    struct UnevenGrowthPolicy;
+
+    impl ResizePolicy for UnevenGrowthPolicy {
+        fn grow_capacity(&self, cap: usize, _epoch: usize, _elapsed: u64) -> usize {
+            cap + 3
+        }
+    }
+
+    #[test]
+    fn uneven_grow_capacity() {
+        let _ = env_logger::try_init();
+        let map = MapBuilder::new()
+            .capacity(16)
+            .resize_policy(Arc::new(UnevenGrowthPolicy))
+            .word_map()
+            .unwrap();
+        for i in 5..4096 {
+            map.insert(&i, i);
+            assert!(map.table.capacity().is_power_of_two());
+        }
+        for i in 5..4096 {
+            assert_eq!(map.get(&i), Some(i));
+        }
+    }
+
+    #[derive(Clone)]
+    struct NoMemory;
+
+    unsafe impl Allocator for NoMemory {
+        fn allocate(&self, _layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            Err(AllocError)
+        }
+
+        unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {
+            unreachable!("nothing is allocated")
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct CountingAlloc(Arc<AtomicUsize>);
+
+    unsafe impl Allocator for CountingAlloc {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.0.fetch_add(layout.size(), Relaxed);
+            System.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.0.fetch_sub(layout.size(), Relaxed);
+            System.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn stateful_allocator() {
+        let _ = env_logger::try_init();
+        let alloc = CountingAlloc::default();
+        let map = MapBuilder::new()
+            .capacity(16)
+            .allocator(alloc.clone())
+            .object_map::<usize>()
+            .unwrap();
+        let initial = alloc.0.load(Relaxed);
+        assert!(initial > 0);
+        for i in 0..1000 {
+            map.insert(&i, i);
+        }
+        assert!(alloc.0.load(Relaxed) > initial);
+        let before_clone = alloc.0.load(Relaxed);
+        let cloned = map.clone();
+        assert!(alloc.0.load(Relaxed) > before_clone);
+        // The clone have its own chunk
+        cloned.insert(&1000, 1000);
+        cloned.remove(&0);
+        assert_eq!(map.get(&1000), None);
+        assert_eq!(map.get(&0), Some(0));
+        for i in 1..=1000 {
+            assert_eq!(cloned.get(&i), Some(i));
+        }
+        assert_eq!(cloned.len(), 1000);
+    }
+
+    struct InvalidPolicy;
+
+    impl ResizePolicy for InvalidPolicy {
+        fn max_load_factor(&self) -> f64 {
+            1.5
+        }
+    }
+
+    #[test]
+    fn map_builder() {
+        let _ = env_logger::try_init();
+        assert_eq!(MapBuilder::new().chunk_capacity(), Ok(DEFAULT_CAPACITY));
+        assert_eq!(MapBuilder::new().capacity(0).chunk_capacity(), Ok(2));
+        assert_eq!(MapBuilder::new().capacity(100).chunk_capacity(), Ok(128));
+        // 100 entries needs more than 128 slots under the 0.75 load factor
+        assert_eq!(
+            MapBuilder::new().expected_elements(100).chunk_capacity(),
+            Ok(256)
+        );
+        assert_eq!(
+            MapBuilder::new().capacity(!0).chunk_capacity(),
+            Err(BuildError::CapacityOverflow)
+        );
+        // The entry words fit, the chunk with its header and attachments does not
+        assert!(MapBuilder::new().capacity(1 << 59).chunk_capacity().is_ok());
+        assert_eq!(
+            MapBuilder::new().capacity(1 << 59).word_map().err(),
+            Some(BuildError::CapacityOverflow)
+        );
+        assert_eq!(
+            MapBuilder::new()
+                .capacity(1 << 56)
+                .inline_map::<u64, [u64; 8]>()
+                .err(),
+            Some(BuildError::CapacityOverflow)
+        );
+        assert_eq!(
+            MapBuilder::new().allocator(NoMemory).word_map().err(),
+            Some(BuildError::AllocFailed)
+        );
+        assert_eq!(
+            MapBuilder::new()
+                .resize_policy(Arc::new(InvalidPolicy))
+                .word_map()
+                .err(),
+            Some(BuildError::InvalidLoadFactor(1.5))
+        );
+        let map = MapBuilder::new()
+            .capacity(100)
+            .hasher::<PassthroughHasher>()
+            .allocator(System)
+            .hash_map::<usize, usize>()
+            .unwrap();
+        assert_eq!(map.table.capacity(), 128);
+        map.insert(&1, 2);
+        assert_eq!(map.get(&1), Some(2));
+        let set = MapBuilder::new()
+            .expected_elements(1000)
+            .hash_set::<usize>()
+            .unwrap();
+        for i in 0..1000 {
+            set.insert(&i);
+        }
+        assert_eq!(set.table.capacity(), 2048);
+        let linked = MapBuilder::new()
+            .capacity(3)
+            .linked_object_map::<usize>()
+            .unwrap();
+        linked.insert_back(&1, 1);
+        assert_eq!(linked.all_keys(), vec![1]);
+        let obj_map = MapBuilder::new().object_map::<usize>().unwrap();
+        obj_map.insert(&1, 2);
+        assert_eq!(obj_map.get(&1), Some(2));
+        let owned_map = MapBuilder::new()
+            .owned_map::<usize, Mutex<usize>>()
+            .unwrap();
+        owned_map.insert(&1, Mutex::new(2));
+        assert_eq!(owned_map.get_with(&1, |v| *v.lock().unwrap()), Some(2));
+    }
+
+    struct IncrementalPolicy;
+
+    impl ResizePolicy for IncrementalPolicy {
+        fn migration_batch(&self) -> Option<usize> {
+            Some(8)
+        }
+    }
+
+    #[test]
+    fn incremental_migration() {
+        let _ = env_logger::try_init();
+        let map = MapBuilder::new()
+            .capacity(64)
+            .resize_policy(Arc::new(IncrementalPolicy))
+            .word_map()
+            .unwrap();
+        let mut key = 5;
+        while !map.table.map_is_copying() {
+            map.insert(&key, key);
+            key += 1;
+        }
+        // Only a few batches have been migrated by the insertion triggered the resize
+        assert_eq!(map.table.capacity(), 64);
+        for k in 5..key {
+            assert_eq!(map.get(&k), Some(k));
+        }
+        assert_eq!(map.remove(&5), Some(5));
+        while map.table.map_is_copying() {
+            map.insert(&key, key);
+            key += 1;
+        }
+        assert!(map.table.capacity() > 64);
+        assert_eq!(map.get(&5), None);
+        for k in 6..key {
+            assert_eq!(map.get(&k), Some(k));
+        }
+        assert_eq!(map.len(), key - 6);
+    }
+
+    #[test]
+    fn incremental_migration_bound() {
+        let _ = env_logger::try_init();
+        let map = MapBuilder::new()
+            .capacity(64)
+            .resize_policy(Arc::new(IncrementalPolicy))
+            .word_map()
+            .unwrap();
+        let progress = |map: &WordMap<System>| {
+            let guard = crossbeam_epoch::pin();
+            let chunk_ptr = map.table.chunk.load(Acquire, &guard);
+            let migrated = unsafe { chunk_ptr.deref() }.migrated.load(Relaxed);
+            (chunk_ptr.as_raw() as usize, migrated)
+        };
+        // No operation copies more than its batch, before its own resize and after
+        let bounded = |op: &dyn Fn()| {
+            let (chunk, migrated) = progress(&map);
+            op();
+            let (new_chunk, new_migrated) = progress(&map);
+            if chunk == new_chunk {
+                assert!(new_migrated - migrated <= 2 * 8);
+            }
+        };
+        for k in 5..20000 {
+            bounded(&|| {
+                map.insert(&k, k);
+            });
+        }
+        let grown = map.table.capacity();
+        // Shrink while new keys keep coming
+        for k in 5..20000 {
+            bounded(&|| {
+                map.remove(&k);
+            });
+            if k % 16 == 0 {
+                bounded(&|| {
+                    map.insert(&(k + 100000), k);
+                });
+            }
+        }
+        assert!(map.table.capacity() < grown);
+        assert_eq!(map.len(), (5..20000).filter(|k| k % 16 == 0).count());
+        for k in (5..20000).filter(|k| k % 16 == 0) {
+            assert_eq!(map.get(&(k + 100000)), Some(k));
+        }
+    }
+
+    #[test]
+    fn cooperative_migration() {
+        let _ = env_logger::try_init();
+        let map = Arc::new(WordMap::<System>::with_capacity(16));
+        let num_threads = 8;
+        let num_data = 20000;
+        let mut threads = vec![];
+        for i in 0..num_threads {
+            let map = map.clone();
+            threads.push(thread::spawn(move || {
+                // Chunks larger than one migration range are shared by all the writers
+                for j in 5..num_data {
+                    let key = i * num_data + j;
+                    map.insert(&key, key);
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(!map.table.map_is_copying());
+        assert!(map.table.capacity() > MIGRATION_RANGE);
+        assert_eq!(map.len(), num_threads * (num_data - 5));
+        for i in 0..num_threads {
+            for j in 5..num_data {
+                let key = i * num_data + j;
+                assert_eq!(map.get(&key), Some(key));
+            }
+        }
+    }
+
+    fn collect_garbage(tracker: &Arc<()>, expected: usize) {
+        // Drops are deferred through the epoch, keep pinning until they are collected
+        for _ in 0..1024 {
+            if Arc::strong_count(tracker) == expected {
+                return;
+            }
+            crossbeam_epoch::pin().flush();
+        }
+        assert_eq!(Arc::strong_count(tracker), expected);
+    }
+
+    #[test]
+    fn drop_attachments() {
+        let _ = env_logger::try_init();
+        let tracker = Arc::new(());
+        {
+            let map = ObjectMap::<Arc<()>>::with_capacity(16);
+            for i in 5..1000 {
+                map.insert(&i, tracker.clone());
+            }
+            // Resized several times, every pair was moved rather than copied
+            assert!(map.table.capacity() > 16);
+            collect_garbage(&tracker, 996);
+            for i in 5..500 {
+                assert!(map.remove(&i).is_some());
+            }
+            collect_garbage(&tracker, 501);
+            for i in 500..1000 {
+                map.insert(&i, tracker.clone());
+            }
+            collect_garbage(&tracker, 501);
+            let cloned = map.clone();
+            collect_garbage(&tracker, 1001);
+            drop(cloned);
+            collect_garbage(&tracker, 501);
+        }
+        collect_garbage(&tracker, 1);
+        {
+            let map = crate::map::HashMap::<String, Arc<()>>::with_capacity(16);
+            for i in 0..1000 {
+                map.insert(&i.to_string(), tracker.clone());
+            }
+            for i in 0..500 {
+                assert!(map.remove(&i.to_string()).is_some());
+                map.insert(&i.to_string(), tracker.clone());
+            }
+            for i in 0..1000 {
+                assert!(map.get(&i.to_string()).is_some());
+            }
+            collect_garbage(&tracker, 1001);
+            for i in 0..1000 {
+                assert!(map.remove(&i.to_string()).is_some());
+            }
+            collect_garbage(&tracker, 1);
+            assert!(map.get(&"0".to_string()).is_none());
+        }
+        collect_garbage(&tracker, 1);
+    }
+
+    #[test]
+    fn no_torn_reads() {
+        let _ = env_logger::try_init();
+        let hash_map = Arc::new(crate::map::HashMap::<usize, [usize; 8]>::with_capacity(16));
+        let obj_map = Arc::new(ObjectMap::<[usize; 8]>::with_capacity(16));
+        let num_keys = 64;
+        let mut threads = vec![];
+        for i in 0..4 {
+            let hash_map = hash_map.clone();
+            let obj_map = obj_map.clone();
+            threads.push(thread::spawn(move || {
+                for n in 0..20000 {
+                    let key = n % num_keys + 5;
+                    if i % 2 == 0 {
+                        // Writers fill every word with the same number
+                        hash_map.insert(&key, [n; 8]);
+                        obj_map.insert(&key, [n; 8]);
+                    } else {
+                        for value in hash_map.get(&key).into_iter().chain(obj_map.get(&key)) {
+                            assert!(value.iter().all(|w| *w == value[0]), "{:?}", value);
+                        }
+                    }
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn borrowed_reads() {
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<String, Vec<usize>>::with_capacity(16);
+        let obj_map = ObjectMap::<Vec<usize>>::with_capacity(16);
+        for i in 0..100 {
+            map.insert(&i.to_string(), vec![i; 64]);
+            obj_map.insert(&i, vec![i; 64]);
+        }
+        for i in 0..100 {
+            assert_eq!(
+                map.get_with(&i.to_string(), |v| v.iter().sum()),
+                Some(i * 64)
+            );
+            assert_eq!(obj_map.get_with(&i, |v| v.len()), Some(64));
+        }
+        assert_eq!(map.get_with(&"100".to_string(), |v| v.len()), None);
+        let guard = pin();
+        let borrowed = map.get_ref(&"1".to_string(), &guard).unwrap();
+        let obj_borrowed = obj_map.get_ref(&1, &guard).unwrap();
+        // Replaced, removed and migrated away, yet still readable under the guard
+        map.insert(&"1".to_string(), vec![]);
+        obj_map.remove(&1);
+        for i in 100..1000 {
+            map.insert(&i.to_string(), vec![i]);
+            obj_map.insert(&i, vec![i]);
+        }
+        assert_eq!(borrowed, &vec![1; 64]);
+        assert_eq!(obj_borrowed, &vec![1; 64]);
+        assert_eq!(map.get_ref(&"1".to_string(), &guard), Some(&vec![]));
+        assert_eq!(obj_map.get_ref(&1, &guard), None);
+    }
+
+    #[test]
+    fn iterators() {
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<String, usize>::with_capacity(16);
+        let obj_map = ObjectMap::<String>::with_capacity(16);
+        let word_map = WordMap::<System>::with_capacity(16);
+        let set = HashSet::<String>::with_capacity(16);
+        for i in 0..1000usize {
+            map.insert(&i.to_string(), i);
+            obj_map.insert(&i, i.to_string());
+            word_map.insert(&i, i * 2);
+            set.insert(&i.to_string());
+        }
+        for i in 0..500usize {
+            map.remove(&i.to_string());
+            obj_map.remove(&i);
+            word_map.remove(&i);
+            set.remove(&i.to_string());
+        }
+        let expected = (500..1000usize).collect::<std::collections::HashSet<_>>();
+        let mut pairs = map.iter().collect::<Vec<_>>();
+        pairs.sort_by_key(|(_, v)| *v);
+        assert_eq!(
+            pairs,
+            (500..1000).map(|i| (i.to_string(), i)).collect::<Vec<_>>()
+        );
+        let keys = map.keys().map(|k| k.parse().unwrap()).collect();
+        assert_eq!(expected, keys);
+        assert_eq!(expected, map.values().collect());
+        assert!(obj_map.iter().all(|(k, v)| k.to_string() == v));
+        assert_eq!(expected, obj_map.keys().collect());
+        assert_eq!(obj_map.values().count(), 500);
+        assert!(word_map.iter().all(|(k, v)| k * 2 == v));
+        assert_eq!(expected, word_map.keys().collect());
+        assert_eq!(
+            word_map.values().sum::<usize>(),
+            expected.iter().sum::<usize>() * 2
+        );
+        let items = set.iter().map(|k| k.parse().unwrap()).collect();
+        assert_eq!(expected, items);
+    }
+
+    #[test]
+    fn scan() {
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<usize, usize>::with_capacity(16);
+        for i in 0..1000 {
+            map.insert(&i, i);
+        }
+        let mut cursor = 0;
+        let mut scanned = vec![];
+        loop {
+            let (next, entries) = map.scan(cursor, 16);
+            assert!(entries.len() < 16 * 2);
+            scanned.extend(entries);
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        scanned.sort();
+        assert_eq!(scanned, (0..1000).map(|i| (i, i)).collect::<Vec<_>>());
+        // A limit past any table takes every entry in one call
+        let (cursor, mut entries) = map.scan(0, usize::MAX);
+        assert_eq!(cursor, 0);
+        entries.sort();
+        assert_eq!(entries, scanned);
+    }
+
+    #[test]
+    fn scan_across_resizes() {
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<usize, usize>::with_capacity(16);
+        let word_map = WordMap::<System>::with_capacity(16);
+        let num_stable = 512;
+        for i in 0..num_stable {
+            map.insert(&i, i);
+            word_map.insert(&i, i);
+        }
+        let mut seen = vec![false; num_stable];
+        let mut word_seen = vec![false; num_stable];
+        let (mut cursor, mut word_cursor) = (0, 0);
+        let mut round = 0;
+        let mut capacities = std::collections::HashSet::new();
+        loop {
+            let (next, entries) = map.scan(cursor, 64);
+            entries
+                .iter()
+                .filter(|e| e.0 < num_stable)
+                .for_each(|e| seen[e.0] = true);
+            cursor = next;
+            let (next, entries) = word_map.scan(word_cursor, 64);
+            entries
+                .iter()
+                .filter(|e| e.0 < num_stable)
+                .for_each(|e| word_seen[e.0] = true);
+            word_cursor = next;
+            if cursor == 0 && word_cursor == 0 {
+                break;
+            }
+            // Swap the chunks between calls, growing and shrinking in turns
+            let extra = num_stable..num_stable + 2048;
+            if round % 2 == 0 {
+                extra.for_each(|i| {
+                    map.insert(&i, i);
+                    word_map.insert(&i, i);
+                });
+            } else {
+                extra.for_each(|i| {
+                    map.remove(&i);
+                    word_map.remove(&i);
+                });
+                map.shrink_to_fit();
+                word_map.shrink_to_fit();
+            }
+            capacities.insert(map.table.capacity());
+            round += 1;
+        }
+        assert!(capacities.len() > 1);
+        assert!(seen.iter().all(|s| *s));
+        assert!(word_seen.iter().all(|s| *s));
+    }
+
+    #[test]
+    fn retain_clear_drain() {
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<String, usize>::with_capacity(16);
+        let obj_map = ObjectMap::<usize>::with_capacity(16);
+        let word_map = WordMap::<System>::with_capacity(16);
+        let set = HashSet::<usize>::with_capacity(16);
+        for i in 0..1000usize {
+            map.insert(&i.to_string(), i);
+            obj_map.insert(&i, i);
+            word_map.insert(&i, i);
+            set.insert(&i);
+        }
+        map.retain(|_, v| v % 2 == 0);
+        obj_map.retain(|k, _| k % 2 == 0);
+        word_map.retain(|_, v| v % 2 == 0);
+        set.retain(|i| i % 2 == 0);
+        assert_eq!(map.len(), 500);
+        assert_eq!(obj_map.len(), 500);
+        assert_eq!(word_map.len(), 500);
+        assert_eq!(set.len(), 500);
+        for i in 0..1000usize {
+            let expected = if i % 2 == 0 { Some(i) } else { None };
+            assert_eq!(map.get(&i.to_string()), expected);
+            assert_eq!(obj_map.get(&i), expected);
+            assert_eq!(word_map.get(&i), expected);
+            assert_eq!(set.contains(&i), expected.is_some());
+        }
+        let mut drained = map.drain().map(|(_, v)| v).collect::<Vec<_>>();
+        drained.sort();
+        assert_eq!(drained, (0..1000).step_by(2).collect::<Vec<_>>());
+        assert_eq!(obj_map.drain().count(), 500);
+        assert_eq!(word_map.drain().map(|(k, v)| k + v).sum::<usize>(), 499000);
+        assert_eq!(set.drain().count(), 500);
+        assert_eq!(map.len(), 0);
+        assert_eq!(obj_map.len(), 0);
+        assert_eq!(word_map.len(), 0);
+        assert_eq!(set.len(), 0);
+        for i in 0..1000usize {
+            map.insert(&i.to_string(), i);
+            word_map.insert(&i, i);
+        }
+        map.clear();
+        word_map.clear();
+        assert_eq!(map.len(), 0);
+        assert_eq!(word_map.len(), 0);
+        assert_eq!(map.iter().count(), 0);
+        assert_eq!(word_map.get(&1), None);
+        map.insert(&"1".to_string(), 1);
+        assert_eq!(map.get(&"1".to_string()), Some(1));
+
+        let tracker = Arc::new(());
+        let map = crate::map::HashMap::<usize, Arc<()>>::with_capacity(16);
+        for i in 0..1000 {
+            map.insert(&i, tracker.clone());
+        }
+        map.retain(|k, _| *k < 100);
+        collect_garbage(&tracker, 101);
+        map.clear();
+        collect_garbage(&tracker, 1);
+    }
+
+    #[test]
+    fn retain_unwind() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<usize, usize>::with_capacity(16);
+        for i in 0..100 {
+            map.insert(&i, i);
+        }
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            map.retain(|k, _| if *k == 50 { panic!("keep") } else { true })
+        }));
+        assert!(res.is_err());
+        // The entry the panic left is not held primed
+        assert_eq!(map.insert(&50, 0), Some(50));
+        assert_eq!(map.remove(&50), Some(0));
+        assert_eq!(map.len(), 99);
+    }
+
+    #[test]
+    fn compute_unwind() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<usize, usize>::with_capacity(16);
+        map.insert(&1, 1);
+        let res = catch_unwind(AssertUnwindSafe(|| map.update(&1, |_| panic!("update"))));
+        assert!(res.is_err());
+        assert_eq!(map.get(&1), Some(1));
+        assert_eq!(map.update(&1, |v| v + 1), Some(2));
+        assert_eq!(map.insert(&1, 3), Some(2));
+    }
+
+    #[test]
+    fn init_unwind() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<usize, usize>::with_capacity(16);
+        // An empty slot, a tombstone and a live pair
+        map.insert(&2, 2);
+        map.insert(&3, 3);
+        map.remove(&2);
+        for key in 1..4 {
+            let res = catch_unwind(AssertUnwindSafe(|| {
+                map.entry(&key)
+                    .and_modify(|_| panic!("modify"))
+                    .or_insert_with(|| panic!("init"))
+            }));
+            assert!(res.is_err());
+        }
+        assert_eq!(map.get(&1), None);
+        assert_eq!(map.get(&2), None);
+        assert_eq!(map.get(&3), Some(3));
+        for key in 1..4 {
+            assert_eq!(map.entry(&key).or_insert(key * 10), key * 10);
+        }
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn inline_map() {
+        let _ = env_logger::try_init();
+        let map = InlineMap::<u32, [u64; 3]>::with_capacity(16);
+        for i in 0..4096u32 {
+            assert_eq!(map.insert(&i, [i as u64; 3]), None);
+        }
+        for i in 0..2048u32 {
+            assert_eq!(map.insert(&i, [0; 3]), Some([i as u64; 3]));
+            assert_eq!(map.remove(&(i + 2048)), Some([(i + 2048) as u64; 3]));
+        }
+        assert_eq!(map.iter().count(), 2048);
+        assert!(map.iter().all(|(_, v)| v == [0; 3]));
+        assert_eq!(map.update(&1, |v| [v[0] + 1; 3]), Some([1; 3]));
+        map.retain(|k, _| k % 2 == 1);
+        assert_eq!(map.drain().count(), 1024);
+        assert_eq!(map.len(), 0);
+
+        // Values replaced in place are never seen half written
+        let map = Arc::new(InlineMap::<u64, [u64; 4]>::with_capacity(16));
+        let num_keys = 64;
+        for k in 0..num_keys {
+            map.insert(&k, [0; 4]);
+        }
+        let writers = (0..2)
+            .map(|_| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for i in 0..20000u64 {
+                        map.insert(&(i % num_keys), [i; 4]);
+                        // Keys coming and going drive resizes
+                        map.insert(&(num_keys + i), [i; 4]);
+                        map.remove(&(num_keys + i));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let readers = (0..2)
+            .map(|_| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for i in 0..20000u64 {
+                        let v = map.get(&(i % num_keys)).unwrap();
+                        assert!(v.iter().all(|x| *x == v[0]), "torn value {:?}", v);
+                        if i % 1000 == 0 {
+                            for v in map.table.iter(|_, _, _, v| *v) {
+                                assert!(v.iter().all(|x| *x == v[0]), "torn value {:?}", v);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in writers.into_iter().chain(readers) {
+            t.join().unwrap();
+        }
+        assert_eq!(map.iter().count(), num_keys as usize);
     }
 
-    pub fn insert(&self, item: &T) -> bool {
-        let hash = hash_key::<T, H>(item);
-        self.table
-            .insert(InsertOp::TryInsert, item, None, hash, !0)
-            .is_none()
+    // Copy the chunk of a table to memory of its own and wipe the entries of the original, the
+    // copy shall find nothing through addresses of the original
+    fn relocate<K, V, A: Attachment<K, V, System>, H: Hasher + Default>(
+        table: &Table<K, V, A, System, H>,
+    ) -> Table<K, V, A, System, H> {
+        let guard = crossbeam_epoch::pin();
+        let chunk = unsafe { table.chunk.load(Acquire, &guard).deref() };
+        let size = chunk.total_size;
+        let copy = alloc_mem(&System, size);
+        unsafe {
+            libc::memcpy(copy as *mut c_void, chunk.ptr as *const c_void, size);
+            libc::memset(
+                chunk.data_addr() as *mut c_void,
+                0,
+                chunk.ptr as usize + size - chunk.data_addr(),
+            );
+        }
+        Table {
+            chunk: Atomic::new(ChunkPtr::new(copy as *mut Chunk<K, V, A, System>)),
+            new_chunk: Atomic::null(),
+            count: AtomicUsize::new(table.count.load(Relaxed)),
+            epoch: AtomicUsize::new(0),
+            timestamp: AtomicU64::new(table.policy.now()),
+            init_cap: table.init_cap,
+            policy: table.policy.clone(),
+            alloc: System,
+            fixed: false,
+            mark: PhantomData,
+        }
     }
 
-    pub fn remove(&self, item: &T) -> bool {
-        let hash = hash_key::<T, H>(item);
-        self.table.remove(item, hash).is_some()
+    #[test]
+    fn relocate_chunks() {
+        let _ = env_logger::try_init();
+        let words = WordMap::<System>::with_capacity(16);
+        let pairs = FullWordMap::<System>::with_capacity(16);
+        let inline = InlineMap::<u32, [u64; 3]>::with_capacity(16);
+        for i in 0..1024usize {
+            words.insert(&i, i * 2);
+            pairs.insert(&(usize::MAX - i), i);
+            inline.insert(&(i as u32), [i as u64; 3]);
+        }
+        let words = WordMap {
+            table: relocate(&words.table),
+        };
+        let pairs = FullWordMap::<System> {
+            table: relocate(&pairs.table),
+            shadow: PhantomData,
+        };
+        let inline = InlineMap::<u32, [u64; 3]> {
+            table: relocate(&inline.table),
+            shadow: PhantomData,
+        };
+        for i in 0..1024usize {
+            assert_eq!(words.get(&i), Some(i * 2));
+            assert_eq!(pairs.get(&(usize::MAX - i)), Some(i));
+            assert_eq!(inline.get(&(i as u32)), Some([i as u64; 3]));
+        }
+        // The copies keep going on their own, and migrate out of the relocated chunks
+        for i in 0..4096usize {
+            words.insert(&i, i);
+            pairs.insert(&(usize::MAX - i), i + 1);
+            inline.insert(&(i as u32), [i as u64 + 1; 3]);
+        }
+        for i in 0..4096usize {
+            assert_eq!(words.get(&i), Some(i));
+            assert_eq!(pairs.get(&(usize::MAX - i)), Some(i + 1));
+            assert_eq!(inline.get(&(i as u32)), Some([i as u64 + 1; 3]));
+        }
+        assert_eq!(pairs.iter().count(), 4096);
+        assert_eq!(inline.len(), 4096);
     }
 
-    pub fn items(&self) -> std::collections::HashSet<T> {
-        self.table
-            .entries()
+    #[test]
+    fn word_set() {
+        let _ = env_logger::try_init();
+        let set = Arc::new(WordSet::<System>::with_capacity(16));
+        assert!(set.insert(&0));
+        assert!(!set.insert(&0));
+        assert!(set.contains(&0));
+        assert!(set.remove(&0));
+        assert!(!set.remove(&0));
+        assert!(set.insert(&0));
+        assert!(set.insert(&WordSet::<System>::MAX_ITEM));
+        assert!(!set.contains(&usize::MAX));
+        assert_eq!(
+            set.checked_insert(&usize::MAX),
+            Err(RangeError::Key(usize::MAX))
+        );
+        set.clear();
+        // Threads insert overlapping items, each item is taken by one of them
+        let num_threads = 4;
+        let num_items = 10000;
+        let threads = (0..num_threads)
+            .map(|t| {
+                let set = set.clone();
+                thread::spawn(move || {
+                    (0..num_items)
+                        .map(|i| i * (t + 1) % num_items)
+                        .filter(|i| set.insert(i))
+                        .count()
+                })
+            })
+            .collect::<Vec<_>>();
+        let taken = threads
             .into_iter()
-            .map(|(_, _, item, _)| item)
-            .collect()
+            .map(|t| t.join().unwrap())
+            .sum::<usize>();
+        assert_eq!(taken, num_items);
+        assert_eq!(set.iter().count(), num_items);
+        assert!((0..num_items).all(|i| set.contains(&i)));
+        set.retain(|i| i % 2 == 0);
+        assert_eq!(set.iter().filter(|i| i % 2 == 1).count(), 0);
+        assert_eq!(set.drain().count(), num_items / 2);
+        assert_eq!(set.len(), 0);
     }
 
-    #[inline(always)]
-    pub fn len(&self) -> usize {
-        self.table.len()
+    #[test]
+    fn parallel_retain_clear() {
+        let _ = env_logger::try_init();
+        let map = Arc::new(crate::map::HashMap::<usize, usize>::with_capacity(16));
+        let writers = (0..2)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for i in 0..20000 {
+                        let key = t * 100000 + i % 4096;
+                        map.insert(&key, key);
+                        if i % 3 == 0 {
+                            map.remove(&(key - key % 8));
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for i in 0..50 {
+            if i % 5 == 0 {
+                map.clear();
+            } else {
+                map.retain(|k, _| k % 2 == 0);
+            }
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert!(map.iter().all(|(k, v)| k == v));
+        assert_eq!(map.len(), map.iter().count());
+        map.clear();
+        assert_eq!(map.len(), 0);
     }
-}
 
-#[inline(always)]
-fn alloc_mem<A: GlobalAlloc + Default>(size: usize) -> usize {
-    let align = 64;
-    let layout = Layout::from_size_align(size, align).unwrap();
-    let alloc = A::default();
-    // must be all zeroed
-    unsafe {
-        let addr = alloc.alloc(layout) as usize;
-        ptr::write_bytes(addr as *mut u8, 0, size);
-        debug_assert_eq!(addr % 64, 0);
-        addr
+    #[test]
+    fn update_compute_merge() {
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<String, usize>::with_capacity(16);
+        let obj_map = ObjectMap::<usize>::with_capacity(16);
+        for i in 0..1000usize {
+            map.insert(&i.to_string(), i);
+            obj_map.insert(&i, i);
+        }
+        for i in 0..2000usize {
+            let expected = if i < 1000 { Some(i + 1) } else { None };
+            assert_eq!(map.update(&i.to_string(), |v| v + 1), expected);
+            assert_eq!(obj_map.update(&i, |v| v + 1), expected);
+        }
+        assert_eq!(map.len(), 1000);
+        assert_eq!(obj_map.len(), 1000);
+        for i in 0..2000usize {
+            let computed = |v: Option<&usize>| match v {
+                Some(v) if v % 2 == 0 => None,
+                Some(v) => Some(v * 2),
+                None => Some(i),
+            };
+            map.compute(&i.to_string(), computed);
+            obj_map.compute(&i, computed);
+        }
+        for i in 0..2000usize {
+            let expected = if i >= 1000 {
+                Some(i)
+            } else if i % 2 == 0 {
+                Some((i + 1) * 2)
+            } else {
+                None
+            };
+            assert_eq!(map.get(&i.to_string()), expected);
+            assert_eq!(obj_map.get(&i), expected);
+        }
+        assert_eq!(map.len(), 1500);
+        assert_eq!(obj_map.len(), 1500);
+        assert_eq!(map.compute(&"none".to_string(), |_| None), None);
+        assert_eq!(map.len(), 1500);
+        assert_eq!(map.merge(&"1".to_string(), 10, |a, b| a + b), 10);
+        assert_eq!(map.merge(&"1".to_string(), 10, |a, b| a + b), 20);
+        assert_eq!(obj_map.merge(&0, 10, |a, b| a * b), 20);
+
+        let tracker = Arc::new(());
+        let map = crate::map::HashMap::<usize, Arc<()>>::with_capacity(16);
+        for i in 0..100 {
+            map.insert(&i, tracker.clone());
+            map.update(&i, |v| v.clone());
+        }
+        for i in 0..50 {
+            map.compute(&i, |_| None);
+        }
+        collect_garbage(&tracker, 51);
     }
-}
-
-#[inline(always)]
-fn dealloc_mem<A: GlobalAlloc + Default + Default>(ptr: usize, size: usize) {
-    let align = 64;
-    let layout = Layout::from_size_align(size, align).unwrap();
-    let alloc = A::default();
-    unsafe { alloc.dealloc(ptr as *mut u8, layout) }
-}
 
-pub struct PassthroughHasher {
-    num: u64,
-}
-
-impl Hasher for PassthroughHasher {
-    fn finish(&self) -> u64 {
-        self.num
+    #[test]
+    fn parallel_update() {
+        let _ = env_logger::try_init();
+        let map = Arc::new(crate::map::HashMap::<usize, usize>::with_capacity(16));
+        let obj_map = Arc::new(ObjectMap::<usize>::with_capacity(16));
+        let num_threads = 4;
+        let num_keys = 512;
+        let rounds = 20;
+        let threads = (0..num_threads)
+            .map(|t| {
+                let map = map.clone();
+                let obj_map = obj_map.clone();
+                thread::spawn(move || {
+                    for r in 0..rounds {
+                        for k in 0..num_keys {
+                            map.merge(&k, 1, |a, b| a + b);
+                            obj_map.compute(&k, |v| Some(v.map(|v| v + 1).unwrap_or(1)));
+                        }
+                        // Keys of this thread come and go to drive resizes
+                        for k in 0..num_keys * 4 {
+                            let key = (t + 1) * 100000 + r * 10000 + k;
+                            map.insert(&key, 0);
+                            obj_map.insert(&key, 0);
+                        }
+                        for k in 0..num_keys * 4 {
+                            let key = (t + 1) * 100000 + r * 10000 + k;
+                            map.remove(&key);
+                            obj_map.remove(&key);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        for k in 0..num_keys {
+            assert_eq!(map.get(&k), Some(num_threads * rounds));
+            assert_eq!(obj_map.get(&k), Some(num_threads * rounds));
+        }
+        assert_eq!(map.iter().count(), num_keys);
+        assert_eq!(obj_map.iter().count(), num_keys);
     }
 
-    fn write(&mut self, _bytes: &[u8]) {
-        unimplemented!()
+    #[test]
+    fn entry_api() {
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<String, usize>::with_capacity(16);
+        let obj_map = ObjectMap::<usize>::with_capacity(16);
+        assert_eq!(map.entry(&"a".to_string()).or_insert(1), 1);
+        assert_eq!(map.entry(&"a".to_string()).or_insert(2), 1);
+        assert_eq!(
+            map.entry(&"a".to_string())
+                .and_modify(|v| *v += 10)
+                .or_insert(2),
+            11
+        );
+        assert_eq!(
+            map.entry(&"b".to_string())
+                .and_modify(|_| unreachable!())
+                .or_insert_with(|| 3),
+            3
+        );
+        assert_eq!(map.entry(&"c".to_string()).or_default(), 0);
+        assert_eq!(map.get(&"a".to_string()), Some(11));
+        assert_eq!(map.len(), 3);
+        assert_eq!(obj_map.entry(&1).or_insert_with(|| 5), 5);
+        assert_eq!(obj_map.entry(&1).or_insert_with(|| unreachable!()), 5);
+        let doubled = obj_map
+            .entry(&1)
+            .and_modify(|v| *v *= 2)
+            .and_modify(|v| *v += 1)
+            .or_default();
+        assert_eq!(doubled, 11);
+        obj_map.remove(&1);
+        assert_eq!(obj_map.get_or_insert(&1, || 7), 7);
+        assert_eq!(obj_map.len(), 1);
+
+        // Every key is initialized once while threads race on it and the table resizes
+        let map = Arc::new(crate::map::HashMap::<usize, usize>::with_capacity(16));
+        let inits = Arc::new(AtomicUsize::new(0));
+        let num_threads = 8;
+        let num_keys = 2048;
+        let threads = (0..num_threads)
+            .map(|t| {
+                let map = map.clone();
+                let inits = inits.clone();
+                thread::spawn(move || {
+                    for k in 0..num_keys {
+                        let value = map.entry(&k).or_insert_with(|| {
+                            inits.fetch_add(1, Relaxed);
+                            k * 3
+                        });
+                        assert_eq!(value, k * 3);
+                        let key = (t + 1) * 100000 + k;
+                        map.insert(&key, k);
+                        if k % 4 != 0 {
+                            map.remove(&key);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(inits.load(Relaxed), num_keys);
+        for k in 0..num_keys {
+            assert_eq!(map.get(&k), Some(k * 3));
+        }
     }
 
-    fn write_usize(&mut self, i: usize) {
-        self.num = i as u64
+    #[test]
+    fn batch_ops() {
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<String, usize>::with_capacity(16);
+        let obj_map = ObjectMap::<usize>::with_capacity(16);
+        let word_map = WordMap::<System>::with_capacity(16);
+        let entries = (0..1000usize).map(|i| (i, i * 2)).collect::<Vec<_>>();
+        let str_entries = entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), *v))
+            .collect::<Vec<_>>();
+        assert!(map.insert_many(&str_entries).iter().all(Option::is_none));
+        assert!(obj_map.insert_many(&entries).iter().all(Option::is_none));
+        assert!(word_map.insert_many(&entries).iter().all(Option::is_none));
+        let keys = (500..1500usize).collect::<Vec<_>>();
+        let str_keys = keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+        let expected = keys
+            .iter()
+            .map(|k| if *k < 1000 { Some(k * 2) } else { None })
+            .collect::<Vec<_>>();
+        assert_eq!(map.get_many(&str_keys), expected);
+        assert_eq!(obj_map.get_many(&keys), expected);
+        assert_eq!(word_map.get_many(&keys), expected);
+        assert_eq!(map.remove_many(&str_keys), expected);
+        assert_eq!(obj_map.remove_many(&keys), expected);
+        assert_eq!(word_map.remove_many(&keys), expected);
+        assert_eq!(map.len(), 500);
+        assert_eq!(obj_map.len(), 500);
+        assert_eq!(word_map.len(), 500);
+        assert_eq!(obj_map.insert_many(&[(1, 1)]), vec![Some(2)]);
+        assert!(map.get_many(&[]).is_empty());
+
+        // Batches of keys that stay, while other keys come and go to resize the table
+        let map = Arc::new(WordMap::<System>::with_capacity(16));
+        let num_threads = 4;
+        let threads = (0..num_threads)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    let keys = (0..4096).map(|k| k * num_threads + t).collect::<Vec<_>>();
+                    let entries = keys.iter().map(|k| (*k, k + 1)).collect::<Vec<_>>();
+                    for round in 0..10 {
+                        map.insert_many(&entries);
+                        let values = map.get_many(&keys);
+                        assert!(values.iter().zip(&keys).all(|(v, k)| *v == Some(k + 1)));
+                        let churn = (0..1024)
+                            .map(|k| (t + 1) * 100000 + round * 10000 + k)
+                            .collect::<Vec<_>>();
+                        let churn_entries = churn.iter().map(|k| (*k, 0)).collect::<Vec<_>>();
+                        map.insert_many(&churn_entries);
+                        assert!(map.remove_many(&churn).iter().all(|v| *v == Some(0)));
+                        let values = map.remove_many(&keys);
+                        assert!(values.iter().zip(&keys).all(|(v, k)| *v == Some(k + 1)));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(map.iter().count(), 0);
     }
-}
 
-impl Default for PassthroughHasher {
-    fn default() -> Self {
-        Self { num: 0 }
+    #[test]
+    fn probe_on_hash_match_only() {
+        static PROBES: AtomicUsize = AtomicUsize::new(0);
+        #[derive(Clone)]
+        struct Key(String);
+        impl Hash for Key {
+            fn hash<S: Hasher>(&self, state: &mut S) {
+                self.0.hash(state)
+            }
+        }
+        impl PartialEq for Key {
+            fn eq(&self, other: &Self) -> bool {
+                PROBES.fetch_add(1, Relaxed);
+                self.0 == other.0
+            }
+        }
+        impl Eq for Key {}
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<Key, usize>::with_capacity(16);
+        for i in 0..4096usize {
+            map.insert(&Key(i.to_string()), i);
+        }
+        PROBES.store(0, Relaxed);
+        for i in 4096..8192usize {
+            assert_eq!(map.get(&Key(i.to_string())), None);
+        }
+        assert_eq!(PROBES.load(Relaxed), 0);
+        for i in 0..4096usize {
+            assert_eq!(map.get(&Key(i.to_string())), Some(i));
+        }
+        assert_eq!(PROBES.load(Relaxed), 4096);
     }
-}
-
-fn timestamp() -> u64 {
-    let start = SystemTime::now();
-    let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap();
-    since_the_epoch.as_millis() as u64
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::map::*;
-    use alloc::sync::Arc;
-    use chashmap::CHashMap;
-    use rayon::prelude::*;
-    use std::collections::HashMap;
-    use std::thread;
-    use std::{
-        alloc::System,
-        sync::{Mutex, RwLock},
-    };
-    use test::Bencher;
 
     #[test]
-    fn will_not_overflow() {
+    fn word_map_fetch_ops() {
         let _ = env_logger::try_init();
-        let table = WordMap::<System>::with_capacity(16);
-        for i in 50..60 {
-            assert_eq!(table.insert(&i, i), None);
+        let map = WordMap::<System>::with_capacity(16);
+        assert_eq!(map.fetch_add(&1, 1), None);
+        assert_eq!(map.compare_exchange(&1, 0, 1), Err(None));
+        map.insert(&1, 10);
+        assert_eq!(map.fetch_add(&1, 5), Some(10));
+        assert_eq!(map.fetch_sub(&1, 3), Some(15));
+        assert_eq!(map.fetch_or(&1, 0b10000), Some(12));
+        assert_eq!(map.fetch_and(&1, 0b11000), Some(0b11100));
+        assert_eq!(map.get(&1), Some(0b11000));
+        assert_eq!(map.compare_exchange(&1, 0, 1), Err(Some(0b11000)));
+        assert_eq!(map.compare_exchange(&1, 0b11000, 1), Ok(0b11000));
+        assert_eq!(map.fetch_update(&1, |v| Some(v * 7)), Ok(1));
+        assert_eq!(map.fetch_update(&1, |_| None), Err(Some(7)));
+        assert_eq!(map.get(&1), Some(7));
+        assert_eq!(map.len(), 1);
+
+        let map = Arc::new(WordMap::<System>::with_capacity(16));
+        let num_keys = 256;
+        let num_threads = 4;
+        let rounds = 20;
+        for k in 0..num_keys {
+            map.insert(&k, 0);
         }
-        for i in 50..60 {
-            assert_eq!(table.get(&i), Some(i));
+        let threads = (0..num_threads)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for r in 0..rounds {
+                        for k in 0..num_keys {
+                            map.fetch_add(&k, 2);
+                            map.fetch_sub(&k, 1);
+                            let mut current = 0;
+                            while let Err(Some(actual)) =
+                                map.compare_exchange(&k, current, current + 1)
+                            {
+                                current = actual;
+                            }
+                        }
+                        // Keys of this thread come and go to drive resizes
+                        for k in 0..num_keys * 4 {
+                            map.insert(&((t + 1) * 100000 + r * 10000 + k), 0);
+                        }
+                        for k in 0..num_keys * 4 {
+                            map.remove(&((t + 1) * 100000 + r * 10000 + k));
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let locker = {
+            let map = map.clone();
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    let mut guard = map.lock(i % num_keys).unwrap();
+                    *guard += 0;
+                }
+            })
+        };
+        for thread in threads {
+            thread.join().unwrap();
         }
-        for i in 50..60 {
-            assert_eq!(table.remove(&i), Some(i));
+        locker.join().unwrap();
+        for k in 0..num_keys {
+            assert_eq!(map.get(&k), Some(num_threads * rounds * 2));
         }
     }
 
     #[test]
-    fn resize() {
+    fn word_range() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
         let _ = env_logger::try_init();
         let map = WordMap::<System>::with_capacity(16);
-        for i in 5..2048 {
-            map.insert(&i, i * 2);
+        let max_key = WordMap::<System>::MAX_KEY;
+        let max_value = WordMap::<System>::MAX_VALUE;
+        assert_eq!(map.checked_insert(&max_key, max_value), Ok(None));
+        assert_eq!(map.checked_insert(&max_key, 1), Ok(Some(max_value)));
+        assert_eq!(map.checked_try_insert(&max_key, 2), Ok(Some(1)));
+        assert_eq!(
+            map.checked_insert(&usize::MAX, 1),
+            Err(RangeError::Key(usize::MAX))
+        );
+        assert_eq!(
+            map.checked_insert(&1, max_value + 1),
+            Err(RangeError::Value(max_value + 1))
+        );
+        assert_eq!(map.get(&usize::MAX), None);
+        assert_eq!(map.remove(&usize::MAX), None);
+        assert!(map.lock(usize::MAX).is_none());
+        assert_eq!(map.get_many(&[max_key, usize::MAX]), vec![Some(1), None]);
+        assert_eq!(
+            map.fetch_update(&max_key, |_| Some(usize::MAX)),
+            Err(Some(1))
+        );
+        // `insert` keeps the words of earlier versions and skips the keys out of range
+        assert_eq!(map.insert(&usize::MAX, 1), None);
+        assert_eq!(map.insert_many(&[(usize::MAX, 1)]), vec![None]);
+        assert_eq!(map.insert(&1, (1 << 63) | 7), None);
+        assert_eq!(map.get(&1), Some(7));
+        assert_eq!(map.insert(&1, 8), Some(7 + NUM_FIX));
+        assert_eq!(map.insert_many(&[(1, 9)]), vec![Some(8 + NUM_FIX)]);
+        assert_eq!(map.remove(&1), Some(9));
+        assert!(catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = map.lock(max_key).unwrap();
+            *guard = usize::MAX;
+        }))
+        .is_err());
+        assert_eq!(map.get(&max_key), Some(1));
+        assert_eq!(map.get(&1), None);
+        assert_eq!(map.iter().collect::<Vec<_>>(), vec![(max_key, 1)]);
+
+        let obj_map = ObjectMap::<usize>::with_capacity(16);
+        let max_key = ObjectMap::<usize>::MAX_KEY;
+        assert_eq!(obj_map.insert(&max_key, 1), None);
+        assert_eq!(*obj_map.read(max_key).unwrap(), 1);
+        assert!(obj_map.read(usize::MAX).is_none());
+        assert_eq!(
+            obj_map.checked_insert(&usize::MAX, 1),
+            Err(RangeError::Key(usize::MAX))
+        );
+        assert_eq!(obj_map.get(&max_key), Some(1));
+
+        let map = FullWordMap::<System>::with_capacity(16);
+        let words = [
+            0,
+            1,
+            2,
+            5,
+            6,
+            usize::MAX - 1,
+            usize::MAX,
+            !0 << 2 >> 2,
+            1 << 63,
+        ];
+        for (i, key) in words.iter().enumerate() {
+            assert_eq!(map.insert(key, words[words.len() - 1 - i]), None);
         }
-        for i in 5..2048 {
-            match map.get(&i) {
-                Some(r) => assert_eq!(r, i * 2),
-                None => panic!("{}", i),
+        for (i, key) in words.iter().enumerate() {
+            assert_eq!(map.get(key), Some(words[words.len() - 1 - i]));
+        }
+        for i in 0..1000 {
+            map.insert(&(usize::MAX - i), i);
+        }
+        assert_eq!(map.get(&usize::MAX), Some(0));
+        assert_eq!(map.get(&0), Some(1 << 63));
+        assert_eq!(map.remove(&(1 << 63)), Some(0));
+        assert_eq!(map.get(&(1 << 63)), None);
+        assert_eq!(map.iter().count(), 1000 + words.len() - 3);
+        assert!(map.iter().all(|(k, v)| map.get(&k) == Some(v)));
+    }
+
+    #[test]
+    fn iterate_during_resize() {
+        let _ = env_logger::try_init();
+        let map = Arc::new(crate::map::HashMap::<usize, usize>::with_capacity(16));
+        let word_map = Arc::new(WordMap::<System>::with_capacity(16));
+        let num_stable = 512;
+        for i in 0..num_stable {
+            map.insert(&i, i);
+            word_map.insert(&i, i);
+        }
+        let writers = (0..2)
+            .map(|t| {
+                let map = map.clone();
+                let word_map = word_map.clone();
+                thread::spawn(move || {
+                    // Grow and shrink the chunks with keys outside of the stable range
+                    for round in 0..20 {
+                        let base = num_stable + (t * 20 + round) * 2048;
+                        for i in base..base + 2048 {
+                            map.insert(&i, i);
+                            word_map.insert(&i, i);
+                        }
+                        for i in base..base + 2048 {
+                            map.remove(&i);
+                            word_map.remove(&i);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..20 {
+            let mut seen = vec![false; num_stable];
+            for (k, v) in map.iter() {
+                assert_eq!(k, v);
+                if k < num_stable {
+                    seen[k] = true;
+                }
             }
+            assert!(seen.iter().all(|s| *s));
+            let mut seen = vec![false; num_stable];
+            for (k, v) in word_map.iter() {
+                assert_eq!(k, v);
+                if k < num_stable {
+                    seen[k] = true;
+                }
+            }
+            assert!(seen.iter().all(|s| *s));
+        }
+        for writer in writers {
+            writer.join().unwrap();
         }
     }
 
@@ -2976,6 +8037,50 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let word_map = WordMap::<System>::with_capacity(16);
+        for i in 5..1000 {
+            word_map.insert(&i, i * 2);
+        }
+        let json = serde_json::to_string(&word_map).unwrap();
+        let word_map = serde_json::from_str::<WordMap>(&json).unwrap();
+        assert_eq!(word_map.len(), 995);
+        for i in 5..1000 {
+            assert_eq!(word_map.get(&i), Some(i * 2));
+        }
+        let out_of_range = format!("{{\"1\":{}}}", WordMap::<System>::MAX_VALUE + 1);
+        assert!(serde_json::from_str::<WordMap>(&out_of_range).is_err());
+
+        let obj_map = ObjectMap::<String>::with_capacity(16);
+        for i in 0..100 {
+            obj_map.insert(&i, format!("{}", i));
+        }
+        let json = serde_json::to_string(&obj_map).unwrap();
+        let obj_map = serde_json::from_str::<ObjectMap<String>>(&json).unwrap();
+        assert_eq!(obj_map.len(), 100);
+        assert_eq!(obj_map.get(&42), Some("42".to_string()));
+
+        let hash_map = crate::map::HashMap::<String, Vec<usize>>::with_capacity(16);
+        for i in 0..100 {
+            hash_map.insert(&format!("{}", i), vec![i; 3]);
+        }
+        let json = serde_json::to_string(&hash_map).unwrap();
+        let hash_map =
+            serde_json::from_str::<crate::map::HashMap<String, Vec<usize>>>(&json).unwrap();
+        assert_eq!(hash_map.len(), 100);
+        assert_eq!(hash_map.get(&"42".to_string()), Some(vec![42; 3]));
+
+        let set = HashSet::<usize>::with_capacity(16);
+        for i in 0..100 {
+            set.insert(&i);
+        }
+        let json = serde_json::to_string(&set).unwrap();
+        let set = serde_json::from_str::<HashSet<usize>>(&json).unwrap();
+        assert_eq!(set.items(), (0..100).collect());
+    }
+
     #[bench]
     fn lfmap(b: &mut Bencher) {
         let _ = env_logger::try_init();
@@ -2987,6 +8092,122 @@ mod tests {
         });
     }
 
+    #[bench]
+    fn lfmap_get(b: &mut Bencher) {
+        let _ = env_logger::try_init();
+        let map = WordMap::<System, DefaultHasher>::with_capacity(8);
+        let keys = (0..1 << 20)
+            .map(|i| i * 7919 % (1 << 20))
+            .collect::<Vec<_>>();
+        keys.iter().for_each(|k| {
+            map.insert(k, *k);
+        });
+        b.iter(|| keys[..4096].iter().map(|k| map.get(k)).collect::<Vec<_>>());
+    }
+
+    #[bench]
+    fn lfmap_get_many(b: &mut Bencher) {
+        let _ = env_logger::try_init();
+        let map = WordMap::<System, DefaultHasher>::with_capacity(8);
+        let keys = (0..1 << 20)
+            .map(|i| i * 7919 % (1 << 20))
+            .collect::<Vec<_>>();
+        keys.iter().for_each(|k| {
+            map.insert(k, *k);
+        });
+        b.iter(|| map.get_many(&keys[..4096]));
+    }
+
+    #[bench]
+    fn lfmap_small_get(b: &mut Bencher) {
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<u64, u64>::with_capacity(8);
+        (0..1 << 21).for_each(|k| {
+            map.insert(&k, k);
+        });
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 7919) & ((1 << 21) - 1);
+            map.get(&i)
+        });
+    }
+
+    #[bench]
+    fn lfmap_inline_get(b: &mut Bencher) {
+        let _ = env_logger::try_init();
+        let map = InlineMap::<u64, u64>::with_capacity(8);
+        (0..1 << 21).for_each(|k| {
+            map.insert(&k, k);
+        });
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 7919) & ((1 << 21) - 1);
+            map.get(&i)
+        });
+    }
+
+    #[bench]
+    fn lfmap_str_get(b: &mut Bencher) {
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<String, usize>::with_capacity(8);
+        let keys = (0..1 << 16)
+            .map(|i| format!("key-{}", i))
+            .collect::<Vec<_>>();
+        keys.iter().enumerate().for_each(|(i, k)| {
+            map.insert(k, i);
+        });
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 7919) & ((1 << 16) - 1);
+            map.get(&keys[i])
+        });
+    }
+
+    #[bench]
+    fn lfmap_str_get_absent(b: &mut Bencher) {
+        let _ = env_logger::try_init();
+        let map = crate::map::HashMap::<String, usize>::with_capacity(8);
+        (0..1 << 16).for_each(|i| {
+            map.insert(&format!("key-{}", i), i);
+        });
+        let absent = (0..1 << 16)
+            .map(|i| format!("absent-{}", i))
+            .collect::<Vec<_>>();
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 7919) & ((1 << 16) - 1);
+            map.get(&absent[i])
+        });
+    }
+
+    #[bench]
+    fn lfset_contains(b: &mut Bencher) {
+        let _ = env_logger::try_init();
+        let set = WordSet::<System, DefaultHasher>::with_capacity(8);
+        (0..1 << 16).for_each(|i| {
+            set.insert(&i);
+        });
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 7919) & ((1 << 17) - 1);
+            set.contains(&i)
+        });
+    }
+
+    #[bench]
+    fn lfset_hash_contains(b: &mut Bencher) {
+        let _ = env_logger::try_init();
+        let set = HashSet::<usize>::with_capacity(8);
+        (0..1 << 16).for_each(|i| {
+            set.insert(&i);
+        });
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 7919) & ((1 << 17) - 1);
+            set.contains(&i)
+        });
+    }
+
     #[bench]
     fn hashmap(b: &mut Bencher) {
         let _ = env_logger::try_init();
@@ -0,0 +1,268 @@
+// A concurrent hash map for values that cannot be cloned, values are moved in and out of it
+// and can only be read in place
+
+use crate::map::{alloc_obj, take_obj, HashMap, Map};
+use std::alloc::{Allocator, System};
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::sync::atomic::Ordering::{Release, SeqCst};
+use std::sync::atomic::{fence, AtomicUsize};
+
+// Values live in slots of their own, the map only carries pointers to them.
+// Readers register on the slot before touching the value. Whoever takes the slot out of the map
+// waits for the registered readers to leave before moving the value out.
+pub(crate) struct Slot<V> {
+    readers: AtomicUsize,
+    value: ManuallyDrop<V>,
+}
+
+pub(crate) struct SlotPtr<V>(*mut Slot<V>);
+
+impl<V> Clone for SlotPtr<V> {
+    fn clone(&self) -> Self {
+        SlotPtr(self.0)
+    }
+}
+
+unsafe impl<V: Send> Send for SlotPtr<V> {}
+unsafe impl<V: Send + Sync> Sync for SlotPtr<V> {}
+
+thread_local! {
+    // Slots the thread is reading values of, taking one of them out would wait for itself
+    static READING: RefCell<Vec<usize>> = RefCell::new(Vec::new());
+}
+
+struct Reading<'a>(&'a AtomicUsize, usize);
+
+impl<'a> Reading<'a> {
+    fn new(readers: &'a AtomicUsize, slot: usize) -> Self {
+        readers.fetch_add(1, SeqCst);
+        READING.with(|reading| reading.borrow_mut().push(slot));
+        Reading(readers, slot)
+    }
+}
+
+impl<'a> Drop for Reading<'a> {
+    fn drop(&mut self) {
+        READING.with(|reading| {
+            let mut reading = reading.borrow_mut();
+            if let Some(pos) = reading.iter().rposition(|slot| *slot == self.1) {
+                reading.remove(pos);
+            }
+        });
+        self.0.fetch_sub(1, Release);
+    }
+}
+
+/// Map of values that cannot be cloned. Values are moved in and out and read in place.
+///
+/// Replacing or removing a key is not lock-free: it waits until every `get_with` that is reading
+/// the value of the key has returned, so a long `get_with` closure stalls the writers of that key.
+/// A `get_with` closure that replaces or removes the key it is reading panics, it would otherwise
+/// wait for itself forever
+
+pub struct OwnedMap<
+    K: Clone + Hash + Eq,
+    V,
+    ALLOC: Allocator + Clone = System,
+    H: Hasher + Default = DefaultHasher,
+> {
+    map: HashMap<K, SlotPtr<V>, ALLOC, H>,
+    alloc: ALLOC,
+}
+
+impl<K: Clone + Hash + Eq, V> OwnedMap<K, V> {
+    pub fn with_capacity(cap: usize) -> Self {
+        Self::with_map(HashMap::with_capacity(cap), System)
+    }
+}
+
+impl<K: Clone + Hash + Eq, V, ALLOC: Allocator + Clone, H: Hasher + Default>
+    OwnedMap<K, V, ALLOC, H>
+{
+    pub(crate) fn with_map(map: HashMap<K, SlotPtr<V>, ALLOC, H>, alloc: ALLOC) -> Self {
+        Self { map, alloc }
+    }
+
+    /// Returns the value that was displaced. Waits for the readers of the displaced value
+    pub fn insert(&self, key: &K, value: V) -> Option<V> {
+        self.check_reentry(key);
+        let slot = alloc_obj(
+            &self.alloc,
+            Slot {
+                readers: AtomicUsize::new(0),
+                value: ManuallyDrop::new(value),
+            },
+        );
+        self.map
+            .insert(key, SlotPtr(slot))
+            .map(|prev| self.reclaim(prev))
+    }
+
+    /// Waits for the readers of the removed value
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.check_reentry(key);
+        self.map.remove(key).map(|slot| self.reclaim(slot))
+    }
+
+    /// Read the value in place. Writers of the key wait for `func` to return, `func` panics if it
+    /// replaces or removes the same key
+    pub fn get_with<R, F: FnOnce(&V) -> R>(&self, key: &K, func: F) -> Option<R> {
+        // Slots taken out of the map are freed through the epoch, pin before finding one
+        let _guard = crossbeam_epoch::pin();
+        let backoff = crossbeam_utils::Backoff::new();
+        loop {
+            let slot = self.map.get(key)?;
+            let slot_ref = unsafe { &*slot.0 };
+            let reading = Reading::new(&slot_ref.readers, slot.0 as usize);
+            fence(SeqCst);
+            // The slot may have been taken out before this reader was seen
+            if self.map.get(key).map(|s| s.0) == Some(slot.0) {
+                let res = func(&slot_ref.value);
+                drop(reading);
+                return Some(res);
+            }
+            drop(reading);
+            backoff.spin();
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Iterate over clones of the keys, see `Table::iter`
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.map.keys()
+    }
+
+    // A slot is never put back once taken out, of the slots this thread reads only the one in
+    // the map now can be taken by the caller
+    fn check_reentry(&self, key: &K) {
+        if READING.with(|reading| reading.borrow().is_empty()) {
+            return;
+        }
+        if let Some(slot) = self.map.get(key) {
+            let slot = slot.0 as usize;
+            if READING.with(|reading| reading.borrow().contains(&slot)) {
+                panic!("the value of the key is being read by this thread, it cannot be moved out");
+            }
+        }
+    }
+
+    fn reclaim(&self, slot: SlotPtr<V>) -> V {
+        let guard = crossbeam_epoch::pin();
+        let backoff = crossbeam_utils::Backoff::new();
+        let slot_ref = unsafe { &*slot.0 };
+        fence(SeqCst);
+        while slot_ref.readers.load(SeqCst) != 0 {
+            backoff.snooze();
+        }
+        let value = unsafe { ptr::read(&*slot_ref.value) };
+        // Late readers may still check in and out of the slot, free it after them
+        let alloc = self.alloc.clone();
+        let slot_ptr = slot.0;
+        unsafe { guard.defer_unchecked(move || drop(take_obj(&alloc, slot_ptr))) }
+        value
+    }
+}
+
+impl<K: Clone + Hash + Eq, V, ALLOC: Allocator + Clone, H: Hasher + Default> Drop
+    for OwnedMap<K, V, ALLOC, H>
+{
+    fn drop(&mut self) {
+        for (_, slot) in self.map.entries() {
+            let mut slot = unsafe { take_obj(&self.alloc, slot.0) };
+            unsafe { ManuallyDrop::drop(&mut slot.value) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    pub fn owned_map_serial() {
+        let map = OwnedMap::with_capacity(16);
+        for i in 0..1024usize {
+            assert!(map.insert(&i, Mutex::new(i)).is_none());
+        }
+        for i in 0..1024usize {
+            assert_eq!(map.get_with(&i, |v| *v.lock().unwrap()), Some(i));
+            map.get_with(&i, |v| *v.lock().unwrap() += 1);
+        }
+        for i in 0..512usize {
+            let prev = map.insert(&i, Mutex::new(0)).unwrap();
+            assert_eq!(prev.into_inner().unwrap(), i + 1);
+            assert_eq!(map.remove(&i).unwrap().into_inner().unwrap(), 0);
+        }
+        assert_eq!(map.len(), 512);
+        assert!(!map.contains_key(&0));
+        assert!(map.remove(&0).is_none());
+        assert_eq!(map.get_with(&1023, |v| *v.lock().unwrap()), Some(1024));
+    }
+
+    #[test]
+    #[should_panic(expected = "being read by this thread")]
+    pub fn owned_map_reentry() {
+        let map = OwnedMap::with_capacity(16);
+        map.insert(&1usize, Mutex::new(1usize));
+        // Other keys can be written while reading
+        map.get_with(&1, |_| map.insert(&2, Mutex::new(2)));
+        map.get_with(&1, |_| map.remove(&1));
+    }
+
+    #[test]
+    pub fn owned_map_drop() {
+        let tracker = Arc::new(());
+        {
+            let map = OwnedMap::with_capacity(16);
+            for i in 0..1024usize {
+                map.insert(&i, Box::new(tracker.clone()));
+            }
+            for i in 0..512usize {
+                drop(map.remove(&i));
+            }
+            assert_eq!(Arc::strong_count(&tracker), 513);
+        }
+        assert_eq!(Arc::strong_count(&tracker), 1);
+    }
+
+    #[test]
+    pub fn owned_map_parallel() {
+        let _ = env_logger::try_init();
+        let map = Arc::new(OwnedMap::with_capacity(16));
+        let num_threads = 4;
+        let num_data = 1000;
+        let mut threads = vec![];
+        for i in 0..num_threads {
+            let map = map.clone();
+            threads.push(thread::spawn(move || {
+                for j in 0..num_data {
+                    let key = j % 64;
+                    if i % 2 == 0 {
+                        if let Some(prev) = map.insert(&key, Box::new(vec![j; 16])) {
+                            assert!(prev.iter().all(|n| *n == prev[0]));
+                        }
+                    } else {
+                        map.get_with(&key, |v| assert!(v.iter().all(|n| *n == v[0])));
+                        map.remove(&key);
+                    }
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,546 @@
+// A word map kept in a memory mapped file, recovered without any serialization when the file is
+// opened again
+
+use crate::map::{
+    Chunk, DefaultResizePolicy, EntryTemplate, InsertError, InsertOp, Map, MapBuilder,
+    ResizePolicy, WordAttachment, WordMap,
+};
+use crate::spin::SpinLock;
+use std::alloc::{AllocError, Allocator, Layout};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{File, OpenOptions};
+use std::hash::Hasher;
+use std::io;
+use std::mem::{self, ManuallyDrop};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::Arc;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"LTNGWMAP");
+// Chunks of version 1 kept the absolute address of their entries
+const VERSION: usize = 2;
+const MAX_BLOCKS: usize = 64;
+const HEADER_SIZE: usize = 4096;
+const BLOCK_ALIGN: usize = 64;
+// Blocks reserved but not handed out yet, they are released on recovery
+const RESERVED: usize = 0;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Block {
+    offset: usize,
+    size: usize,
+    // Order of allocation, tells the current chunk of a table from the chunk it migrates to
+    generation: usize,
+}
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    version: usize,
+    // Layout of the chunks, files written with another layout cannot be read
+    chunk_header_size: usize,
+    entry_size: usize,
+    file_size: usize,
+    init_cap: usize,
+    generation: usize,
+    blocks: [Block; MAX_BLOCKS],
+}
+
+type PersistentChunk = Chunk<(), (), WordAttachment, MappedFile>;
+
+struct Mapping {
+    base: usize,
+    size: usize,
+    lock: SpinLock<()>,
+    // Chunks released after the map is dropped stay in the file, the recovery releases them
+    closed: AtomicBool,
+    _file: File,
+}
+
+/// Allocator handing out blocks of a memory mapped file, the blocks in use are recorded in the
+/// header of the file. Memory is never given back to the file system, the file has the size
+/// it is created with and allocations fail once no gap of the file fits them.
+#[derive(Clone)]
+pub struct MappedFile {
+    mapping: Arc<Mapping>,
+}
+
+impl MappedFile {
+    fn map(file: File, size: usize) -> io::Result<Self> {
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            mapping: Arc::new(Mapping {
+                base: base as usize,
+                size,
+                lock: SpinLock::new(()),
+                closed: AtomicBool::new(false),
+                _file: file,
+            }),
+        })
+    }
+
+    fn header(&self) -> *mut Header {
+        self.mapping.base as *mut Header
+    }
+
+    /// Write the mapped pages back to the file, they are otherwise written back by the system
+    /// at some point after the changes, or lost if it stops before
+    pub fn flush(&self) -> io::Result<()> {
+        let res = unsafe {
+            libc::msync(
+                self.mapping.base as *mut libc::c_void,
+                self.mapping.size,
+                libc::MS_SYNC,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Record a block for the layout in the first gap that fits, not handed out until published
+    fn reserve(&self, layout: Layout) -> Result<(usize, usize), AllocError> {
+        let _guard = self.mapping.lock.lock();
+        let header = unsafe { &mut *self.header() };
+        let align = layout.align().max(BLOCK_ALIGN);
+        let mut used = header
+            .blocks
+            .iter()
+            .filter(|block| block.size != 0)
+            .map(|block| (block.offset, block.size))
+            .collect::<Vec<_>>();
+        used.sort_unstable();
+        let mut offset = HEADER_SIZE;
+        for (used_offset, used_size) in used.into_iter().chain(Some((self.mapping.size, 0))) {
+            let start = offset + crate::align_padding(offset, align);
+            if start + layout.size() <= used_offset {
+                let slot = header
+                    .blocks
+                    .iter()
+                    .position(|block| block.size == 0)
+                    .ok_or(AllocError)?;
+                header.blocks[slot] = Block {
+                    offset: start,
+                    size: layout.size().max(1),
+                    generation: RESERVED,
+                };
+                return Ok((slot, start));
+            }
+            offset = offset.max(used_offset + used_size);
+        }
+        Err(AllocError)
+    }
+
+    fn publish(&self, slot: usize) {
+        let _guard = self.mapping.lock.lock();
+        let header = unsafe { &mut *self.header() };
+        header.generation += 1;
+        header.blocks[slot].generation = header.generation;
+    }
+
+    fn release(&self, offset: usize) {
+        if self.mapping.closed.load(Acquire) {
+            return;
+        }
+        let _guard = self.mapping.lock.lock();
+        let header = unsafe { &mut *self.header() };
+        if let Some(block) = header
+            .blocks
+            .iter_mut()
+            .find(|block| block.size != 0 && block.offset == offset)
+        {
+            *block = Block {
+                offset: 0,
+                size: 0,
+                generation: RESERVED,
+            };
+        }
+    }
+}
+
+unsafe impl Allocator for MappedFile {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let (slot, offset) = self.reserve(layout)?;
+        self.publish(slot);
+        let ptr = (self.mapping.base + offset) as *mut u8;
+        Ok(NonNull::slice_from_raw_parts(
+            unsafe { NonNull::new_unchecked(ptr) },
+            layout.size(),
+        ))
+    }
+
+    // The block may hold a chunk released before, it is zeroed before it is published so a
+    // recovery never takes the stale chunk for a new one
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let (slot, offset) = self.reserve(layout)?;
+        let ptr = (self.mapping.base + offset) as *mut u8;
+        unsafe { ptr::write_bytes(ptr, 0, layout.size()) };
+        self.publish(slot);
+        Ok(NonNull::slice_from_raw_parts(
+            unsafe { NonNull::new_unchecked(ptr) },
+            layout.size(),
+        ))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        self.release(ptr.as_ptr() as usize - self.mapping.base)
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.size) };
+    }
+}
+
+/// `usize` to `usize` mapping like `WordMap`, with its chunks allocated from a memory mapped
+/// file. Dropping the map leaves the chunk in the file, opening the file again recovers the
+/// entries, including those of a map that stopped during a migration.
+/// A file shall be opened by one map at a time, with the same hasher it was created with.
+/// The file never grows, once it cannot hold a larger chunk the map stays at its capacity and
+/// `insert` returns an error for new keys beyond its load limit.
+pub struct PersistentWordMap<H: Hasher + Default = DefaultHasher> {
+    map: ManuallyDrop<WordMap<MappedFile, H>>,
+    file: MappedFile,
+}
+
+impl<H: Hasher + Default> PersistentWordMap<H> {
+    /// Create a file of `size` bytes for a map of `cap` slots to begin with. The chunks of the
+    /// map and of its migrations shall fit in the file
+    pub fn create<P: AsRef<Path>>(path: P, size: usize, cap: usize) -> io::Result<Self> {
+        Self::create_with_policy(path, size, cap, Arc::new(DefaultResizePolicy))
+    }
+
+    /// The resize policy is not kept in the file, it is given again on `open_with_policy`
+    pub fn create_with_policy<P: AsRef<Path>>(
+        path: P,
+        size: usize,
+        cap: usize,
+        policy: Arc<dyn ResizePolicy>,
+    ) -> io::Result<Self> {
+        let builder = MapBuilder::new()
+            .hasher::<H>()
+            .capacity(cap)
+            .resize_policy(policy);
+        let init_cap = builder.chunk_capacity().map_err(invalid_input)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        if size < HEADER_SIZE + mem::size_of::<PersistentChunk>() {
+            return Err(invalid_input("file size cannot hold a chunk"));
+        }
+        file.set_len(size as u64)?;
+        let file = MappedFile::map(file, size)?;
+        unsafe {
+            ptr::write(
+                file.header(),
+                Header {
+                    magic: MAGIC,
+                    version: VERSION,
+                    chunk_header_size: mem::size_of::<PersistentChunk>(),
+                    entry_size: mem::size_of::<EntryTemplate>(),
+                    file_size: size,
+                    init_cap,
+                    generation: 0,
+                    blocks: [Block {
+                        offset: 0,
+                        size: 0,
+                        generation: RESERVED,
+                    }; MAX_BLOCKS],
+                },
+            )
+        };
+        let map = builder
+            .allocator(file.clone())
+            .word_map()
+            .map_err(invalid_input)?;
+        Ok(Self {
+            map: ManuallyDrop::new(map),
+            file,
+        })
+    }
+
+    /// Open a file created by `create` and recover its map
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open_with_policy(path, Arc::new(DefaultResizePolicy))
+    }
+
+    pub fn open_with_policy<P: AsRef<Path>>(
+        path: P,
+        policy: Arc<dyn ResizePolicy>,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let size = file.metadata()?.len() as usize;
+        if size < HEADER_SIZE {
+            return Err(invalid_data("file is too small"));
+        }
+        let file = MappedFile::map(file, size)?;
+        let header = unsafe { &*file.header() };
+        if header.magic != MAGIC || header.version != VERSION {
+            return Err(invalid_data("not a persistent word map"));
+        }
+        if header.chunk_header_size != mem::size_of::<PersistentChunk>()
+            || header.entry_size != mem::size_of::<EntryTemplate>()
+            || header.file_size != size
+        {
+            return Err(invalid_data("chunk layout does not match"));
+        }
+        let mut blocks = header
+            .blocks
+            .iter()
+            .filter(|block| block.size != 0)
+            .cloned()
+            .collect::<Vec<_>>();
+        for block in &blocks {
+            if block.offset < HEADER_SIZE || block.offset.saturating_add(block.size) > size {
+                return Err(invalid_data("block out of the file"));
+            }
+        }
+        let init_cap = header.init_cap;
+        blocks.sort_unstable_by_key(|block| block.generation);
+        let mut chunks = vec![];
+        for block in blocks {
+            if block.generation == RESERVED || block.size < mem::size_of::<PersistentChunk>() {
+                file.release(block.offset);
+            } else {
+                chunks.push(file.mapping.base + block.offset);
+            }
+        }
+        let recovered =
+            unsafe { WordMap::recover(&chunks, init_cap, policy.clone(), file.clone()) };
+        let map = match recovered {
+            Some((map, garbage)) => {
+                for chunk in garbage {
+                    file.release(chunk - file.mapping.base);
+                }
+                map
+            }
+            None => MapBuilder::new()
+                .hasher::<H>()
+                .allocator(file.clone())
+                .capacity(init_cap)
+                .resize_policy(policy)
+                .word_map()
+                .map_err(invalid_data)?,
+        };
+        Ok(Self {
+            map: ManuallyDrop::new(map),
+            file,
+        })
+    }
+
+    /// Write the map back to the file, see `MappedFile::flush`
+    pub fn flush(&self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    #[inline(always)]
+    pub fn get(&self, key: &usize) -> Option<usize> {
+        self.map.get(key)
+    }
+
+    /// Insert, or give an error when the key or the value is out of range, or the key is new
+    /// and the file has no room left for the map to grow
+    #[inline(always)]
+    pub fn insert(&self, key: &usize, value: usize) -> Result<Option<usize>, InsertError> {
+        self.map.fallible_insert(InsertOp::UpsertFast, key, value)
+    }
+
+    #[inline(always)]
+    pub fn remove(&self, key: &usize) -> Option<usize> {
+        self.map.remove(key)
+    }
+
+    pub fn entries(&self) -> Vec<(usize, usize)> {
+        self.map.entries()
+    }
+
+    #[inline(always)]
+    pub fn contains_key(&self, key: &usize) -> bool {
+        self.map.contains_key(key)
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn compare_exchange(
+        &self,
+        key: &usize,
+        current: usize,
+        new: usize,
+    ) -> Result<usize, Option<usize>> {
+        self.map.compare_exchange(key, current, new)
+    }
+
+    pub fn fetch_add(&self, key: &usize, val: usize) -> Option<usize> {
+        self.map.fetch_add(key, val)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.map.iter()
+    }
+
+    pub fn retain<F: FnMut(usize, usize) -> bool>(&self, keep: F) {
+        self.map.retain(keep)
+    }
+
+    pub fn clear(&self) {
+        self.map.clear()
+    }
+
+    pub fn shrink_to_fit(&self) {
+        self.map.shrink_to_fit()
+    }
+}
+
+impl<H: Hasher + Default> Drop for PersistentWordMap<H> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::take(&mut self.map) }.detach();
+        // Migrated chunks may be released later through the epoch, while the file is opened by
+        // another map that could have taken their blocks
+        self.file.mapping.closed.store(true, Release);
+    }
+}
+
+fn invalid_input<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    struct BatchPolicy;
+
+    impl ResizePolicy for BatchPolicy {
+        fn migration_batch(&self) -> Option<usize> {
+            Some(1)
+        }
+    }
+
+    fn temp_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    pub fn persistent_reopen() {
+        let path = temp_file("lightning-persistent-reopen");
+        let map = PersistentWordMap::<DefaultHasher>::create(&path, 1 << 22, 16).unwrap();
+        for i in 5..10000 {
+            map.insert(&i, i * 2).unwrap();
+        }
+        for i in (5..10000).step_by(3) {
+            map.remove(&i);
+        }
+        map.flush().unwrap();
+        drop(map);
+        assert!(PersistentWordMap::<DefaultHasher>::create(&path, 1 << 22, 16).is_err());
+        let map = PersistentWordMap::<DefaultHasher>::open(&path).unwrap();
+        assert_eq!(map.len(), 9995 - 3332);
+        for i in 5..10000 {
+            let expected = if (i - 5) % 3 == 0 { None } else { Some(i * 2) };
+            assert_eq!(map.get(&i), expected);
+        }
+        map.insert(&3, 4).unwrap();
+        drop(map);
+        let map = PersistentWordMap::<DefaultHasher>::open(&path).unwrap();
+        assert_eq!(map.get(&3), Some(4));
+        drop(map);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn persistent_recover_migration() {
+        let path = temp_file("lightning-persistent-migration");
+        let map = PersistentWordMap::<DefaultHasher>::create_with_policy(
+            &path,
+            1 << 20,
+            16,
+            Arc::new(BatchPolicy),
+        )
+        .unwrap();
+        // The chunk starts to grow on the 13th key and migrates one slot per insertion after,
+        // the migration is not done by the last key
+        for i in 0..20 {
+            map.insert(&i, i + 100).unwrap();
+        }
+        // Stop without leaving the chunks as a drop does, like a crash
+        map.file.mapping.closed.store(true, Release);
+        mem::forget(map);
+        let map = PersistentWordMap::<DefaultHasher>::open(&path).unwrap();
+        assert_eq!(map.len(), 20);
+        for i in 0..20 {
+            assert_eq!(map.get(&i), Some(i + 100));
+        }
+        map.insert(&20, 120).unwrap();
+        drop(map);
+        let map = PersistentWordMap::<DefaultHasher>::open(&path).unwrap();
+        assert_eq!(map.len(), 21);
+        drop(map);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn persistent_file_full() {
+        let path = temp_file("lightning-persistent-full");
+        let map = PersistentWordMap::<DefaultHasher>::create(&path, 1 << 16, 16).unwrap();
+        let mut inserted = 0;
+        while map.insert(&inserted, inserted + 1).is_ok() {
+            inserted += 1;
+        }
+        assert!(inserted >= 16);
+        assert_eq!(
+            map.insert(&inserted, 0),
+            Err(InsertError::Full(crate::map::FullError))
+        );
+        assert_eq!(
+            map.insert(
+                &WordMap::<MappedFile>::MAX_KEY,
+                WordMap::<MappedFile>::MAX_VALUE + 1
+            ),
+            Err(InsertError::Range(crate::map::RangeError::Value(
+                WordMap::<MappedFile>::MAX_VALUE + 1
+            )))
+        );
+        // The map stays usable at the capacity it reached
+        assert_eq!(map.len(), inserted);
+        assert_eq!(map.insert(&0, 100), Ok(Some(1)));
+        for i in 1..inserted {
+            assert_eq!(map.get(&i), Some(i + 1));
+        }
+        map.clear();
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.insert(&1, 2), Ok(None));
+        drop(map);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,408 @@
+// A concurrent hash map of plain data values, values are encoded into the value word of the
+// entries so there is no attachment to look at
+
+use crate::map::{InsertOp, Map, RangeError, WordMap};
+use std::alloc::{Allocator, System};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+
+/// Plain data that can be encoded in a word. `from_word` gives back the value `to_word`
+/// encoded. Values whose word is above `WordMap::MAX_VALUE` cannot be stored in a `PodMap`.
+/// Every value of `u8` to `u32`, `i8` to `i32`, `f32`, `bool` and `char` fits, the wider types
+/// only fit in part, see `PodMap`
+pub trait WordRepr: Copy {
+    fn to_word(self) -> usize;
+    fn from_word(word: usize) -> Self;
+}
+
+macro_rules! unsigned_word_repr {
+    ($($t:ty),*) => {
+        $(
+            impl WordRepr for $t {
+                #[inline(always)]
+                fn to_word(self) -> usize {
+                    self as usize
+                }
+
+                #[inline(always)]
+                fn from_word(word: usize) -> Self {
+                    word as $t
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! signed_word_repr {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl WordRepr for $t {
+                #[inline(always)]
+                fn to_word(self) -> usize {
+                    self as $u as usize
+                }
+
+                #[inline(always)]
+                fn from_word(word: usize) -> Self {
+                    word as $u as $t
+                }
+            }
+        )*
+    };
+}
+
+// Zigzag, small negative numbers stay small
+macro_rules! zigzag_word_repr {
+    ($($t:ty),*) => {
+        $(
+            impl WordRepr for $t {
+                #[inline(always)]
+                fn to_word(self) -> usize {
+                    ((self << 1) ^ (self >> 63)) as usize
+                }
+
+                #[inline(always)]
+                fn from_word(word: usize) -> Self {
+                    ((word >> 1) as $t) ^ -((word & 1) as $t)
+                }
+            }
+        )*
+    };
+}
+
+unsigned_word_repr!(u8, u16, u32, u64, usize);
+signed_word_repr!(i8 => u8, i16 => u16, i32 => u32);
+zigzag_word_repr!(i64, isize);
+
+impl WordRepr for bool {
+    #[inline(always)]
+    fn to_word(self) -> usize {
+        self as usize
+    }
+
+    #[inline(always)]
+    fn from_word(word: usize) -> Self {
+        word != 0
+    }
+}
+
+impl WordRepr for char {
+    #[inline(always)]
+    fn to_word(self) -> usize {
+        self as usize
+    }
+
+    #[inline(always)]
+    fn from_word(word: usize) -> Self {
+        char::from_u32(word as u32).unwrap()
+    }
+}
+
+impl WordRepr for f32 {
+    #[inline(always)]
+    fn to_word(self) -> usize {
+        self.to_bits() as usize
+    }
+
+    #[inline(always)]
+    fn from_word(word: usize) -> Self {
+        f32::from_bits(word as u32)
+    }
+}
+
+const F64_MANTISSA: u64 = (1 << 52) - 1;
+
+// The exponent goes to the top of the word with the sign at the bottom. Exponents of normal
+// values from 2^-255 to 2^256 are swapped to the front after zero and subnormals, they are the
+// ones that leave the two top bits clear
+impl WordRepr for f64 {
+    #[inline(always)]
+    fn to_word(self) -> usize {
+        let bits = self.to_bits();
+        let exp = match (bits >> 52) & 0x7ff {
+            0 => 0,
+            exp @ 1..=767 => exp + 511,
+            exp @ 768..=1278 => exp - 767,
+            exp => exp,
+        };
+        ((exp << 53) | ((bits & F64_MANTISSA) << 1) | (bits >> 63)) as usize
+    }
+
+    #[inline(always)]
+    fn from_word(word: usize) -> Self {
+        let word = word as u64;
+        let exp = match word >> 53 {
+            0 => 0,
+            exp @ 1..=511 => exp + 767,
+            exp @ 512..=1278 => exp - 511,
+            exp => exp,
+        };
+        f64::from_bits(((word & 1) << 63) | (exp << 52) | ((word >> 1) & F64_MANTISSA))
+    }
+}
+
+impl<T> WordRepr for *const T {
+    #[inline(always)]
+    fn to_word(self) -> usize {
+        self as usize
+    }
+
+    #[inline(always)]
+    fn from_word(word: usize) -> Self {
+        word as *const T
+    }
+}
+
+impl<T> WordRepr for *mut T {
+    #[inline(always)]
+    fn to_word(self) -> usize {
+        self as usize
+    }
+
+    #[inline(always)]
+    fn from_word(word: usize) -> Self {
+        word as *mut T
+    }
+}
+
+impl<T> WordRepr for NonNull<T> {
+    #[inline(always)]
+    fn to_word(self) -> usize {
+        self.as_ptr() as usize
+    }
+
+    #[inline(always)]
+    fn from_word(word: usize) -> Self {
+        NonNull::new(word as *mut T).unwrap()
+    }
+}
+
+/// `usize` to `V` mapping on top of `WordMap`, a lookup only touches the entry like it does on
+/// `WordMap`. Values are compared by their words in `compare_exchange`
+pub struct PodMap<
+    V: WordRepr,
+    ALLOC: Allocator + Clone = System,
+    H: Hasher + Default = DefaultHasher,
+> {
+    map: WordMap<ALLOC, H>,
+    shadow: PhantomData<fn() -> V>,
+}
+
+impl<V: WordRepr> PodMap<V> {
+    pub fn with_capacity(cap: usize) -> Self {
+        Self::with_map(WordMap::with_capacity(cap))
+    }
+}
+
+impl<V: WordRepr, ALLOC: Allocator + Clone, H: Hasher + Default> PodMap<V, ALLOC, H> {
+    pub(crate) fn with_map(map: WordMap<ALLOC, H>) -> Self {
+        Self {
+            map,
+            shadow: PhantomData,
+        }
+    }
+
+    fn insert_with_op(&self, op: InsertOp, key: &usize, value: V) -> Option<V> {
+        self.map
+            .checked_insert_with_op(op, key, value.to_word())
+            .unwrap_or_else(|e| panic!("{}", e))
+            .map(V::from_word)
+    }
+
+    /// Insert, or give an error instead of panicking when the key or the value is out of range
+    pub fn checked_insert(&self, key: &usize, value: V) -> Result<Option<V>, RangeError> {
+        self.map
+            .checked_insert(key, value.to_word())
+            .map(|prev| prev.map(V::from_word))
+    }
+
+    /// See `WordMap::compare_exchange`
+    pub fn compare_exchange(&self, key: &usize, current: V, new: V) -> Result<V, Option<V>> {
+        self.map
+            .compare_exchange(key, current.to_word(), new.to_word())
+            .map(V::from_word)
+            .map_err(|actual| actual.map(V::from_word))
+    }
+
+    /// See `WordMap::fetch_update`
+    pub fn fetch_update<F: Fn(V) -> Option<V>>(
+        &self,
+        key: &usize,
+        func: F,
+    ) -> Result<V, Option<V>> {
+        self.map
+            .fetch_update(key, |word| func(V::from_word(word)).map(V::to_word))
+            .map(V::from_word)
+            .map_err(|actual| actual.map(V::from_word))
+    }
+
+    /// Iterate over the entries without collecting them, see `Table::iter`
+    pub fn iter(&self) -> impl Iterator<Item = (usize, V)> + '_ {
+        self.map.iter().map(|(k, v)| (k, V::from_word(v)))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = usize> + '_ {
+        self.map.keys()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = V> + '_ {
+        self.map.values().map(V::from_word)
+    }
+
+    /// Keep only the entries `keep` returns true for, see `Table::retain`
+    pub fn retain<F: FnMut(usize, V) -> bool>(&self, mut keep: F) {
+        self.map.retain(|k, v| keep(k, V::from_word(v)))
+    }
+
+    /// Remove every entry, see `Table::clear`
+    pub fn clear(&self) {
+        self.map.clear()
+    }
+
+    /// Remove the entries as they are yielded, see `Table::drain`
+    pub fn drain(&self) -> impl Iterator<Item = (usize, V)> + '_ {
+        self.map.drain().map(|(k, v)| (k, V::from_word(v)))
+    }
+
+    pub fn shrink_to_fit(&self) {
+        self.map.shrink_to_fit()
+    }
+}
+
+/// `insert` and `try_insert` panic on values that do not fit: `u64`, `usize` and pointers above
+/// `WordMap::MAX_VALUE`, `i64` and `isize` beyond about 2^61 either way, and `f64` that are not
+/// zero or subnormal and not from 2^-255 to 2^256 in magnitude, infinities and NaNs included.
+/// `checked_insert` returns an error for them instead
+impl<V: WordRepr, ALLOC: Allocator + Clone, H: Hasher + Default> Map<usize, V>
+    for PodMap<V, ALLOC, H>
+{
+    #[inline(always)]
+    fn get(&self, key: &usize) -> Option<V> {
+        self.map.get(key).map(V::from_word)
+    }
+
+    #[inline(always)]
+    fn insert(&self, key: &usize, value: V) -> Option<V> {
+        self.insert_with_op(InsertOp::UpsertFast, key, value)
+    }
+
+    #[inline(always)]
+    fn try_insert(&self, key: &usize, value: V) -> Option<V> {
+        self.insert_with_op(InsertOp::TryInsert, key, value)
+    }
+
+    #[inline(always)]
+    fn remove(&self, key: &usize) -> Option<V> {
+        self.map.remove(key).map(V::from_word)
+    }
+
+    fn entries(&self) -> Vec<(usize, V)> {
+        self.iter().collect()
+    }
+
+    #[inline(always)]
+    fn contains_key(&self, key: &usize) -> bool {
+        self.map.contains_key(key)
+    }
+
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn round_trip<V: WordRepr + PartialEq + std::fmt::Debug>(values: &[V]) {
+        let map = PodMap::<V>::with_capacity(16);
+        for (k, v) in values.iter().enumerate() {
+            assert!(map.insert(&k, *v).is_none());
+        }
+        for (k, v) in values.iter().enumerate() {
+            assert_eq!(map.get(&k), Some(*v));
+        }
+    }
+
+    #[test]
+    pub fn pod_map_values() {
+        round_trip(&[0u32, 1, u32::MAX]);
+        round_trip(&[0i32, -1, i32::MIN, i32::MAX]);
+        round_trip(&[0i64, -1, 1, -(1 << 60), (1 << 60) - 1]);
+        round_trip(&[0.0f32, -0.0, 1.5, f32::INFINITY, f32::MIN_POSITIVE]);
+        round_trip(&[
+            0.0f64,
+            -0.0,
+            1.0,
+            -2.5,
+            0.1,
+            1e-70,
+            1e70,
+            f64::MIN_POSITIVE / 4.0,
+            f64::EPSILON,
+        ]);
+        round_trip(&[true, false]);
+        round_trip(&['a', '\u{10FFFF}']);
+        let boxed = Box::into_raw(Box::new(1));
+        round_trip(&[NonNull::new(boxed).unwrap()]);
+        round_trip(&[boxed as *const i32, std::ptr::null()]);
+        drop(unsafe { Box::from_raw(boxed) });
+
+        let map = PodMap::<f64>::with_capacity(16);
+        for word in (0..1 << 12).map(|e| (e << 52) | 0x123) {
+            let v = f64::from_bits(word);
+            assert_eq!(f64::from_word(v.to_word()).to_bits(), word);
+            let fits = v == 0.0 || v.abs() < 2f64.powi(256) && v.abs() >= 2f64.powi(-255);
+            assert_eq!(map.checked_insert(&1, v).is_ok(), fits || v.is_subnormal());
+        }
+        assert!(map.checked_insert(&1, f64::NAN).is_err());
+        let map = PodMap::<i64>::with_capacity(16);
+        assert_eq!(
+            map.checked_insert(&1, i64::MIN),
+            Err(RangeError::Value(usize::MAX))
+        );
+        assert_eq!(map.compare_exchange(&1, 0, -1), Err(None));
+        map.insert(&1, -5);
+        assert_eq!(map.fetch_update(&1, |v| Some(v * 2)), Ok(-5));
+        assert_eq!(map.compare_exchange(&1, -10, 10), Ok(-10));
+        assert_eq!(map.iter().collect::<Vec<_>>(), vec![(1, 10)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    pub fn pod_map_insert_out_of_range() {
+        let map = PodMap::<f64>::with_capacity(16);
+        assert_eq!(map.insert(&1, 1.0), None);
+        map.insert(&1, f64::INFINITY);
+    }
+
+    #[test]
+    pub fn pod_map_threads() {
+        let map = Arc::new(PodMap::<f64>::with_capacity(16));
+        let num_threads = 4;
+        let num_keys = 1024;
+        let threads = (0..num_threads)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for k in 0..num_keys {
+                        map.insert(&(k * num_threads + t), k as f64 * 0.5);
+                        let _ = map.fetch_update(&k, |v| Some(v + 1.0));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        for k in 0..num_keys * num_threads {
+            let v = map.get(&k).unwrap();
+            assert_eq!(v.fract(), ((k / num_threads) % 2) as f64 * 0.5);
+        }
+    }
+}
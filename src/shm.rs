@@ -0,0 +1,540 @@
+// Maps kept in named POSIX shared memory segments, so processes on one machine can share one
+// lock-free table. Chunks address their entries from where they are mapped, every process maps
+// the segment at an address of its own.
+//
+// Memory released through the epoch of one process could still be read by another, so tables in
+// a segment never migrate. They have one chunk of a fixed capacity and decline new keys once its
+// occupied slots reach the load limit. Removed keys keep their slots, the chunk is sized for the
+// keys ever inserted rather than for the live ones.
+
+use crate::map::{
+    EntryTemplate, FullError, InlineMap, InsertError, InsertOp, Map, MapBuilder, WordMap,
+};
+use std::alloc::System;
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::CString;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Acquire, Release};
+
+const WORD_MAGIC: u64 = u64::from_le_bytes(*b"LTNGSHWM");
+const INLINE_MAGIC: u64 = u64::from_le_bytes(*b"LTNGSHIM");
+const VERSION: usize = 1;
+// One cache line, the chunk after it starts on a cache line as well
+const HEADER_SIZE: usize = 64;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    version: usize,
+    // Layout of the chunk, processes built with another layout cannot share it
+    entry_size: usize,
+    pair_size: usize,
+    chunk_size: usize,
+    capacity: usize,
+    // Set once the chunk is written, the segment cannot be opened before
+    ready: AtomicUsize,
+}
+
+// What a segment holds, checked by every process opening it
+struct Layout {
+    magic: u64,
+    pair_size: usize,
+    capacity: usize,
+    chunk_size: fn(usize) -> usize,
+}
+
+struct Segment {
+    base: usize,
+    size: usize,
+}
+
+impl Segment {
+    fn create(name: &str, layout: &Layout) -> io::Result<Self> {
+        let name = segment_name(name)?;
+        let chunk_size = (layout.chunk_size)(layout.capacity);
+        let size = HEADER_SIZE + chunk_size;
+        let fd = unsafe {
+            libc::shm_open(
+                name.as_ptr(),
+                libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+                0o600,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        // New pages are zeroed, as a chunk shall be before it is written
+        let segment = file
+            .set_len(size as u64)
+            .and_then(|_| Self::map(&file, size));
+        if segment.is_err() {
+            unsafe { libc::shm_unlink(name.as_ptr()) };
+        }
+        let segment = segment?;
+        // Written through the mapping, no reference to the header is taken before
+        unsafe {
+            ptr::write(
+                segment.base as *mut Header,
+                Header {
+                    magic: layout.magic,
+                    version: VERSION,
+                    entry_size: mem::size_of::<EntryTemplate>(),
+                    pair_size: layout.pair_size,
+                    chunk_size,
+                    capacity: layout.capacity,
+                    ready: AtomicUsize::new(0),
+                },
+            )
+        };
+        Ok(segment)
+    }
+
+    // Open a segment written by `create`, the capacity of the layout is taken from its header
+    fn open(name: &str, layout: &mut Layout) -> io::Result<Self> {
+        let name = segment_name(name)?;
+        let fd = unsafe { libc::shm_open(name.as_ptr(), libc::O_RDWR, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        let size = file.metadata()?.len() as usize;
+        if size < HEADER_SIZE {
+            // Sized right after it is created
+            return Err(not_ready());
+        }
+        let segment = Self::map(&file, size)?;
+        let header = segment.header();
+        if header.ready.load(Acquire) == 0 {
+            return Err(not_ready());
+        }
+        if header.magic != layout.magic || header.version != VERSION {
+            return Err(invalid_data("not a shared map of this kind"));
+        }
+        let capacity = header.capacity;
+        if header.entry_size != mem::size_of::<EntryTemplate>()
+            || header.pair_size != layout.pair_size
+            || !capacity.is_power_of_two()
+            || header.chunk_size != (layout.chunk_size)(capacity)
+            || size != HEADER_SIZE + header.chunk_size
+        {
+            return Err(invalid_data("chunk layout does not match"));
+        }
+        layout.capacity = capacity;
+        Ok(segment)
+    }
+
+    fn map(file: &File, size: usize) -> io::Result<Self> {
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            base: base as usize,
+            size,
+        })
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.base as *const Header) }
+    }
+
+    fn chunk_addr(&self) -> usize {
+        self.base + HEADER_SIZE
+    }
+
+    fn publish(&self) {
+        self.header().ready.store(1, Release);
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.size) };
+    }
+}
+
+/// Remove the name of a segment, processes that have it opened keep it until they drop it
+pub fn unlink(name: &str) -> io::Result<()> {
+    let name = segment_name(name)?;
+    if unsafe { libc::shm_unlink(name.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `usize` to `usize` mapping like `WordMap`, in a named shared memory segment that other
+/// processes open by its name. The segment is sized on `create` for a number of keys and the
+/// map never grows, inserting a key beyond them returns an error. Removed keys keep their
+/// slots, so a segment takes no more keys than it is sized for over its whole life.
+/// Processes sharing a segment shall be built with the same hasher.
+pub struct SharedWordMap<H: Hasher + Default = DefaultHasher> {
+    map: WordMap<System, H>,
+    // Keeps the chunk mapped, dropped after the map
+    _segment: Segment,
+}
+
+impl<H: Hasher + Default> SharedWordMap<H> {
+    fn layout(capacity: usize) -> Layout {
+        Layout {
+            magic: WORD_MAGIC,
+            pair_size: 0,
+            capacity,
+            chunk_size: WordMap::<System, H>::fixed_size,
+        }
+    }
+
+    /// Create the segment `name`, such as `/index`, with room for `elements` keys. Fails if a
+    /// segment of the name exists
+    pub fn create(name: &str, elements: usize) -> io::Result<Self> {
+        let cap = MapBuilder::new()
+            .expected_elements(elements)
+            .chunk_capacity()
+            .map_err(invalid_input)?;
+        let segment = Segment::create(name, &Self::layout(cap))?;
+        let map = unsafe { WordMap::fixed_at(segment.chunk_addr(), cap, true, System) };
+        segment.publish();
+        Ok(Self {
+            map,
+            _segment: segment,
+        })
+    }
+
+    /// Open the segment `name` created by `create` in this process or another. Fails with
+    /// `WouldBlock` while it is being created
+    pub fn open(name: &str) -> io::Result<Self> {
+        let mut layout = Self::layout(0);
+        let segment = Segment::open(name, &mut layout)?;
+        let map =
+            unsafe { WordMap::fixed_at(segment.chunk_addr(), layout.capacity, false, System) };
+        Ok(Self {
+            map,
+            _segment: segment,
+        })
+    }
+
+    #[inline(always)]
+    pub fn get(&self, key: &usize) -> Option<usize> {
+        self.map.get(key)
+    }
+
+    /// Insert, or give an error when the key or the value is out of range or no slot is left
+    /// for a new key
+    #[inline(always)]
+    pub fn insert(&self, key: &usize, value: usize) -> Result<Option<usize>, InsertError> {
+        self.map.fallible_insert(InsertOp::UpsertFast, key, value)
+    }
+
+    #[inline(always)]
+    pub fn remove(&self, key: &usize) -> Option<usize> {
+        self.map.remove(key)
+    }
+
+    pub fn entries(&self) -> Vec<(usize, usize)> {
+        self.map.entries()
+    }
+
+    #[inline(always)]
+    pub fn contains_key(&self, key: &usize) -> bool {
+        self.map.contains_key(key)
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn compare_exchange(
+        &self,
+        key: &usize,
+        current: usize,
+        new: usize,
+    ) -> Result<usize, Option<usize>> {
+        self.map.compare_exchange(key, current, new)
+    }
+
+    pub fn fetch_update<F: Fn(usize) -> Option<usize>>(
+        &self,
+        key: &usize,
+        func: F,
+    ) -> Result<usize, Option<usize>> {
+        self.map.fetch_update(key, func)
+    }
+
+    pub fn fetch_add(&self, key: &usize, val: usize) -> Option<usize> {
+        self.map.fetch_add(key, val)
+    }
+
+    pub fn fetch_sub(&self, key: &usize, val: usize) -> Option<usize> {
+        self.map.fetch_sub(key, val)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.map.iter()
+    }
+
+    pub fn retain<F: FnMut(usize, usize) -> bool>(&self, keep: F) {
+        self.map.retain(keep)
+    }
+
+    /// Remove every entry, their keys keep the slots
+    pub fn clear(&self) {
+        self.map.clear()
+    }
+}
+
+/// `InlineMap` of plain data keys and values in a named shared memory segment, see
+/// `SharedWordMap`. Keys and values are copied into the segment as they are, they shall hold no
+/// pointer or anything else that only means something in one process
+pub struct SharedInlineMap<K: Copy + Hash + Eq, V: Copy, H: Hasher + Default = DefaultHasher> {
+    map: InlineMap<K, V, System, H>,
+    // Keeps the chunk mapped, dropped after the map
+    _segment: Segment,
+}
+
+impl<K: Copy + Hash + Eq, V: Copy, H: Hasher + Default> SharedInlineMap<K, V, H> {
+    fn layout(capacity: usize) -> Layout {
+        Layout {
+            magic: INLINE_MAGIC,
+            pair_size: mem::size_of::<(K, V)>(),
+            capacity,
+            chunk_size: InlineMap::<K, V, System, H>::fixed_size,
+        }
+    }
+
+    /// See `SharedWordMap::create`
+    pub fn create(name: &str, elements: usize) -> io::Result<Self> {
+        let cap = MapBuilder::new()
+            .expected_elements(elements)
+            .chunk_capacity()
+            .map_err(invalid_input)?;
+        let segment = Segment::create(name, &Self::layout(cap))?;
+        let map = unsafe { InlineMap::fixed_at(segment.chunk_addr(), cap, true, System) };
+        segment.publish();
+        Ok(Self {
+            map,
+            _segment: segment,
+        })
+    }
+
+    /// See `SharedWordMap::open`
+    pub fn open(name: &str) -> io::Result<Self> {
+        let mut layout = Self::layout(0);
+        let segment = Segment::open(name, &mut layout)?;
+        let map =
+            unsafe { InlineMap::fixed_at(segment.chunk_addr(), layout.capacity, false, System) };
+        Ok(Self {
+            map,
+            _segment: segment,
+        })
+    }
+
+    #[inline(always)]
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.map.get(key)
+    }
+
+    /// Insert, or give an error when no slot is left for a new key
+    #[inline(always)]
+    pub fn insert(&self, key: &K, value: V) -> Result<Option<V>, FullError> {
+        self.map.fallible_insert(InsertOp::Insert, key, value)
+    }
+
+    #[inline(always)]
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.map.remove(key)
+    }
+
+    pub fn entries(&self) -> Vec<(K, V)> {
+        self.map.entries()
+    }
+
+    #[inline(always)]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// See `HashMap::update`, absent keys are left absent so it never takes a slot
+    pub fn update<F: Fn(&V) -> V>(&self, key: &K, func: F) -> Option<V> {
+        self.map.update(key, func)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.map.iter()
+    }
+
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&self, keep: F) {
+        self.map.retain(keep)
+    }
+
+    /// Remove every entry, their keys keep the slots
+    pub fn clear(&self) {
+        self.map.clear()
+    }
+}
+
+fn segment_name(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(invalid_input)
+}
+
+fn not_ready() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "segment is being created")
+}
+
+fn invalid_input<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::map::RangeError;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn segment(name: &str) -> String {
+        let name = format!("/{}-{}", name, std::process::id());
+        let _ = unlink(&name);
+        name
+    }
+
+    #[test]
+    pub fn shared_word_map() {
+        let name = segment("lightning-shm-word");
+        let map = Arc::new(SharedWordMap::<DefaultHasher>::create(&name, 4096).unwrap());
+        assert!(SharedWordMap::<DefaultHasher>::create(&name, 4096).is_err());
+        // Another mapping of the segment, at another address like in another process
+        let other = Arc::new(SharedWordMap::<DefaultHasher>::open(&name).unwrap());
+        assert_ne!(map._segment.base, other._segment.base);
+        let threads = (0..4)
+            .map(|t| {
+                let map = if t % 2 == 0 {
+                    map.clone()
+                } else {
+                    other.clone()
+                };
+                thread::spawn(move || {
+                    for i in t * 1000..(t + 1) * 1000 {
+                        assert_eq!(map.insert(&i, i + 1), Ok(None));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(map.len(), 4000);
+        assert_eq!(other.len(), 4000);
+        for i in 0..4000 {
+            assert_eq!(map.get(&i), Some(i + 1));
+            assert_eq!(other.get(&i), Some(i + 1));
+        }
+        assert_eq!(other.remove(&10), Some(11));
+        assert_eq!(map.get(&10), None);
+        assert_eq!(map.fetch_add(&20, 5), Some(21));
+        assert_eq!(other.get(&20), Some(26));
+        drop(map);
+        let map = SharedWordMap::<DefaultHasher>::open(&name).unwrap();
+        assert_eq!(map.len(), 3999);
+        assert_eq!(
+            SharedInlineMap::<usize, usize>::open(&name)
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+        unlink(&name).unwrap();
+        assert!(SharedWordMap::<DefaultHasher>::open(&name).is_err());
+        assert_eq!(other.get(&30), Some(31));
+    }
+
+    #[test]
+    pub fn shared_map_full() {
+        let name = segment("lightning-shm-full");
+        let map = SharedWordMap::<DefaultHasher>::create(&name, 10).unwrap();
+        let mut inserted = 0;
+        while map.insert(&inserted, inserted).is_ok() {
+            inserted += 1;
+        }
+        assert!(inserted >= 10);
+        assert_eq!(map.insert(&inserted, 0), Err(InsertError::Full(FullError)));
+        assert_eq!(
+            map.insert(
+                &WordMap::<System>::MAX_KEY,
+                WordMap::<System>::MAX_VALUE + 1
+            ),
+            Err(InsertError::Range(RangeError::Value(
+                WordMap::<System>::MAX_VALUE + 1
+            )))
+        );
+        // Keys already in the map keep their slots
+        assert_eq!(map.insert(&0, 100), Ok(Some(0)));
+        assert_eq!(map.remove(&1), Some(1));
+        assert_eq!(map.insert(&1, 101), Ok(None));
+        assert_eq!(map.len(), inserted);
+        map.clear();
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.insert(&2, 102), Ok(None));
+        unlink(&name).unwrap();
+    }
+
+    #[test]
+    pub fn shared_inline_map_full() {
+        let name = segment("lightning-shm-inline-full");
+        let map = SharedInlineMap::<u32, u32>::create(&name, 10).unwrap();
+        let mut inserted = 0;
+        while map.insert(&inserted, inserted).is_ok() {
+            inserted += 1;
+        }
+        assert_eq!(map.insert(&inserted, 0), Err(FullError));
+        assert_eq!(map.update(&inserted, |v| v + 1), None);
+        assert_eq!(map.update(&0, |v| v + 1), Some(1));
+        assert_eq!(map.len(), inserted as usize);
+        unlink(&name).unwrap();
+    }
+
+    #[test]
+    pub fn shared_inline_map() {
+        let name = segment("lightning-shm-inline");
+        let map = SharedInlineMap::<u32, (u64, u16)>::create(&name, 1000).unwrap();
+        let other = SharedInlineMap::<u32, (u64, u16)>::open(&name).unwrap();
+        for i in 0..1000u32 {
+            assert_eq!(map.insert(&i, (i as u64 * 3, i as u16)), Ok(None));
+        }
+        for i in 0..1000u32 {
+            assert_eq!(other.get(&i), Some((i as u64 * 3, i as u16)));
+        }
+        assert_eq!(other.update(&7, |(a, b)| (a + 1, *b)), Some((22, 7)));
+        assert_eq!(map.get(&7), Some((22, 7)));
+        other.retain(|k, _| k % 2 == 0);
+        assert_eq!(map.len(), 500);
+        let mut entries = map.iter().collect::<Vec<_>>();
+        entries.sort_unstable();
+        assert_eq!(entries[1], (2, (6, 2)));
+        unlink(&name).unwrap();
+    }
+}